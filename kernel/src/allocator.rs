@@ -1,65 +1,479 @@
-use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use alloc::{collections::BTreeMap, vec, vec::Vec};
+use bootloader::bootinfo::{MemoryMap, MemoryRegion, MemoryRegionType};
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    cmp::{max, min},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+use lazy_static::lazy_static;
 use linked_list_allocator::LockedHeap;
 use spin::{Mutex, Once};
 use x86_64::{
-    structures::paging::{PageSize, PhysFrame, Size4KiB},
-    PhysAddr,
+    instructions::{interrupts, tlb},
+    structures::paging::{frame::PhysFrameRange, PageSize, PhysFrame, Size2MiB, Size4KiB},
 };
 
 use crate::{
-    memory::load_active_pagetable,
+    cpu,
+    memory::{detect_conflicting_mappings, get_offset, load_active_pagetable},
+    pagetable::PageMapError,
     paging::{Page, PageRangeInclusive, PageTableEntry, PageTableEntryFlags},
+    phys_addr::PhysAddr,
+    serial_println,
+    sync::IrqMutex,
     virt_addr::VirtAddr,
 };
 
-pub static FRAME_ALLOCATOR: Once<Mutex<BootInfoAllocator>> = Once::new();
+/// The physical frame allocator, locking it masks interrupts so handlers can allocate too
+pub static FRAME_ALLOCATOR: Once<IrqMutex<BootInfoAllocator>> = Once::new();
+
+lazy_static! {
+    /// Reference counts for frames shared between several mappings, e.g. copy on write pages
+    ///
+    /// Frames which aren't present have a single owner. This lives on the heap
+    /// so frames can only be shared once the heap is initialized
+    static ref FRAME_REFS: Mutex<BTreeMap<u64, usize>> = Mutex::new(BTreeMap::new());
+}
 
 #[global_allocator]
-static GLOBAL_ALLOCATOR: LockedHeap = LockedHeap::empty();
+static GLOBAL_ALLOCATOR: HistogramHeap = HistogramHeap {
+    heap: LockedHeap::empty(),
+};
+
+/// The largest allocation in each size class, the last class holds everything bigger
+const SIZE_CLASSES: [usize; 5] = [16, 64, 256, 1024, 4096];
+const SIZE_CLASS_COUNT: usize = SIZE_CLASSES.len() + 1;
+
+/// The number of heap allocations requested in each size class
+static ALLOC_HISTOGRAM: [AtomicU64; SIZE_CLASS_COUNT] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+
+/// The heap, counting the size of every allocation in `ALLOC_HISTOGRAM`
+struct HistogramHeap {
+    heap: LockedHeap,
+}
+
+unsafe impl GlobalAlloc for HistogramHeap {
+    /// If the heap is full it's grown once before giving up
+    ///
+    /// Growing needs the frame allocator, so allocating while it's locked fails instead of recursing
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOC_HISTOGRAM[size_class(layout.size())].fetch_add(1, Ordering::Relaxed);
+        let ptr = self.heap.alloc(layout);
+        if !ptr.is_null() {
+            return ptr;
+        }
+
+        // Leave room to align the allocation within the new pages
+        let additional = max(layout.size() + layout.align(), HEAP_GROW_STEP);
+        let grown = match FRAME_ALLOCATOR.wait().and_then(|alloc| alloc.try_lock()) {
+            Some(mut alloc) => grow_heap(additional, &mut *alloc).is_ok(),
+            None => false,
+        };
+        match grown {
+            true => self.heap.alloc(layout),
+            false => ptr,
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.heap.dealloc(ptr, layout)
+    }
+}
+
+fn size_class(size: usize) -> usize {
+    SIZE_CLASSES
+        .iter()
+        .position(|&max| size <= max)
+        .unwrap_or(SIZE_CLASSES.len())
+}
+
+/// How many heap allocations have been requested of each size, in buckets of
+/// up to 16, 64, 256, 1024 and 4096 bytes, then anything larger
+pub fn alloc_histogram() -> [u64; SIZE_CLASS_COUNT] {
+    let mut histogram = [0; SIZE_CLASS_COUNT];
+    for (count, class) in histogram.iter_mut().zip(ALLOC_HISTOGRAM.iter()) {
+        *count = class.load(Ordering::Relaxed);
+    }
+
+    histogram
+}
 
 pub const HEAP_START: usize = 0x4444_4444_0000;
+/// The initial size of the heap, it grows on demand up to `HEAP_MAX_SIZE`
 pub const HEAP_SIZE: usize = 100 * 1024;
+pub const HEAP_MAX_SIZE: usize = 16 * 1024 * 1024;
+/// The least the heap grows by when an allocation doesn't fit
+const HEAP_GROW_STEP: usize = 64 * 1024;
 
-pub fn init_heap(frame_allocator: &mut impl FrameAllocator) -> Result<(), ()> {
-    let table = unsafe { load_active_pagetable() };
+/// Whether heap pages should be mapped NO_EXECUTE
+static HEAP_NX: AtomicBool = AtomicBool::new(true);
 
-    let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START.try_into().unwrap()); // TODO: Make this less gross
-        let heap_end = heap_start + (HEAP_SIZE - 1).try_into().unwrap();
-        let heap_start_page = Page::containing_address(heap_start);
-        let heap_end_page = Page::containing_address(heap_end);
-        PageRangeInclusive::new(heap_start_page, heap_end_page)
-    };
+#[derive(Debug)]
+pub enum HeapError {
+    /// The heap doesn't fit in the virtual address space
+    InvalidRange,
+    /// Growing the heap would take it past `HEAP_MAX_SIZE`
+    TooLarge,
+    /// `init_heap` hasn't been called yet
+    NotInitialized,
+    FrameAllocation,
+    PageMap(PageMapError),
+}
 
-    for page in page_range {
-        let frame = frame_allocator.allocate().unwrap();
-        let flags = PageTableEntryFlags::PRESENT | PageTableEntryFlags::WRITABLE;
-        let entry = PageTableEntry::new(frame, flags);
-        let page_result = unsafe { table.map_page(page, entry, frame_allocator) };
-        match page_result {
-            Ok(_) => {}
-            Err(_) => return Err(()),
-        };
+/// Choose whether the heap is mapped NO_EXECUTE, this must be called before `init_heap`
+pub fn set_heap_nx(enabled: bool) {
+    HEAP_NX.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether heap pages are mapped NO_EXECUTE
+///
+/// This is false if the cpu doesn't support NX, as setting the bit would fault
+pub fn heap_is_nx() -> bool {
+    HEAP_NX.load(Ordering::Relaxed) && cpu::nx_enabled()
+}
+
+/// Map the heap and hand it to the global allocator
+///
+/// If mapping fails part way the heap pages which were mapped are unmapped and their frames returned
+pub fn init_heap<A: FrameAllocator + FrameDeallocator>(
+    frame_allocator: &mut A,
+) -> Result<(), HeapError> {
+    detect_conflicting_mappings();
+
+    let mut flags = PageTableEntryFlags::kernel_rw();
+    if heap_is_nx() {
+        flags |= PageTableEntryFlags::NO_EXECUTE;
+    }
+
+    let page_range = heap_page_range(HEAP_START as u64, HEAP_SIZE as u64)?;
+    let table = unsafe { load_active_pagetable() };
+    match table.map_range(page_range, flags, frame_allocator) {
+        Ok(_) => {}
+        Err(PageMapError::FrameAllocation) => return Err(HeapError::FrameAllocation),
+        Err(err) => return Err(HeapError::PageMap(err)),
     }
 
     unsafe {
-        GLOBAL_ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        GLOBAL_ALLOCATOR.heap.lock().init(HEAP_START, HEAP_SIZE);
     }
 
     Ok(())
 }
 
+/// Map at least `additional_bytes` more memory directly after the heap and give it to the heap
+///
+/// The size is rounded up to whole pages. If mapping fails part way the pages which were
+/// mapped are still added to the heap
+pub fn grow_heap(
+    additional_bytes: usize,
+    frame_allocator: &mut impl FrameAllocator,
+) -> Result<(), HeapError> {
+    let (top, size) = interrupts::without_interrupts(|| {
+        let heap = GLOBAL_ALLOCATOR.heap.lock();
+        (heap.top(), heap.size())
+    });
+    if size == 0 {
+        return Err(HeapError::NotInitialized);
+    }
+
+    let pages = (additional_bytes + 4095) / 4096;
+    match pages
+        .checked_mul(4096)
+        .and_then(|bytes| bytes.checked_add(size))
+    {
+        Some(new_size) if new_size <= HEAP_MAX_SIZE => {}
+        _ => return Err(HeapError::TooLarge),
+    }
+
+    let mut flags = PageTableEntryFlags::kernel_rw();
+    if heap_is_nx() {
+        flags |= PageTableEntryFlags::NO_EXECUTE;
+    }
+
+    let table = unsafe { load_active_pagetable() };
+    let first = Page::containing_address(VirtAddr::new(top as u64));
+    let mut result = Ok(());
+    let mut mapped = 0;
+    while mapped < pages {
+        let frame = match frame_allocator.allocate() {
+            Some(f) => f,
+            None => {
+                result = Err(HeapError::FrameAllocation);
+                break;
+            }
+        };
+        let entry = PageTableEntry::new(frame, flags);
+        let page_result =
+            unsafe { table.map_page_no_flush(first + mapped as u64, entry, frame_allocator) }; // The frame was just allocated so nothing else references it
+        if let Err(err) = page_result {
+            result = Err(HeapError::PageMap(err));
+            break;
+        }
+        mapped += 1;
+    }
+    tlb::flush_all();
+
+    interrupts::without_interrupts(|| unsafe {
+        GLOBAL_ALLOCATOR.heap.lock().extend(mapped * 4096); // The pages directly after the heap are now mapped
+    });
+
+    result
+}
+
+/// The pages covering `size` bytes from `start`, checking the end of the range doesn't overflow
+fn heap_page_range(start: u64, size: u64) -> Result<PageRangeInclusive, HeapError> {
+    let last_byte = match size.checked_sub(1).and_then(|s| start.checked_add(s)) {
+        Some(addr) => addr,
+        None => return Err(HeapError::InvalidRange),
+    };
+
+    let start_page = Page::containing_address(VirtAddr::new(start));
+    let end_page = Page::containing_address(VirtAddr::new(last_byte));
+    Ok(PageRangeInclusive::new(start_page, end_page))
+}
+
+/// Record another mapping of `frame`
+pub fn share_frame(frame: PhysFrame) {
+    let mut refs = FRAME_REFS.lock();
+    let count = refs.entry(frame.start_address().as_u64()).or_insert(1);
+    *count += 1;
+}
+
+/// Drop a mapping of `frame`, returning the number of mappings left
+pub fn release_frame(frame: PhysFrame) -> usize {
+    let mut refs = FRAME_REFS.lock();
+    let addr = frame.start_address().as_u64();
+    match refs.get_mut(&addr) {
+        Some(count) if *count > 2 => {
+            *count -= 1;
+            *count
+        }
+        Some(_) => {
+            refs.remove(&addr);
+            1
+        }
+        None => 0,
+    }
+}
+
+/// The number of mappings currently referencing `frame`
+pub fn frame_refs(frame: PhysFrame) -> usize {
+    match FRAME_REFS.lock().get(&frame.start_address().as_u64()) {
+        Some(count) => *count,
+        None => 1,
+    }
+}
+
 /// Initialize the boot info allocator
 ///
 /// This is unsafe because the caller must guarantee that the passed
 /// memory map is valid. All froms marked as USABLE must actually be unused
 pub unsafe fn init(memory_map: &'static MemoryMap) {
     FRAME_ALLOCATOR
-        .call_once(|| Mutex::<BootInfoAllocator>::new(BootInfoAllocator::init(memory_map)));
+        .call_once(|| IrqMutex::<BootInfoAllocator>::new(BootInfoAllocator::init(memory_map)));
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ReserveError {
+    NotInitialized,
+    /// Every reservation slot is already in use
+    TooManyRanges,
+    /// The range ends before it starts
+    InvalidRange,
+}
+
+/// Keep `frame` out of the allocator for good, e.g. because firmware tables or MMIO live there
+///
+/// Call this during init, a frame which has already been handed out isn't taken back
+pub fn reserve_frame(frame: PhysFrame) -> Result<(), ReserveError> {
+    let start = PhysAddr::from(frame.start_address());
+    reserve_range(start, start + Size4KiB::SIZE)
+}
+
+/// Keep every frame overlapping the physical range `start..end` out of the allocator, see `reserve_frame`
+pub fn reserve_range(start: PhysAddr, end: PhysAddr) -> Result<(), ReserveError> {
+    match FRAME_ALLOCATOR.wait() {
+        Some(a) => a.lock().reserve(start, end),
+        None => Err(ReserveError::NotInitialized),
+    }
+}
+
+/// Panic if the global frame allocator's bookkeeping is inconsistent
+///
+/// This is a debugging aid, call it after bulk allocator operations. It does nothing in release builds
+pub fn assert_invariants() {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+
+    if let Some(alloc) = FRAME_ALLOCATOR.wait() {
+        if let Err(err) = alloc.lock().check_invariants() {
+            panic!("frame allocator invariant violated: {:?}", err);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum InvariantError {
+    /// Usable regions overlap, so a frame could be handed out twice
+    OverlappingRegions(PhysAddr),
+    /// The cursor points past the end of usable memory
+    CursorOutOfRange(usize),
+    /// A frame on the free list isn't usable memory
+    FreeListCorrupt(PhysAddr),
+    /// The free list holds a different number of frames than were deallocated
+    FreeListLength(usize),
 }
 
 pub trait FrameAllocator<S: PageSize = Size4KiB> {
     fn allocate(&mut self) -> Option<PhysFrame<S>>;
+
+    /// Allocate a physically contiguous, 2MiB aligned run of memory for a huge page
+    ///
+    /// Allocators which can't provide one return None
+    fn allocate_huge_2m(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        None
+    }
+
+    /// Allocate `count` physically adjacent frames, e.g. for a device to DMA into
+    ///
+    /// By default this allocates frames one at a time until enough are adjacent,
+    /// any frames allocated before the run starts are lost
+    fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrameRange<S>> {
+        if count == 0 {
+            return None;
+        }
+
+        let mut start = self.allocate()?;
+        let mut end = start + 1;
+        while end - start < count as u64 {
+            let frame = self.allocate()?;
+            if frame != end {
+                start = frame;
+            }
+            end = frame + 1;
+        }
+
+        Some(PhysFrame::range(start, end))
+    }
+
+    /// Allocate a frame whose start address is a multiple of `align`, a power of two of at least 4KiB
+    ///
+    /// By default this allocates frames until one is aligned, the frames before it are lost
+    fn allocate_aligned(&mut self, align: u64) -> Option<PhysFrame<S>> {
+        check_frame_alignment(align);
+        loop {
+            let frame = self.allocate()?;
+            if frame.start_address().is_aligned(align) {
+                return Some(frame);
+            }
+        }
+    }
+}
+
+fn check_frame_alignment(align: u64) {
+    if !align.is_power_of_two() || align < Size4KiB::SIZE {
+        panic!("invalid frame alignment {:#x}", align);
+    }
+}
+
+pub trait FrameDeallocator<S: PageSize = Size4KiB> {
+    /// Return a frame to the allocator
+    ///
+    /// This is unsafe because the caller must guarantee the frame is no longer in use
+    unsafe fn deallocate(&mut self, frame: PhysFrame<S>);
+}
+
+/// Allocate a frame which is returned to `allocator` when the guard is dropped
+pub fn allocate_guarded<A: FrameAllocator + FrameDeallocator>(
+    allocator: &mut A,
+) -> Option<FrameGuard<'_, A>> {
+    let frame = allocator.allocate()?;
+    Some(FrameGuard { frame, allocator })
+}
+
+/// An allocated frame which is deallocated on drop unless it is leaked
+///
+/// This keeps error paths from leaking frames, e.g. when mapping the frame fails
+pub struct FrameGuard<'a, A: FrameDeallocator> {
+    frame: PhysFrame,
+    allocator: &'a mut A,
+}
+
+impl<'a, A: FrameDeallocator> FrameGuard<'a, A> {
+    pub fn frame(&self) -> PhysFrame {
+        self.frame
+    }
+
+    /// The allocator the frame came from, for allocating while the guard is held
+    pub fn allocator(&mut self) -> &mut A {
+        self.allocator
+    }
+
+    /// Keep the frame allocated, e.g. once it has been successfully mapped
+    pub fn leak(self) -> PhysFrame {
+        let frame = self.frame;
+        core::mem::forget(self);
+        frame
+    }
+}
+
+impl<'a, A: FrameDeallocator> Drop for FrameGuard<'a, A> {
+    fn drop(&mut self) {
+        unsafe { self.allocator.deallocate(self.frame) }; // The guard owns the frame and it hasn't been leaked, so nothing else is using it
+    }
+}
+
+/// Physical memory usage, in frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryStats {
+    pub total_frames: usize,
+    pub used_frames: usize,
+    pub free_frames: usize,
+}
+
+/// How much physical memory the frame allocator has handed out, or None before it's initialized
+pub fn memory_stats() -> Option<MemoryStats> {
+    let alloc = FRAME_ALLOCATOR.wait()?.lock();
+    let total_frames = alloc.total_usable_frames();
+    let used_frames = alloc.allocated_frames();
+
+    Some(MemoryStats {
+        total_frames,
+        used_frames,
+        free_frames: total_frames.saturating_sub(used_frames),
+    })
+}
+
+/// Print how many frames have been handed out and how much of the heap is in use over serial
+pub fn print_mem_stats() {
+    match memory_stats() {
+        Some(stats) => {
+            serial_println!(
+                "frames: {} allocated, {} free of {}",
+                stats.used_frames,
+                stats.free_frames,
+                stats.total_frames
+            );
+        }
+        None => {
+            serial_println!("frames: allocator not initialized");
+        }
+    }
+
+    let (used, free, size) = interrupts::without_interrupts(|| {
+        let heap = GLOBAL_ALLOCATOR.heap.lock();
+        (heap.used(), heap.free(), heap.size())
+    });
+    serial_println!("heap: {} used, {} free of {} bytes", used, free, size);
+    serial_println!("heap allocations by size: {:?}", alloc_histogram());
 }
 
 /// An allocator that always returns None
@@ -71,19 +485,94 @@ impl FrameAllocator for ZeroAllocator {
     }
 }
 
+/// The most physical ranges which can be reserved
+const MAX_RESERVED: usize = 16;
+
+/// The link stored in the last frame of the free list
+const FREE_LIST_END: u64 = u64::MAX;
+
 // TODO: Check out named existential types to store iterator and avoid recreating for every alloc
 // TODO: Add some tests
 pub struct BootInfoAllocator {
     memory_map: &'static MemoryMap,
     next: usize,
+    verify: Option<fn(PhysFrame) -> bool>,
+    /// Physical ranges which are never handed out
+    reserved: [Option<(PhysAddr, PhysAddr)>; MAX_RESERVED],
+    /// The most recently deallocated frame, each free frame holds the address of the next
+    free_list: Option<PhysFrame>,
+    free_frames: usize,
 }
 
 impl FrameAllocator for BootInfoAllocator {
-    // TODO: Deallocate frames
+    /// Deallocated frames are reused before any new ones are handed out
     fn allocate(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+        if let Some(frame) = self.pop_free() {
+            return Some(frame);
+        }
+
+        loop {
+            let frame = self.usable_frames().nth(self.next)?;
+            self.next += 1;
+            if !self.is_reserved(frame) && self.passes_verify(frame) {
+                return Some(frame);
+            }
+        }
+    }
+
+    /// Frames are handed out in order, so any frames skipped to reach an aligned run are lost.
+    /// The free list isn't searched, as freed frames are rarely contiguous
+    fn allocate_huge_2m(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        const FRAMES: usize = (Size2MiB::SIZE / Size4KiB::SIZE) as usize;
+
+        let start = self.allocate_run(FRAMES, Size2MiB::SIZE)?;
+        PhysFrame::from_start_address(start.into()).ok()
+    }
+
+    /// Frames are handed out in order, so this skips straight over frames which can't start a run.
+    /// Runs never span a gap between regions or a reserved frame, and freed frames are never reused
+    fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrameRange> {
+        if count == 0 {
+            return None;
+        }
+
+        let start = self.allocate_run(count, Size4KiB::SIZE)?;
+        let start = PhysFrame::containing_address(start.into());
+        Some(PhysFrame::range(start, start + count as u64))
+    }
+
+    /// The cursor moves past any unaligned frames, so they are lost. Freed frames are never reused
+    fn allocate_aligned(&mut self, align: u64) -> Option<PhysFrame> {
+        check_frame_alignment(align);
+        let start = self.allocate_run(1, align)?;
+        Some(PhysFrame::containing_address(start.into()))
+    }
+}
+
+impl FrameDeallocator for BootInfoAllocator {
+    /// Push `frame` onto the free list, the link is written into the frame itself
+    unsafe fn deallocate(&mut self, frame: PhysFrame) {
+        let next = match self.free_list {
+            Some(f) => f.start_address().as_u64(),
+            None => FREE_LIST_END,
+        };
+        free_link(frame).write_volatile(next);
+
+        self.free_list = Some(frame);
+        self.free_frames += 1;
+    }
+}
+
+/// The free list link stored at the start of `frame`
+fn free_link(frame: PhysFrame) -> *mut u64 {
+    (get_offset() + frame.start_address().as_u64()).as_mut_ptr()
+}
+
+/// The frame a free list link points at, None at the end of the list
+fn link_frame(link: u64) -> Option<PhysFrame> {
+    match link {
+        FREE_LIST_END => None,
+        addr => Some(PhysFrame::containing_address(PhysAddr::new(addr).into())),
     }
 }
 
@@ -96,7 +585,247 @@ impl BootInfoAllocator {
         BootInfoAllocator {
             memory_map,
             next: 0,
+            verify: None,
+            reserved: [None; MAX_RESERVED],
+            free_list: None,
+            free_frames: 0,
+        }
+    }
+
+    /// Whether any region of the memory map, usable or not, contains `frame`
+    pub fn in_memory_map(&self, frame: PhysFrame) -> bool {
+        let addr = frame.start_address().as_u64();
+        self.memory_map
+            .iter()
+            .any(|r| r.range.start_addr() <= addr && addr < r.range.end_addr())
+    }
+
+    /// Never hand out frames overlapping the physical range `start..end`
+    pub fn reserve(&mut self, start: PhysAddr, end: PhysAddr) -> Result<(), ReserveError> {
+        if end < start {
+            return Err(ReserveError::InvalidRange);
         }
+
+        match self.reserved.iter_mut().find(|r| r.is_none()) {
+            Some(slot) => {
+                *slot = Some((start, end));
+                Ok(())
+            }
+            None => Err(ReserveError::TooManyRanges),
+        }
+    }
+
+    fn is_reserved(&self, frame: PhysFrame) -> bool {
+        let start = PhysAddr::from(frame.start_address());
+        let end = start + Size4KiB::SIZE;
+        self.reserved
+            .iter()
+            .flatten()
+            .any(|&(reserved_start, reserved_end)| start < reserved_end && end > reserved_start)
+    }
+
+    /// Check every frame is backed by RAM with `verify_frame` before handing it out,
+    /// skipping any which fail
+    ///
+    /// This touches all of the frame's memory so it's slow, and it relies on the
+    /// virtual memory system being initialized
+    pub fn set_verify(&mut self, enabled: bool) {
+        self.verify = match enabled {
+            true => Some(BootInfoAllocator::verify_frame),
+            false => None,
+        };
+    }
+
+    /// Whether `frame` passes the verify hook, every frame does if it's disabled
+    fn passes_verify(&self, frame: PhysFrame) -> bool {
+        match self.verify {
+            Some(verify) => verify(frame),
+            None => true,
+        }
+    }
+
+    /// Write then read back test patterns across the whole frame to check it's actually backed by RAM
+    ///
+    /// The frame's contents are clobbered so it must not be in use
+    pub fn verify_frame(frame: PhysFrame) -> bool {
+        const PATTERNS: [u64; 2] = [0x5555_5555_5555_5555, 0xAAAA_AAAA_AAAA_AAAA];
+
+        let start: *mut u64 = (get_offset() + frame.start_address().as_u64()).as_mut_ptr();
+        for i in 0..512 {
+            for pattern in PATTERNS {
+                unsafe {
+                    let ptr = start.add(i); // This is inside the frame, which is mapped at the physical memory offset
+                    ptr.write_volatile(pattern);
+                    if ptr.read_volatile() != pattern {
+                        return false;
+                    }
+                }
+            }
+        }
+
+        true
+    }
+
+    /// The number of frames in the memory map's usable regions, including any reserved ones
+    pub fn total_usable_frames(&self) -> usize {
+        self.memory_map
+            .iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable)
+            .map(|r| ((r.range.end_addr() - r.range.start_addr()) / Size4KiB::SIZE) as usize)
+            .sum()
+    }
+
+    /// The number of usable frames which have been handed out, or skipped to keep huge frames aligned
+    ///
+    /// Frames which never came from this allocator can be deallocated into it, so this saturates at 0
+    pub fn allocated_frames(&self) -> usize {
+        self.next.saturating_sub(self.free_frames)
+    }
+
+    fn pop_free(&mut self) -> Option<PhysFrame> {
+        let frame = self.free_list?;
+        let next = unsafe { free_link(frame).read_volatile() }; // Free frames always hold a link
+        self.free_list = link_frame(next);
+        self.free_frames -= 1;
+
+        Some(frame)
+    }
+
+    /// Unlink the first frame on the free list which starts below `below`
+    fn take_free_below(&mut self, below: PhysAddr) -> Option<PhysFrame> {
+        let mut previous: Option<PhysFrame> = None;
+        let mut next = self.free_list;
+        while let Some(frame) = next {
+            let link = unsafe { free_link(frame).read_volatile() }; // Free frames always hold a link
+            if PhysAddr::from(frame.start_address()) < below {
+                match previous {
+                    Some(p) => unsafe { free_link(p).write_volatile(link) }, // `p` is still on the list
+                    None => self.free_list = link_frame(link),
+                }
+                self.free_frames -= 1;
+                return Some(frame);
+            }
+
+            previous = Some(frame);
+            next = link_frame(link);
+        }
+
+        None
+    }
+
+    /// Find `count` contiguous usable frames starting at a multiple of `align`, and move
+    /// the cursor past them
+    fn allocate_run(&mut self, count: usize, align: u64) -> Option<PhysAddr> {
+        // The index and address of the first frame in the current aligned, contiguous run
+        let mut run: Option<(usize, PhysAddr)> = None;
+        for (i, frame) in self.usable_frames().enumerate().skip(self.next) {
+            // A bad frame breaks the run just like a reserved one
+            if self.is_reserved(frame) || !self.passes_verify(frame) {
+                run = None;
+                continue;
+            }
+
+            let addr = PhysAddr::from(frame.start_address());
+            run = match run {
+                Some((start, start_addr))
+                    if addr == start_addr + (i - start) as u64 * Size4KiB::SIZE =>
+                {
+                    Some((start, start_addr))
+                }
+                _ if addr.is_aligned(align) => Some((i, addr)),
+                _ => None,
+            };
+
+            if let Some((start, start_addr)) = run {
+                if i - start + 1 == count {
+                    self.next = i + 1;
+                    return Some(start_addr);
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Allocate a usable frame whose start address is strictly below `below`
+    ///
+    /// This is useful for legacy devices (e.g. ISA DMA) which can only address low memory.
+    /// The free list is searched first. Past that the memory map is sorted by address and frames
+    /// are handed out in order, so if the next unused frame isn't below the bound none are
+    pub fn allocate_low(&mut self, below: PhysAddr) -> Option<PhysFrame> {
+        if let Some(frame) = self.take_free_below(below) {
+            return Some(frame);
+        }
+
+        loop {
+            let frame = self.usable_frames().nth(self.next)?;
+            if PhysAddr::from(frame.start_address()) >= below {
+                return None;
+            }
+
+            self.next += 1;
+            if !self.is_reserved(frame) {
+                return Some(frame);
+            }
+        }
+    }
+
+    /// Check every frame the allocator can hand out is page aligned, usable, and can only
+    /// be handed out once
+    pub fn check_invariants(&self) -> Result<(), InvariantError> {
+        let regions = self.memory_map.iter();
+        let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
+
+        let mut frame_count = 0;
+        let mut previous_end = 0;
+        for region in usable_regions {
+            // Frames are handed out in memory map order so regions must be sorted and disjoint
+            if region.range.start_addr() < previous_end {
+                return Err(InvariantError::OverlappingRegions(PhysAddr::new(
+                    region.range.start_addr(),
+                )));
+            }
+
+            previous_end = region.range.end_addr();
+            frame_count += (region.range.end_addr() - region.range.start_addr()) as usize / 4096;
+        }
+
+        if self.next > frame_count {
+            return Err(InvariantError::CursorOutOfRange(self.next));
+        }
+
+        self.check_free_list()
+    }
+
+    /// Walk the free list checking every frame on it is usable memory and the length matches
+    fn check_free_list(&self) -> Result<(), InvariantError> {
+        let mut len = 0;
+        let mut next = self.free_list;
+        while let Some(frame) = next {
+            // Stop early if the list has become a cycle
+            if len == self.free_frames {
+                return Err(InvariantError::FreeListLength(len + 1));
+            }
+
+            let addr = PhysAddr::from(frame.start_address());
+            let usable = self.memory_map.iter().any(|r| {
+                r.region_type == MemoryRegionType::Usable
+                    && r.range.start_addr() <= addr.as_u64()
+                    && addr.as_u64() < r.range.end_addr()
+            });
+            if !usable {
+                return Err(InvariantError::FreeListCorrupt(addr));
+            }
+
+            len += 1;
+            next = link_frame(unsafe { free_link(frame).read_volatile() }); // Checked to be usable memory above
+        }
+
+        if len != self.free_frames {
+            return Err(InvariantError::FreeListLength(len));
+        }
+
+        Ok(())
     }
 
     /// Returns an iterator of usable frames from the memory map
@@ -106,6 +835,713 @@ impl BootInfoAllocator {
         let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
         let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
 
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr).into()))
+    }
+}
+
+/// A frame allocator with a bit per usable frame, so any frame can be freed and handed out again
+///
+/// The bitmap lives on the heap, so this can only be created once the heap is initialized
+pub struct BitmapAllocator {
+    memory_map: &'static MemoryMap,
+    /// Bit `i` is set when the `i`th usable frame in the memory map is allocated
+    bitmap: Vec<u64>,
+    frames: usize,
+    /// Every word before this one is full, so searches start here
+    next_word: usize,
+}
+
+impl BitmapAllocator {
+    /// Create an allocator with every usable frame free
+    ///
+    /// This is unsafe because the caller must guarantee that the passed
+    /// memory map is valid. All frames marked as USABLE must actually be unused
+    pub unsafe fn new(memory_map: &'static MemoryMap) -> Self {
+        let frames = usable_regions(memory_map)
+            .map(|r| ((r.range.end_addr() - r.range.start_addr()) / Size4KiB::SIZE) as usize)
+            .sum();
+
+        BitmapAllocator {
+            memory_map,
+            bitmap: vec![0; (frames + 63) / 64],
+            frames,
+            next_word: 0,
+        }
+    }
+
+    /// Take over from a boot info allocator, keeping every frame it handed out allocated
+    ///
+    /// Frames on its free list are free again, and reserved frames are never handed out
+    pub fn from_boot_info_allocator(mut alloc: BootInfoAllocator) -> Self {
+        let mut bitmap = unsafe { BitmapAllocator::new(alloc.memory_map) }; // The boot info allocator was created from a valid map
+        for index in 0..min(alloc.next, bitmap.frames) {
+            bitmap.set(index);
+        }
+        while let Some(frame) = alloc.pop_free() {
+            match bitmap.index_of(frame) {
+                Some(index) => bitmap.clear(index),
+                None => panic!("{:?} was freed but isn't usable", frame),
+            }
+        }
+        for (index, frame) in alloc.usable_frames().enumerate().skip(alloc.next) {
+            if alloc.is_reserved(frame) {
+                bitmap.set(index);
+            }
+        }
+
+        bitmap
+    }
+
+    /// The number of usable frames which are currently allocated
+    pub fn allocated_frames(&self) -> usize {
+        self.bitmap.iter().map(|w| w.count_ones() as usize).sum()
+    }
+
+    fn set(&mut self, index: usize) {
+        self.bitmap[index / 64] |= 1 << (index % 64);
+    }
+
+    fn clear(&mut self, index: usize) {
+        self.bitmap[index / 64] &= !(1 << (index % 64));
+        self.next_word = min(self.next_word, index / 64);
+    }
+
+    fn is_set(&self, index: usize) -> bool {
+        self.bitmap[index / 64] & 1 << (index % 64) != 0
+    }
+
+    /// The bitmap index of a usable frame
+    fn index_of(&self, frame: PhysFrame) -> Option<usize> {
+        let addr = frame.start_address().as_u64();
+        let mut first = 0;
+        for region in usable_regions(self.memory_map) {
+            let (start, end) = (region.range.start_addr(), region.range.end_addr());
+            if start <= addr && addr < end {
+                return Some(first + ((addr - start) / Size4KiB::SIZE) as usize);
+            }
+            first += ((end - start) / Size4KiB::SIZE) as usize;
+        }
+
+        None
+    }
+
+    /// The usable frame at a bitmap index
+    fn frame_at(&self, mut index: usize) -> Option<PhysFrame> {
+        for region in usable_regions(self.memory_map) {
+            let (start, end) = (region.range.start_addr(), region.range.end_addr());
+            let frames = ((end - start) / Size4KiB::SIZE) as usize;
+            if index < frames {
+                let addr = PhysAddr::new(start + index as u64 * Size4KiB::SIZE);
+                return Some(PhysFrame::containing_address(addr.into()));
+            }
+            index -= frames;
+        }
+
+        None
+    }
+}
+
+impl FrameAllocator for BitmapAllocator {
+    /// Hands out the free frame with the lowest address
+    fn allocate(&mut self) -> Option<PhysFrame> {
+        for w in self.next_word..self.bitmap.len() {
+            let word = self.bitmap[w];
+            if word == u64::MAX {
+                continue;
+            }
+
+            self.next_word = w;
+            let index = w * 64 + (!word).trailing_zeros() as usize;
+            if index >= self.frames {
+                return None;
+            }
+            self.set(index);
+            return self.frame_at(index);
+        }
+
+        self.next_word = self.bitmap.len();
+        None
+    }
+}
+
+impl FrameDeallocator for BitmapAllocator {
+    unsafe fn deallocate(&mut self, frame: PhysFrame) {
+        match self.index_of(frame) {
+            Some(index) if self.is_set(index) => self.clear(index),
+            Some(_) => panic!("{:?} was freed twice", frame),
+            None => panic!("{:?} isn't a usable frame", frame),
+        }
+    }
+}
+
+fn usable_regions(memory_map: &'static MemoryMap) -> impl Iterator<Item = &'static MemoryRegion> {
+    memory_map
+        .iter()
+        .filter(|r| r.region_type == MemoryRegionType::Usable)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{vec, vec::Vec};
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    use bootloader::bootinfo::{FrameRange, MemoryMap, MemoryRegion, MemoryRegionType};
+    use lazy_static::lazy_static;
+    use x86_64::structures::paging::{PageSize, PhysFrame, Size2MiB, Size4KiB};
+
+    use crate::{paging::Page, phys_addr::PhysAddr, virt_addr::VirtAddr};
+
+    use super::{
+        allocate_guarded, assert_invariants, free_link, heap_page_range, memory_stats,
+        reserve_range, size_class, BitmapAllocator, BootInfoAllocator, FrameAllocator,
+        FrameDeallocator, HeapError, InvariantError, ReserveError, FRAME_ALLOCATOR, MAX_RESERVED,
+    };
+
+    lazy_static! {
+        /// Four usable frames, a gap, then two more
+        static ref SPLIT_MAP: MemoryMap = {
+            let mut map = MemoryMap::new();
+            map.add_region(MemoryRegion {
+                range: FrameRange::new(0x10_0000, 0x10_4000),
+                region_type: MemoryRegionType::Usable,
+            });
+            map.add_region(MemoryRegion {
+                range: FrameRange::new(0x10_4000, 0x20_0000),
+                region_type: MemoryRegionType::Reserved,
+            });
+            map.add_region(MemoryRegion {
+                range: FrameRange::new(0x20_0000, 0x20_2000),
+                region_type: MemoryRegionType::Usable,
+            });
+            map
+        };
+        static ref OVERLAPPING_MAP: MemoryMap = {
+            let mut map = MemoryMap::new();
+            map.add_region(MemoryRegion {
+                range: FrameRange::new(0x10_0000, 0x20_0000),
+                region_type: MemoryRegionType::Usable,
+            });
+            map.add_region(MemoryRegion {
+                range: FrameRange::new(0x18_0000, 0x28_0000),
+                region_type: MemoryRegionType::Usable,
+            });
+            map
+        };
+    }
+
+    static BAD_FRAME: AtomicU64 = AtomicU64::new(0);
+
+    /// Hands out frames from a stack and pushes them back when deallocated
+    struct StackAllocator {
+        frames: Vec<PhysFrame>,
+    }
+
+    impl FrameAllocator for StackAllocator {
+        fn allocate(&mut self) -> Option<PhysFrame> {
+            self.frames.pop()
+        }
+    }
+
+    impl FrameDeallocator for StackAllocator {
+        unsafe fn deallocate(&mut self, frame: PhysFrame) {
+            self.frames.push(frame);
+        }
+    }
+
+    fn stack_allocator() -> StackAllocator {
+        StackAllocator {
+            frames: vec![
+                PhysFrame::containing_address(PhysAddr::new(0x1000).into()),
+                PhysFrame::containing_address(PhysAddr::new(0x2000).into()),
+            ],
+        }
+    }
+
+    fn reject_bad_frame(frame: PhysFrame) -> bool {
+        frame.start_address().as_u64() != BAD_FRAME.load(Ordering::SeqCst)
+    }
+
+    #[test_case]
+    fn allocate_below_16mb() {
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+
+        let bound = PhysAddr::new(16 * 1024 * 1024);
+        let frame = alloc.lock().allocate_low(bound);
+        match frame {
+            Some(f) => assert!(PhysAddr::from(f.start_address()) < bound),
+            None => panic!("no frame was allocated below 16MiB"),
+        }
+    }
+
+    #[test_case]
+    fn heap_range_at_top_of_address_space() {
+        let start = 0xFFFF_FFFF_FFFF_0000;
+        let mut range = match heap_page_range(start, 0x10000) {
+            Ok(r) => r,
+            Err(err) => panic!("valid heap range was rejected: {:?}", err),
+        };
+
+        assert_eq!(
+            range.next(),
+            Some(Page::containing_address(VirtAddr::new(start)))
+        );
+    }
+
+    #[test_case]
+    fn size_class_boundaries() {
+        assert_eq!(size_class(0), 0);
+        assert_eq!(size_class(16), 0);
+        assert_eq!(size_class(17), 1);
+        assert_eq!(size_class(1024), 3);
+        assert_eq!(size_class(4096), 4);
+        assert_eq!(size_class(4097), 5);
+    }
+
+    #[test_case]
+    fn heap_range_overflow() {
+        match heap_page_range(0xFFFF_FFFF_FFFF_0000, 0x10001) {
+            Ok(_) => panic!("overflowing heap range was accepted"),
+            Err(HeapError::InvalidRange) => {}
+            Err(err) => panic!("unexpected error: {:?}", err),
+        }
+    }
+
+    #[test_case]
+    fn heap_range_empty() {
+        match heap_page_range(0x4444_4444_0000, 0) {
+            Ok(_) => panic!("empty heap range was accepted"),
+            Err(HeapError::InvalidRange) => {}
+            Err(err) => panic!("unexpected error: {:?}", err),
+        }
+    }
+
+    #[test_case]
+    fn verify_usable_frame() {
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+
+        let frame = match alloc.lock().allocate() {
+            Some(f) => f,
+            None => panic!("could not allocate frame"),
+        };
+        assert!(BootInfoAllocator::verify_frame(frame));
+
+        assert_invariants();
+    }
+
+    #[test_case]
+    fn verified_allocation_skips_bad_frame() {
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+
+        let mut test_alloc = {
+            let alloc = alloc.lock();
+            BootInfoAllocator {
+                memory_map: alloc.memory_map,
+                next: alloc.next,
+                verify: Some(reject_bad_frame),
+                reserved: [None; MAX_RESERVED],
+                free_list: None,
+                free_frames: 0,
+            }
+        };
+
+        let mut frames = test_alloc.usable_frames().skip(test_alloc.next);
+        let (bad, good) = match (frames.next(), frames.next()) {
+            (Some(b), Some(g)) => (b, g),
+            _ => panic!("not enough usable frames"),
+        };
+        BAD_FRAME.store(bad.start_address().as_u64(), Ordering::SeqCst);
+
+        assert_eq!(test_alloc.allocate(), Some(good));
+    }
+
+    #[test_case]
+    fn verified_run_skips_bad_frame() {
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+
+        let mut test_alloc = {
+            let alloc = alloc.lock();
+            BootInfoAllocator {
+                memory_map: alloc.memory_map,
+                next: alloc.next,
+                verify: Some(reject_bad_frame),
+                reserved: [None; MAX_RESERVED],
+                free_list: None,
+                free_frames: 0,
+            }
+        };
+
+        // The bad frame is in the middle of the first run the allocator would pick
+        let bad = match test_alloc.usable_frames().nth(test_alloc.next + 1) {
+            Some(f) => f,
+            None => panic!("not enough usable frames"),
+        };
+        BAD_FRAME.store(bad.start_address().as_u64(), Ordering::SeqCst);
+
+        let run = match test_alloc.allocate_contiguous(4) {
+            Some(r) => r,
+            None => panic!("could not allocate a run"),
+        };
+        assert!(run.start > bad);
+    }
+
+    #[test_case]
+    fn reserved_frames_are_never_allocated() {
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+
+        // Reserve a few of the frames which would be handed out next
+        let (start, end) = {
+            let alloc = alloc.lock();
+            let mut frames = alloc.usable_frames().skip(alloc.next + 2);
+            match (frames.next(), frames.nth(3)) {
+                (Some(s), Some(e)) => (s.start_address().into(), e.start_address().into()),
+                _ => panic!("not enough usable frames"),
+            }
+        };
+        assert_eq!(reserve_range(start, end), Ok(()));
+
+        for _ in 0..64 {
+            let addr = match alloc.lock().allocate() {
+                Some(f) => PhysAddr::from(f.start_address()),
+                None => panic!("could not allocate frame"),
+            };
+            assert!(addr < start || addr >= end);
+        }
+    }
+
+    #[test_case]
+    fn reservation_slots_run_out() {
+        let mut alloc = BootInfoAllocator {
+            memory_map: &OVERLAPPING_MAP,
+            next: 0,
+            verify: None,
+            reserved: [None; MAX_RESERVED],
+            free_list: None,
+            free_frames: 0,
+        };
+
+        let addr = PhysAddr::new(0x10_0000);
+        for _ in 0..MAX_RESERVED {
+            assert_eq!(alloc.reserve(addr, addr + 4096u64), Ok(()));
+        }
+        assert_eq!(
+            alloc.reserve(addr, addr + 4096u64),
+            Err(ReserveError::TooManyRanges)
+        );
+        assert_eq!(
+            alloc.reserve(addr + 4096u64, addr),
+            Err(ReserveError::InvalidRange)
+        );
+    }
+
+    #[test_case]
+    fn invariants_catch_overlapping_regions() {
+        let alloc = BootInfoAllocator {
+            memory_map: &OVERLAPPING_MAP,
+            next: 0,
+            verify: None,
+            reserved: [None; MAX_RESERVED],
+            free_list: None,
+            free_frames: 0,
+        };
+
+        match alloc.check_invariants() {
+            Ok(_) => panic!("overlapping regions were not detected"),
+            Err(InvariantError::OverlappingRegions(addr)) => assert_eq!(addr.as_u64(), 0x18_0000),
+            Err(err) => panic!("unexpected error: {:?}", err),
+        }
+    }
+
+    #[test_case]
+    fn invariants_catch_corrupt_cursor() {
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+
+        let mut corrupt = {
+            let alloc = alloc.lock();
+            BootInfoAllocator {
+                memory_map: alloc.memory_map,
+                next: alloc.next,
+                verify: None,
+                reserved: [None; MAX_RESERVED],
+                free_list: None,
+                free_frames: 0,
+            }
+        };
+        match corrupt.check_invariants() {
+            Ok(_) => {}
+            Err(err) => panic!("valid allocator failed invariants: {:?}", err),
+        }
+
+        corrupt.next = usize::MAX;
+        match corrupt.check_invariants() {
+            Ok(_) => panic!("corrupt cursor was not detected"),
+            Err(InvariantError::CursorOutOfRange(next)) => assert_eq!(next, usize::MAX),
+            Err(err) => panic!("unexpected error: {:?}", err),
+        }
+    }
+
+    #[test_case]
+    fn invariants_catch_corrupt_free_list() {
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+        let frame = match alloc.lock().allocate() {
+            Some(f) => f,
+            None => panic!("could not allocate frame"),
+        };
+
+        // Link the frame to an address far outside the memory map
+        let outside = PhysAddr::new(0xFD_0000_0000);
+        unsafe { free_link(frame).write_volatile(outside.as_u64()) }; // The frame is ours until it's freed
+        let corrupt = BootInfoAllocator {
+            memory_map: alloc.lock().memory_map,
+            next: 0,
+            verify: None,
+            reserved: [None; MAX_RESERVED],
+            free_list: Some(frame),
+            free_frames: 2,
+        };
+        let result = corrupt.check_invariants();
+        unsafe { alloc.lock().deallocate(frame) }; // Nothing uses the frame
+
+        match result {
+            Ok(_) => panic!("corrupt free list was not detected"),
+            Err(InvariantError::FreeListCorrupt(addr)) => assert_eq!(addr, outside),
+            Err(err) => panic!("unexpected error: {:?}", err),
+        }
+    }
+
+    #[test_case]
+    fn dropped_frame_guard_deallocates() {
+        let mut alloc = stack_allocator();
+
+        let frame = match allocate_guarded(&mut alloc) {
+            Some(guard) => guard.frame(),
+            None => panic!("could not allocate frame"),
+        };
+
+        assert_eq!(alloc.frames.len(), 2);
+        assert_eq!(alloc.frames.last(), Some(&frame));
+    }
+
+    #[test_case]
+    fn leaked_frame_guard_stays_allocated() {
+        let mut alloc = stack_allocator();
+
+        let frame = match allocate_guarded(&mut alloc) {
+            Some(guard) => guard.leak(),
+            None => panic!("could not allocate frame"),
+        };
+
+        assert_eq!(alloc.frames.len(), 1);
+        assert!(!alloc.frames.contains(&frame));
+    }
+
+    #[test_case]
+    fn huge_frame_is_aligned_and_allocated() {
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+
+        let huge = match alloc.lock().allocate_huge_2m() {
+            Some(f) => f,
+            None => panic!("could not allocate huge frame"),
+        };
+        let start = huge.start_address().as_u64();
+        assert_eq!(start % 0x20_0000, 0);
+
+        // Every frame covered by the huge frame is behind the cursor
+        match alloc.lock().allocate() {
+            Some(f) => assert!(f.start_address().as_u64() >= start + 0x20_0000),
+            None => panic!("could not allocate frame"),
+        }
+        assert_invariants();
+    }
+
+    #[test_case]
+    fn deallocated_frames_are_reused() {
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+
+        let mut frames = Vec::new();
+        for _ in 0..4 {
+            match alloc.lock().allocate() {
+                Some(f) => frames.push(f),
+                None => panic!("could not allocate frame"),
+            }
+        }
+        let allocated = alloc.lock().allocated_frames();
+
+        for &frame in frames.iter() {
+            unsafe { alloc.lock().deallocate(frame) }; // Nothing uses the frames
+        }
+        assert_eq!(alloc.lock().allocated_frames(), allocated - frames.len());
+        assert_invariants();
+
+        // The free list hands back the most recently freed frame first
+        for &frame in frames.iter().rev() {
+            assert_eq!(alloc.lock().allocate(), Some(frame));
+        }
+        assert_eq!(alloc.lock().allocated_frames(), allocated);
+        assert_invariants();
+    }
+
+    #[test_case]
+    fn allocate_low_reuses_freed_frames() {
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+
+        let mut frames = [None; 2];
+        for slot in frames.iter_mut() {
+            *slot = alloc.lock().allocate();
+        }
+        let (low, high) = match frames {
+            [Some(a), Some(b)] if a < b => (a, b),
+            [Some(a), Some(b)] => (b, a),
+            _ => panic!("could not allocate frame"),
+        };
+        unsafe {
+            // Nothing uses the frames, `low` ends up behind `high` on the list
+            alloc.lock().deallocate(low);
+            alloc.lock().deallocate(high);
+        }
+
+        let bound = PhysAddr::from(low.start_address()) + Size4KiB::SIZE;
+        assert_eq!(alloc.lock().allocate_low(bound), Some(low));
+        assert_eq!(alloc.lock().allocate(), Some(high));
+        assert_invariants();
+    }
+
+    #[test_case]
+    fn allocation_reduces_free_frames() {
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+        let before = match memory_stats() {
+            Some(s) => s,
+            None => panic!("boot info allocator not initialized"),
+        };
+
+        let frame = match alloc.lock().allocate() {
+            Some(f) => f,
+            None => panic!("could not allocate frame"),
+        };
+        let after = match memory_stats() {
+            Some(s) => s,
+            None => panic!("boot info allocator not initialized"),
+        };
+        unsafe { alloc.lock().deallocate(frame) }; // Nothing uses the frame
+
+        assert_eq!(after.total_frames, before.total_frames);
+        assert_eq!(after.used_frames, before.used_frames + 1);
+        assert_eq!(after.free_frames, before.free_frames - 1);
+    }
+
+    #[test_case]
+    fn contiguous_frames_are_adjacent() {
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+
+        let range = match alloc.lock().allocate_contiguous(4) {
+            Some(r) => r,
+            None => panic!("could not allocate contiguous frames"),
+        };
+
+        assert_eq!(range.count(), 4);
+        for (frame, next) in range.zip(range.skip(1)) {
+            assert_eq!(next.start_address() - frame.start_address(), 4096);
+        }
+        for frame in range {
+            unsafe { alloc.lock().deallocate(frame) }; // Nothing uses the frames
+        }
+    }
+
+    #[test_case]
+    fn aligned_frame_is_aligned() {
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+
+        let frame = match alloc.lock().allocate_aligned(Size2MiB::SIZE) {
+            Some(f) => f,
+            None => panic!("could not allocate aligned frame"),
+        };
+
+        assert!(frame.start_address().is_aligned(Size2MiB::SIZE));
+        unsafe { alloc.lock().deallocate(frame) }; // Nothing uses the frame
+    }
+
+    #[test_case]
+    fn bitmap_reuses_freed_frame() {
+        let mut alloc = unsafe { BitmapAllocator::new(&SPLIT_MAP) }; // The frames are never touched
+
+        let mut frames = Vec::new();
+        while let Some(frame) = alloc.allocate() {
+            frames.push(frame.start_address().as_u64());
+        }
+        assert_eq!(
+            frames,
+            [0x10_0000, 0x10_1000, 0x10_2000, 0x10_3000, 0x20_0000, 0x20_1000]
+        );
+
+        let freed = PhysFrame::containing_address(PhysAddr::new(0x10_2000).into());
+        unsafe { alloc.deallocate(freed) }; // The frames are never touched
+        assert_eq!(alloc.allocated_frames(), 5);
+        assert_eq!(alloc.allocate(), Some(freed));
+        assert_eq!(alloc.allocate(), None);
+    }
+
+    #[test_case]
+    fn bitmap_keeps_boot_info_allocations() {
+        let mut boot_alloc = BootInfoAllocator {
+            memory_map: &SPLIT_MAP,
+            next: 0,
+            verify: None,
+            reserved: [None; MAX_RESERVED],
+            free_list: None,
+            free_frames: 0,
+        };
+        if let Err(err) = boot_alloc.reserve(PhysAddr::new(0x20_1000), PhysAddr::new(0x20_2000)) {
+            panic!("reservation failed: {:?}", err);
+        }
+        for _ in 0..2 {
+            if boot_alloc.allocate().is_none() {
+                panic!("could not allocate frame");
+            }
+        }
+
+        let mut alloc = BitmapAllocator::from_boot_info_allocator(boot_alloc);
+        let mut frames = Vec::new();
+        while let Some(frame) = alloc.allocate() {
+            frames.push(frame.start_address().as_u64());
+        }
+        assert_eq!(frames, [0x10_2000, 0x10_3000, 0x20_0000]);
     }
 }
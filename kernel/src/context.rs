@@ -0,0 +1,100 @@
+use core::arch::global_asm;
+
+/// Saved state of a suspended kernel thread
+///
+/// The callee saved registers live on the thread's own stack, pushed there by `switch_context`
+#[derive(Debug)]
+#[repr(C)]
+pub struct Context {
+    rsp: u64,
+}
+
+impl Context {
+    pub const fn new() -> Context {
+        Context { rsp: 0 }
+    }
+
+    /// Set up `stack` so that switching to the returned context starts running `entry`
+    /// with interrupts enabled
+    pub fn kernel_thread(stack: &mut [u8], entry: fn() -> !) -> Context {
+        let top = (stack.as_mut_ptr() as u64 + stack.len() as u64) & !0xF;
+
+        // Popped by switch_context, with the trampoline as the return address
+        let frame: [u64; 7] = [
+            0,                     // r15
+            0,                     // r14
+            0,                     // r13
+            entry as usize as u64, // r12
+            0,                     // rbx
+            0,                     // rbp
+            kernel_thread_trampoline as *const () as u64,
+        ];
+
+        let rsp = top - 8 * frame.len() as u64;
+        unsafe { core::ptr::copy_nonoverlapping(frame.as_ptr(), rsp as *mut u64, frame.len()) }; // The frame fits inside the stack, below the aligned top
+
+        Context { rsp }
+    }
+}
+
+extern "C" {
+    /// Save the running thread's callee saved registers and stack pointer into `old`,
+    /// then resume the thread saved in `new`
+    pub fn switch_context(old: *mut Context, new: *const Context);
+
+    fn kernel_thread_trampoline();
+}
+
+global_asm!(
+    ".global switch_context",
+    "switch_context:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, [rsi]",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+    "",
+    ".global kernel_thread_trampoline",
+    "kernel_thread_trampoline:",
+    "mov rdi, r12",
+    "call kernel_thread_start",
+);
+
+#[no_mangle]
+extern "C" fn kernel_thread_start(entry: u64) -> ! {
+    let entry: fn() -> ! = unsafe { core::mem::transmute(entry as usize) }; // This was a fn() -> ! before being stored in the thread's initial frame
+
+    x86_64::instructions::interrupts::enable();
+    entry()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Context;
+
+    fn never_run() -> ! {
+        panic!("test thread entry should never run");
+    }
+
+    #[test_case]
+    fn kernel_thread_frame() {
+        let mut stack = [0u8; 256];
+        let top = (stack.as_ptr() as u64 + stack.len() as u64) & !0xF;
+
+        let context = Context::kernel_thread(&mut stack, never_run);
+        assert_eq!(context.rsp, top - 56);
+
+        let r12 = unsafe { *((context.rsp + 24) as *const u64) };
+        assert_eq!(r12, never_run as *const () as u64);
+    }
+}
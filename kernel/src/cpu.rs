@@ -0,0 +1,95 @@
+use core::arch::{asm, x86_64::__cpuid};
+
+use x86_64::registers::{
+    control::Cr2,
+    model_specific::{Efer, EferFlags},
+};
+
+use crate::virt_addr::VirtAddr;
+
+/// Read the address which caused the most recent page fault
+#[inline]
+pub fn read_cr2() -> VirtAddr {
+    VirtAddr::new(Cr2::read().as_u64())
+}
+
+/// Overwrite cr2
+///
+/// This is unsafe because the page fault handler relies on cr2 holding the faulting address
+#[inline]
+pub unsafe fn write_cr2(addr: VirtAddr) {
+    asm!("mov cr2, {}", in(reg) addr.as_u64(), options(nostack, preserves_flags));
+}
+
+/// The address of the instruction after this call, it's always inlined so that's in the caller
+#[inline(always)]
+pub fn current_rip() -> u64 {
+    let rip: u64;
+    unsafe {
+        asm!("lea {}, [rip]", out(reg) rip, options(nomem, nostack, preserves_flags));
+    }
+
+    rip
+}
+
+/// The initial APIC id of the cpu this runs on
+#[inline]
+pub fn id() -> u32 {
+    let features = unsafe { __cpuid(1) }; // cpuid leaf 1 is always available on x86_64
+    features.ebx >> 24
+}
+
+/// Whether the cpu supports the NO_EXECUTE page table bit
+#[inline]
+pub fn nx_supported() -> bool {
+    let features = unsafe { __cpuid(0x8000_0001) }; // The extended feature leaf is always available on x86_64
+    features.edx & (1 << 20) != 0
+}
+
+/// Whether NO_EXECUTE bits in page table entries are being enforced
+#[inline]
+pub fn nx_enabled() -> bool {
+    Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE)
+}
+
+/// Turn on NO_EXECUTE enforcement if the cpu supports it, returning whether it's enabled
+///
+/// Without this set the NO_EXECUTE bit is reserved and any entry with it set causes a page fault
+pub fn enable_nx() -> bool {
+    if !nx_supported() {
+        return false;
+    }
+
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE)) }; // The cpu supports NX so setting NXE is valid
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::virt_addr::VirtAddr;
+
+    use super::{current_rip, read_cr2, write_cr2};
+
+    #[test_case]
+    fn cr2_round_trip() {
+        let previous = read_cr2();
+        let addr = VirtAddr::new(0xDEAD_B000);
+
+        unsafe { write_cr2(addr) };
+        assert_eq!(read_cr2(), addr);
+
+        unsafe { write_cr2(previous) };
+    }
+
+    /// The start of this function and the rip `current_rip` reports inside it
+    #[inline(never)]
+    fn start_and_rip() -> (u64, u64) {
+        (start_and_rip as *const () as u64, current_rip())
+    }
+
+    #[test_case]
+    fn current_rip_is_in_caller() {
+        let (start, rip) = start_and_rip();
+        assert!(rip > start && rip < start + 0x100);
+    }
+}
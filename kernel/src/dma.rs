@@ -0,0 +1,220 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::allocator::{FrameAllocator, FrameDeallocator, FRAME_ALLOCATOR};
+use crate::cpu;
+use crate::memory::load_active_pagetable;
+use crate::pagetable::PageMapError;
+use crate::paging::{Page, PageRangeInclusive, PageTableEntry, PageTableEntryFlags, Phys};
+use crate::phys_addr::PhysAddr;
+use crate::virt_addr::VirtAddr;
+
+/// An otherwise unused region of the address space which DMA buffers are mapped into
+const DMA_WINDOW_START: u64 = 0x6666_0000_0000;
+const PAGE_SIZE: u64 = 4096;
+
+/// The next free address in the DMA window, buffers are never packed back together
+static NEXT_DMA_ADDR: AtomicU64 = AtomicU64::new(DMA_WINDOW_START);
+
+#[derive(Debug)]
+pub enum DmaError {
+    /// Not enough physically contiguous frames were free
+    FrameAllocation,
+    Map(PageMapError),
+}
+
+/// Physically contiguous memory for devices to read and write directly
+///
+/// The frames are mapped uncached into the DMA window and unmapped when the buffer is dropped
+pub struct DmaBuffer {
+    virt: VirtAddr,
+    phys: PhysAddr,
+    pages: usize,
+}
+
+impl DmaBuffer {
+    /// Allocate `pages` contiguous frames and map them into the DMA window
+    pub fn new(pages: usize) -> Result<Self, DmaError> {
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => return Err(DmaError::FrameAllocation),
+        };
+        let mut alloc = alloc.lock();
+        let start = match alloc.allocate_contiguous(pages) {
+            Some(r) => r.start,
+            None => return Err(DmaError::FrameAllocation),
+        };
+
+        let virt =
+            VirtAddr::new(NEXT_DMA_ADDR.fetch_add(pages as u64 * PAGE_SIZE, Ordering::Relaxed));
+        let mut flags = PageTableEntryFlags::mmio();
+        if cpu::nx_enabled() {
+            flags |= PageTableEntryFlags::NO_EXECUTE;
+        }
+
+        let table = unsafe { load_active_pagetable() };
+        let first = Page::containing_address(virt);
+        for i in 0..pages as u64 {
+            let entry = PageTableEntry::new(start + i, flags);
+            let result = unsafe { table.map_page(first + i, entry, &mut *alloc) }; // The frames were just allocated so nothing else references them
+            if let Err(err) = result {
+                // The failed page may belong to someone else, only unmap the ones before it
+                if i > 0 {
+                    table.unmap_range(PageRangeInclusive::new(first, first + (i - 1)), &mut |_| {});
+                }
+                for j in 0..pages as u64 {
+                    unsafe { alloc.deallocate(start + j) }; // None of the frames are mapped any more
+                }
+                return Err(DmaError::Map(err));
+            }
+        }
+
+        Ok(DmaBuffer {
+            virt,
+            phys: start.start_address().into(),
+            pages,
+        })
+    }
+
+    /// The physical address of the first byte, for handing to a device
+    #[inline]
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    #[inline]
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.virt
+    }
+
+    /// The size of the buffer in bytes
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.pages * PAGE_SIZE as usize
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.pages == 0
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.virt.as_ptr(), self.len()) } // Mapped while self lives
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        let len = self.len();
+        unsafe { core::slice::from_raw_parts_mut(self.virt.as_mut_ptr(), len) } // Mapped while self lives
+    }
+}
+
+impl Drop for DmaBuffer {
+    /// Unmap the buffer and return its frames to the allocator
+    fn drop(&mut self) {
+        let first = Page::containing_address(self.virt);
+        let table = unsafe { load_active_pagetable() };
+        table.unmap_range(
+            PageRangeInclusive::new(first, first + self.pages as u64),
+            &mut |frame| {
+                if let (Phys::Size4Kb(f), Some(alloc)) = (frame, FRAME_ALLOCATOR.wait()) {
+                    unsafe { alloc.lock().deallocate(f) }; // The buffer owned the frame and it's now unmapped
+                }
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::Ordering;
+    use x86_64::structures::paging::{PageSize, Size4KiB};
+
+    use crate::{
+        allocator::{FrameAllocator, FrameDeallocator, FRAME_ALLOCATOR},
+        memory::{get_offset, load_active_pagetable},
+        pagetable::PageMapError,
+        paging::{Page, PageTableEntry, PageTableEntryFlags, Phys},
+        virt_addr::VirtAddr,
+    };
+
+    use super::{DmaBuffer, DmaError, NEXT_DMA_ADDR};
+
+    #[test_case]
+    fn two_page_buffer_is_contiguous() {
+        let mut buffer = match DmaBuffer::new(2) {
+            Ok(b) => b,
+            Err(err) => panic!("dma allocation failed: {:?}", err),
+        };
+        let phys = buffer.phys_addr();
+        assert!(phys.is_aligned(Size4KiB::SIZE));
+        assert_eq!(buffer.len(), 2 * Size4KiB::SIZE as usize);
+
+        let table = unsafe { load_active_pagetable() };
+        assert_eq!(table.translate_addr(buffer.virt_addr()), Some(phys));
+        assert_eq!(
+            table.translate_addr(buffer.virt_addr() + Size4KiB::SIZE),
+            Some(phys + Size4KiB::SIZE)
+        );
+
+        let len = buffer.len();
+        let slice = buffer.as_mut_slice();
+        slice[0] = 0xAB;
+        slice[len - 1] = 0xCD;
+        let first: *const u8 = (get_offset() + phys.as_u64()).as_ptr();
+        let last: *const u8 = (get_offset() + phys.as_u64() + (len - 1) as u64).as_ptr();
+        unsafe {
+            assert_eq!(first.read_volatile(), 0xAB);
+            assert_eq!(last.read_volatile(), 0xCD);
+        }
+    }
+
+    #[test_case]
+    fn drop_unmaps_buffer() {
+        let buffer = match DmaBuffer::new(1) {
+            Ok(b) => b,
+            Err(err) => panic!("dma allocation failed: {:?}", err),
+        };
+        let virt = buffer.virt_addr();
+        drop(buffer);
+
+        let table = unsafe { load_active_pagetable() };
+        assert!(table.translate_addr(virt).is_none());
+    }
+
+    #[test_case]
+    fn failed_map_frees_every_frame() {
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+        let frame = match alloc.lock().allocate() {
+            Some(f) => f,
+            None => panic!("could not allocate frame"),
+        };
+
+        // Take the page the next buffer starts at, so neither of its frames gets mapped
+        let table = unsafe { load_active_pagetable() };
+        let page = Page::containing_address(VirtAddr::new(NEXT_DMA_ADDR.load(Ordering::Relaxed)));
+        let entry = PageTableEntry::new(frame, PageTableEntryFlags::kernel_rw());
+        match unsafe { table.map_page(page, entry, &mut *alloc.lock()) } {
+            Ok(_) => {}
+            Err(err) => panic!("error mapping page: {:?}", err),
+        }
+        let allocated = alloc.lock().allocated_frames();
+
+        match DmaBuffer::new(2) {
+            Err(DmaError::Map(PageMapError::PageAlreadyMapped)) => {}
+            Err(err) => panic!("unexpected error: {:?}", err),
+            Ok(_) => panic!("dma buffer mapped over an existing page"),
+        }
+        assert_eq!(alloc.lock().allocated_frames(), allocated);
+        assert_eq!(
+            table.translate_addr(page.as_virt_addr()),
+            Some(frame.start_address().into())
+        );
+
+        match table.unmap_page(page) {
+            Ok(Phys::Size4Kb(f)) => unsafe { alloc.lock().deallocate(f) }, // The test owned the frame
+            result => panic!("unexpected unmap result: {:?}", result),
+        }
+    }
+}
@@ -0,0 +1,224 @@
+use core::convert::TryInto;
+
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 0x3E;
+const PT_LOAD: u32 = 1;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+/// Segment permission flags
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+
+#[derive(Debug, PartialEq)]
+pub enum ElfError {
+    TooShort,
+    BadMagic,
+    /// Not a little endian x86_64 ELF64 executable
+    Unsupported,
+    /// A program header or segment lies outside the file
+    OutOfBounds,
+}
+
+/// A loadable segment, `mem_size - file_size` bytes past the file data are zeroed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Segment {
+    pub vaddr: u64,
+    pub offset: u64,
+    pub file_size: u64,
+    pub mem_size: u64,
+    pub flags: u32,
+}
+
+impl Segment {
+    #[inline]
+    pub fn writable(&self) -> bool {
+        self.flags & PF_W != 0
+    }
+
+    #[inline]
+    pub fn executable(&self) -> bool {
+        self.flags & PF_X != 0
+    }
+}
+
+/// A statically linked ELF64 executable, only the program headers are read
+pub struct Elf<'a> {
+    bytes: &'a [u8],
+    entry: u64,
+    phoff: usize,
+    phnum: usize,
+}
+
+impl<'a> Elf<'a> {
+    /// Check the header and every load segment lie within `bytes`
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, ElfError> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(ElfError::TooShort);
+        }
+        if bytes[0..4] != ELF_MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if bytes[4] != ELFCLASS64
+            || bytes[5] != ELFDATA2LSB
+            || read_u16(bytes, 16) != ET_EXEC
+            || read_u16(bytes, 18) != EM_X86_64
+            || read_u16(bytes, 54) as usize != PROGRAM_HEADER_SIZE
+        {
+            return Err(ElfError::Unsupported);
+        }
+
+        let elf = Elf {
+            bytes,
+            entry: read_u64(bytes, 24),
+            phoff: read_u64(bytes, 32) as usize,
+            phnum: read_u16(bytes, 56) as usize,
+        };
+
+        let headers_end = elf
+            .phnum
+            .checked_mul(PROGRAM_HEADER_SIZE)
+            .and_then(|size| size.checked_add(elf.phoff));
+        match headers_end {
+            Some(end) if end <= bytes.len() => {}
+            _ => return Err(ElfError::OutOfBounds),
+        }
+
+        for segment in elf.load_segments() {
+            let in_file = match segment.offset.checked_add(segment.file_size) {
+                Some(end) => end <= bytes.len() as u64,
+                None => false,
+            };
+            if !in_file || segment.file_size > segment.mem_size {
+                return Err(ElfError::OutOfBounds);
+            }
+        }
+
+        Ok(elf)
+    }
+
+    #[inline]
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    /// Iterate the segments which need loading into memory
+    pub fn load_segments(&self) -> impl Iterator<Item = Segment> + '_ {
+        (0..self.phnum)
+            .map(move |i| self.phoff + i * PROGRAM_HEADER_SIZE)
+            .filter(move |&header| read_u32(self.bytes, header) == PT_LOAD)
+            .map(move |header| Segment {
+                flags: read_u32(self.bytes, header + 4),
+                offset: read_u64(self.bytes, header + 8),
+                vaddr: read_u64(self.bytes, header + 16),
+                file_size: read_u64(self.bytes, header + 32),
+                mem_size: read_u64(self.bytes, header + 40),
+            })
+    }
+
+    /// The bytes of `segment` stored in the file
+    pub fn segment_data(&self, segment: &Segment) -> &'a [u8] {
+        let start = segment.offset as usize;
+        &self.bytes[start..start + segment.file_size as usize]
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap()) // The slice is always 4 bytes
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap()) // The slice is always 8 bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Elf, ElfError, Segment, HEADER_SIZE, PF_X, PROGRAM_HEADER_SIZE};
+
+    const IMAGE_SIZE: usize = HEADER_SIZE + PROGRAM_HEADER_SIZE + 2;
+    const BASE: u64 = 0x2000_0000_0000;
+
+    fn put(image: &mut [u8], offset: usize, bytes: &[u8]) {
+        image[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    /// An executable with a single segment holding `jmp $`
+    fn image() -> [u8; IMAGE_SIZE] {
+        let mut image = [0; IMAGE_SIZE];
+        let code = HEADER_SIZE + PROGRAM_HEADER_SIZE;
+        put(&mut image, 0, &[0x7F, b'E', b'L', b'F', 2, 1, 1]);
+        put(&mut image, 16, &2u16.to_le_bytes());
+        put(&mut image, 18, &0x3Eu16.to_le_bytes());
+        put(&mut image, 24, &(BASE + code as u64).to_le_bytes());
+        put(&mut image, 32, &(HEADER_SIZE as u64).to_le_bytes());
+        put(&mut image, 54, &(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+        put(&mut image, 56, &1u16.to_le_bytes());
+
+        put(&mut image, HEADER_SIZE, &1u32.to_le_bytes());
+        put(&mut image, HEADER_SIZE + 4, &PF_X.to_le_bytes());
+        put(&mut image, HEADER_SIZE + 16, &BASE.to_le_bytes());
+        put(
+            &mut image,
+            HEADER_SIZE + 32,
+            &(IMAGE_SIZE as u64).to_le_bytes(),
+        );
+        put(&mut image, HEADER_SIZE + 40, &0x2000u64.to_le_bytes());
+        put(&mut image, code, &[0xEB, 0xFE]);
+
+        image
+    }
+
+    #[test_case]
+    fn parse_single_segment() {
+        let image = image();
+        let elf = match Elf::parse(&image) {
+            Ok(e) => e,
+            Err(err) => panic!("valid image was rejected: {:?}", err),
+        };
+
+        assert_eq!(
+            elf.entry(),
+            BASE + (HEADER_SIZE + PROGRAM_HEADER_SIZE) as u64
+        );
+        let mut segments = elf.load_segments();
+        let segment = segments.next();
+        assert_eq!(
+            segment,
+            Some(Segment {
+                vaddr: BASE,
+                offset: 0,
+                file_size: IMAGE_SIZE as u64,
+                mem_size: 0x2000,
+                flags: PF_X,
+            })
+        );
+        assert!(segments.next().is_none());
+        assert_eq!(elf.segment_data(&segment.unwrap()).len(), IMAGE_SIZE);
+    }
+
+    #[test_case]
+    fn reject_malformed_images() {
+        let image = image();
+        assert!(matches!(Elf::parse(&image[..32]), Err(ElfError::TooShort)));
+
+        let mut bad_magic = image;
+        bad_magic[1] = b'X';
+        assert!(matches!(Elf::parse(&bad_magic), Err(ElfError::BadMagic)));
+
+        let mut elf32 = image;
+        elf32[4] = 1;
+        assert!(matches!(Elf::parse(&elf32), Err(ElfError::Unsupported)));
+
+        let mut truncated = image;
+        truncated[HEADER_SIZE + 32] = 0xFF;
+        assert!(matches!(Elf::parse(&truncated), Err(ElfError::OutOfBounds)));
+    }
+}
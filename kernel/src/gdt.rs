@@ -1,36 +1,67 @@
+use core::{
+    arch::asm,
+    ptr::{addr_of, addr_of_mut},
+};
+
 use lazy_static::lazy_static;
+use x86_64::instructions::interrupts;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
+use x86_64::structures::DescriptorTablePointer;
 use x86_64::VirtAddr;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-
-        tss
+/// The GDTR limit of the kernel's GDT: the null entry, the code segment, the two entry TSS descriptor
+/// and the user data & code segments
+const GDT_LIMIT: u16 = 6 * 8 - 1;
+
+const STACK_SIZE: usize = 4096 * 5;
+
+/// A stack for the TSS, aligned so its top is on the 16 byte boundary interrupt frames expect
+#[allow(dead_code)]
+#[repr(align(16))]
+struct Stack([u8; STACK_SIZE]);
+
+/// The kernel's TSS, only written by `init_tss` and `set_kernel_interrupt_stack`
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+/// Give the TSS its double fault and ring 0 stacks, this runs once while the GDT is built
+fn init_tss() -> &'static TaskStateSegment {
+    let tss = unsafe { &mut *addr_of_mut!(TSS) }; // Only the GDT's initialization gets here, before the TSS is loaded
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
+        static mut STACK: Stack = Stack([0; STACK_SIZE]);
+
+        let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+        let stack_end = stack_start + STACK_SIZE;
+        stack_end
     };
+    // The stack the cpu switches to when an interrupt arrives from ring 3, until a process
+    // replaces it with its own kernel stack
+    tss.privilege_stack_table[0] = {
+        static mut STACK: Stack = Stack([0; STACK_SIZE]);
+
+        let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+        stack_start + STACK_SIZE
+    };
+
+    tss
 }
 
 lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(init_tss()));
+        let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+        let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
         (
             gdt,
             Selectors {
                 code_selector,
                 tss_selector,
+                user_code_selector,
+                user_data_selector,
             },
         )
     };
@@ -39,6 +70,8 @@ lazy_static! {
 struct Selectors {
     code_selector: SegmentSelector,
     tss_selector: SegmentSelector,
+    user_code_selector: SegmentSelector,
+    user_data_selector: SegmentSelector,
 }
 
 pub fn init() {
@@ -52,3 +85,113 @@ pub fn init() {
         load_tss(GDT.1.tss_selector);
     }
 }
+
+/// The ring 3 code segment selector, with RPL 3
+pub fn user_code_selector() -> SegmentSelector {
+    GDT.1.user_code_selector
+}
+
+/// The ring 3 data & stack segment selector, with RPL 3
+pub fn user_data_selector() -> SegmentSelector {
+    GDT.1.user_data_selector
+}
+
+/// The top of the stack used for interrupts from ring 3
+pub fn kernel_interrupt_stack() -> VirtAddr {
+    unsafe { (*addr_of!(TSS)).privilege_stack_table[0] } // Only written with interrupts disabled
+}
+
+/// Make interrupts from ring 3 arrive on the stack ending at `top`
+///
+/// Each process traps onto its own kernel stack, so a process switched away in the middle of a
+/// syscall keeps its frames. This is unsafe as the stack must stay mapped until it's replaced
+pub unsafe fn set_kernel_interrupt_stack(top: VirtAddr) {
+    interrupts::without_interrupts(|| {
+        (*addr_of_mut!(TSS)).privilege_stack_table[0] = top;
+    })
+}
+
+#[derive(Debug, PartialEq)]
+pub enum GdtError {
+    /// GDTR doesn't point at the kernel's GDT
+    WrongBase(u64),
+    WrongLimit(u16),
+    WrongCodeSelector(u16),
+    /// The task register doesn't hold the kernel's TSS selector
+    WrongTssSelector(u16),
+}
+
+/// Check GDTR, CS and the task register match the kernel's GDT and TSS
+pub fn verify_loaded() -> Result<(), GdtError> {
+    use x86_64::instructions::segmentation::Segment;
+    use x86_64::registers::segmentation::CS;
+
+    let gdtr = read_gdtr();
+    let base = gdtr.base.as_u64();
+    if base != &GDT.0 as *const GlobalDescriptorTable as u64 {
+        return Err(GdtError::WrongBase(base));
+    }
+
+    let limit = gdtr.limit;
+    if limit != GDT_LIMIT {
+        return Err(GdtError::WrongLimit(limit));
+    }
+
+    let code = CS::get_reg();
+    if code != GDT.1.code_selector {
+        return Err(GdtError::WrongCodeSelector(code.0));
+    }
+
+    let tr = read_tr();
+    if tr != GDT.1.tss_selector.0 {
+        return Err(GdtError::WrongTssSelector(tr));
+    }
+
+    Ok(())
+}
+
+fn read_gdtr() -> DescriptorTablePointer {
+    let mut gdtr = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::new(0),
+    };
+    unsafe {
+        asm!("sgdt [{}]", in(reg) &mut gdtr, options(nostack, preserves_flags));
+    }
+
+    gdtr
+}
+
+fn read_tr() -> u16 {
+    let tr: u16;
+    unsafe {
+        asm!("str {0:x}", out(reg) tr, options(nomem, nostack, preserves_flags));
+    }
+
+    tr
+}
+
+#[cfg(test)]
+mod tests {
+    use x86_64::PrivilegeLevel;
+
+    use super::{kernel_interrupt_stack, user_code_selector, user_data_selector, verify_loaded};
+
+    #[test_case]
+    fn gdt_and_tss_loaded() {
+        assert_eq!(verify_loaded(), Ok(()));
+    }
+
+    #[test_case]
+    fn kernel_interrupt_stack_is_set() {
+        let stack = kernel_interrupt_stack();
+        assert_ne!(stack.as_u64(), 0);
+        assert!(stack.is_aligned(16u64));
+    }
+
+    #[test_case]
+    fn user_selectors_are_ring_3() {
+        assert_eq!(user_code_selector().rpl(), PrivilegeLevel::Ring3);
+        assert_eq!(user_data_selector().rpl(), PrivilegeLevel::Ring3);
+    }
+}
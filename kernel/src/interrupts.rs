@@ -1,11 +1,18 @@
-use crate::{gdt, hlt_loop, print, println};
+use core::arch::global_asm;
+
+use crate::{
+    allocator::FRAME_ALLOCATOR,
+    cpu, gdt, hlt_loop, keyboard,
+    memory::load_active_pagetable,
+    println, process, profiler, syscall,
+    trap::{TrapFrame, SYSCALL_VECTOR},
+    watchdog,
+};
 use lazy_static::lazy_static;
-use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
 use pic8259::ChainedPics;
-use spin::{self, Mutex};
 use x86_64::instructions::port::Port;
-use x86_64::registers::control::Cr2;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::PrivilegeLevel;
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
@@ -30,6 +37,13 @@ lazy_static! {
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
 
+        // Syscalls, which user code is allowed to raise
+        unsafe {
+            idt[SYSCALL_VECTOR as usize]
+                .set_handler_addr(x86_64::VirtAddr::new(syscall_entry as *const () as u64))
+                .set_privilege_level(PrivilegeLevel::Ring3);
+        }
+
         idt
     };
 }
@@ -70,8 +84,20 @@ extern "x86-interrupt" fn page_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
+    let cow_fault = PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+    if error_code.contains(cow_fault) {
+        // The fault may have interrupted the allocator's own holder, so don't wait for it. The
+        // fault is then reported below rather than deadlocking
+        if let Some(mut alloc) = FRAME_ALLOCATOR.wait().and_then(|a| a.try_lock()) {
+            let table = unsafe { load_active_pagetable() };
+            if table.resolve_cow(cpu::read_cr2(), &mut *alloc).is_ok() {
+                return;
+            }
+        }
+    }
+
     println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
+    println!("Accessed Address: {:?}", cpu::read_cr2());
     println!("Error Code: {:?}", error_code);
     println!("{:#?}", stack_frame);
 
@@ -82,32 +108,23 @@ extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
     println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+extern "x86-interrupt" fn timer_interrupt_handler(stack_frame: InterruptStackFrame) {
+    watchdog::on_timer_tick();
+    profiler::sample(stack_frame.instruction_pointer.as_u64());
+
     unsafe {
         PICS.lock()
             .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
     }
+
+    // After the EOI, otherwise no more ticks arrive until this thread runs again
+    process::preempt();
 }
 
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> = Mutex::new(
-            Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore)
-        );
-    }
-
-    let mut keyboard = KEYBOARD.lock();
     let mut port = Port::new(0x60);
-
     let scancode: u8 = unsafe { port.read() };
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::Unicode(character) => print!("{}", character),
-                DecodedKey::RawKey(key) => print!("{:?}", key),
-            }
-        }
-    }
+    keyboard::add_scancode(scancode);
 
     unsafe {
         PICS.lock()
@@ -119,3 +136,56 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
 fn test_breakpoint_exception() {
     x86_64::instructions::interrupts::int3();
 }
+
+extern "C" {
+    /// Save the general purpose registers as a `TrapFrame`, dispatch the syscall and return
+    /// with the result in rax
+    fn syscall_entry();
+}
+
+global_asm!(
+    ".global syscall_entry",
+    "syscall_entry:",
+    "push rax",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rbp",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    // The cpu's frame and the 15 registers keep the stack 16 byte aligned for the call
+    "mov rdi, rsp",
+    "call syscall_handler",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    "iretq",
+);
+
+#[no_mangle]
+extern "C" fn syscall_handler(frame: &mut TrapFrame) {
+    process::save_trap_frame(frame);
+    let [a1, a2, a3] = frame.syscall_args();
+    let ret = syscall::syscall(frame.syscall_number(), a1, a2, a3);
+    frame.set_return_value(ret);
+}
@@ -0,0 +1,84 @@
+use crossbeam_queue::ArrayQueue;
+use lazy_static::lazy_static;
+use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+use spin::{Mutex, Once};
+
+/// The most scancodes waiting to be read, any more are dropped
+const QUEUE_SIZE: usize = 128;
+
+/// Scancodes from the keyboard interrupt which haven't been read yet
+///
+/// The queue lives on the heap so it's only created by `init`, keystrokes before then are dropped
+static SCANCODE_QUEUE: Once<ArrayQueue<u8>> = Once::new();
+
+lazy_static! {
+    static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> = Mutex::new(
+        Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore)
+    );
+}
+
+/// Create the scancode queue, this must be called after the heap is initialized
+pub fn init() {
+    SCANCODE_QUEUE.call_once(|| ArrayQueue::new(QUEUE_SIZE));
+}
+
+/// Queue a scancode read by the keyboard interrupt handler
+///
+/// This never locks, allocates or waits on `init`, so it's safe to call from an interrupt handler
+pub(crate) fn add_scancode(scancode: u8) {
+    if let Some(queue) = SCANCODE_QUEUE.r#try() {
+        // The reader has fallen behind, so drop the keystroke
+        let _ = queue.push(scancode);
+    }
+}
+
+/// Take the oldest scancode the keyboard has sent, if any
+pub fn read_scancode() -> Option<u8> {
+    SCANCODE_QUEUE.r#try()?.pop()
+}
+
+/// Decode queued scancodes with the US layout until one completes a character
+///
+/// Keys which don't produce a character, e.g. shift or the arrows, are skipped.
+/// Returns None once the queue is empty
+pub fn read_char() -> Option<char> {
+    let mut keyboard = KEYBOARD.lock();
+    while let Some(scancode) = read_scancode() {
+        if let Ok(Some(event)) = keyboard.add_byte(scancode) {
+            if let Some(DecodedKey::Unicode(c)) = keyboard.process_keyevent(event) {
+                return Some(c);
+            }
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{add_scancode, init, read_char, read_scancode};
+
+    #[test_case]
+    fn scancodes_are_read_in_order() {
+        init();
+        add_scancode(0x1E);
+        add_scancode(0x9E);
+
+        assert_eq!(read_scancode(), Some(0x1E));
+        assert_eq!(read_scancode(), Some(0x9E));
+        assert_eq!(read_scancode(), None);
+    }
+
+    #[test_case]
+    fn decode_key_press() {
+        init();
+        // Press and release A, then release B which doesn't produce a character
+        for scancode in [0x1E, 0x9E, 0xB0] {
+            add_scancode(scancode);
+        }
+
+        assert_eq!(read_char(), Some('a'));
+        assert_eq!(read_char(), None);
+        assert_eq!(read_scancode(), None);
+    }
+}
@@ -0,0 +1,108 @@
+use core::{
+    fmt,
+    sync::atomic::{AtomicU8, AtomicUsize, Ordering},
+};
+
+use crate::serial;
+
+pub const LOG_CAPACITY: usize = 4096;
+
+/// A copy of everything printed, so recent history can be dumped after a panic
+pub static KERNEL_LOG: LogRing<LOG_CAPACITY> = LogRing::new();
+
+/// A fixed size byte ring which overwrites its oldest bytes once full
+///
+/// Writers never block, so it's safe to append from interrupt handlers and the panic path.
+/// Concurrent writers each get their own bytes, though their output may interleave
+pub struct LogRing<const N: usize> {
+    buf: [AtomicU8; N],
+    /// The total number of bytes ever written
+    head: AtomicUsize,
+}
+
+impl<const N: usize> LogRing<N> {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: AtomicU8 = AtomicU8::new(0);
+
+    pub const fn new() -> Self {
+        LogRing {
+            buf: [Self::EMPTY; N],
+            head: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn push(&self, bytes: &[u8]) {
+        for byte in bytes {
+            let pos = self.head.fetch_add(1, Ordering::AcqRel);
+            self.buf[pos % N].store(*byte, Ordering::Release);
+        }
+    }
+
+    /// Copy the retained bytes into `out` from oldest to newest, returning how many were copied
+    pub fn copy_to(&self, out: &mut [u8]) -> usize {
+        let head = self.head.load(Ordering::Acquire);
+        let len = head.min(N).min(out.len());
+        let start = head - len;
+
+        for (i, byte) in out[..len].iter_mut().enumerate() {
+            *byte = self.buf[(start + i) % N].load(Ordering::Acquire);
+        }
+
+        len
+    }
+
+    /// Write the retained bytes over serial without taking any locks
+    pub fn dump(&self) {
+        let head = self.head.load(Ordering::Acquire);
+        let start = head - head.min(N);
+
+        serial::early_print("--- log ---\n");
+        for pos in start..head {
+            serial::early_write(&[self.buf[pos % N].load(Ordering::Acquire)]);
+        }
+        serial::early_print("\n--- end of log ---\n");
+    }
+}
+
+/// Appends formatted output to `KERNEL_LOG`
+struct Recorder;
+
+impl fmt::Write for Recorder {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        KERNEL_LOG.push(s.as_bytes());
+        Ok(())
+    }
+}
+
+#[doc(hidden)]
+pub fn _record(args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    let _ = Recorder.write_fmt(args);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LogRing;
+
+    #[test_case]
+    fn oldest_entries_overwritten() {
+        let ring = LogRing::<16>::new();
+        ring.push(b"0123456789");
+        ring.push(b"abcdefghij");
+
+        let mut out = [0; 32];
+        let len = ring.copy_to(&mut out);
+        assert_eq!(&out[..len], b"456789abcdefghij");
+    }
+
+    #[test_case]
+    fn partially_filled_ring() {
+        let ring = LogRing::<16>::new();
+        ring.push(b"abc");
+
+        let mut out = [0; 32];
+        let len = ring.copy_to(&mut out);
+        assert_eq!(&out[..len], b"abc");
+    }
+}
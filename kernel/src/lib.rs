@@ -7,31 +7,84 @@
 #![reexport_test_harness_main = "test_main"]
 
 use bootloader::BootInfo;
-use core::{alloc::Layout, cmp::max, panic::PanicInfo};
+use core::{
+    alloc::Layout,
+    cmp::max,
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+use spin::Once;
 
 extern crate alloc;
 
 pub mod allocator;
+pub mod context;
+pub mod cpu;
+pub mod dma;
+pub mod elf;
 pub mod gdt;
 pub mod interrupts;
+pub mod keyboard;
+pub mod klog;
 pub mod memory;
 pub mod pagetable;
 pub mod paging;
+pub mod panic_action;
+pub mod pat;
+pub mod phys_addr;
 pub mod process;
+pub mod profiler;
+pub mod ramfs;
 pub mod serial;
+pub mod shell;
+pub mod sync;
+pub mod syscall;
+pub mod trap;
+pub mod usercopy;
 pub mod vga_buffer;
 pub mod virt_addr;
+pub mod watchdog;
 
 pub fn init(boot_info: &'static BootInfo) {
+    serial::early_stage("gdt");
     gdt::init();
+    serial::early_stage("interrupts");
     interrupts::init_idt();
     interrupts::init_pics();
+    serial::early_stage("frame allocator");
     unsafe { allocator::init(&boot_info.memory_map) }; // We're getting the memory map from the boot info so this is safe
+    serial::early_stage("memory");
     unsafe { memory::init(boot_info.physical_memory_offset) }; // We're getting the offset from the boot info so this is safe
+    serial::early_stage("nx");
+    cpu::enable_nx();
+    serial::early_stage("pat");
+    pat::init();
+    serial::early_stage("mmio");
+    memory::remap_mmio(virt_addr::VirtAddr::new(vga_buffer::VGA_BUFFER_ADDRESS));
+    if cfg!(feature = "boot_self_test") {
+        serial::early_stage("paging self test");
+        if let Err(err) = memory::self_test() {
+            panic!("paging self test failed: {:?}", err);
+        }
+    }
+    serial::early_stage("processes");
     process::init_process_list();
     x86_64::instructions::interrupts::enable();
 }
 
+/// Initialize the kernel and then the heap, for integration tests which need to allocate
+pub fn test_boot_with_heap(boot_info: &'static BootInfo) -> Result<(), allocator::HeapError> {
+    init(boot_info);
+    let alloc = match allocator::FRAME_ALLOCATOR.wait() {
+        Some(a) => a,
+        None => return Err(allocator::HeapError::FrameAllocation),
+    };
+
+    allocator::init_heap(&mut *alloc.lock())?;
+    keyboard::init();
+    Ok(())
+}
+
 pub fn hlt_loop() -> ! {
     loop {
         x86_64::instructions::hlt();
@@ -54,24 +107,31 @@ pub fn exit_qemu(exit_code: QemuExitCode) {
     }
 }
 
-pub trait Testable {
+pub trait Testable: Sync {
     fn run(&self, longest: usize) -> ();
     fn name_length(&self) -> usize;
 }
 
+/// Print the test's name, padded so the results line up
+fn print_test_name(name: &str, longest: usize) {
+    serial_print!("{}...", name);
+
+    let indent = longest - name.len();
+    for _ in 0..indent + 2 {
+        serial_print!(" ");
+    }
+}
+
 impl<T> Testable for T
 where
-    T: Fn(),
+    T: Fn() + Sync,
 {
     fn run(&self, longest: usize) {
-        serial_print!("{}...", core::any::type_name::<T>());
-
-        let indent = longest - core::any::type_name::<T>().len();
-        for _ in 0..indent + 2 {
-            serial_print!(" ");
-        }
+        print_test_name(core::any::type_name::<T>(), longest);
 
+        watchdog::TEST_WATCHDOG.arm(watchdog::test_budget());
         self();
+        watchdog::TEST_WATCHDOG.disarm();
         serial_println!("[ok]");
     }
 
@@ -80,24 +140,91 @@ where
     }
 }
 
-pub fn test_runner(tests: &[&dyn Testable]) {
+/// A test which passes only if it panics
+///
+/// Panics can't unwind, so the test's stack is abandoned and the rest of the run continues
+/// from the panic handler. Locks held by the test at the time of the panic are never released
+pub struct ShouldPanic {
+    name: &'static str,
+    test: fn(),
+}
+
+impl ShouldPanic {
+    pub const fn new(name: &'static str, test: fn()) -> Self {
+        ShouldPanic { name, test }
+    }
+}
+
+impl Testable for ShouldPanic {
+    fn run(&self, longest: usize) {
+        print_test_name(self.name, longest);
+
+        watchdog::TEST_WATCHDOG.arm(watchdog::test_budget());
+        EXPECTING_PANIC.store(true, Ordering::SeqCst);
+        (self.test)();
+        EXPECTING_PANIC.store(false, Ordering::SeqCst);
+        watchdog::TEST_WATCHDOG.disarm();
+
+        serial_println!("[failed]\n");
+        serial_println!("Error: test did not panic\n");
+        exit_qemu(QemuExitCode::Failed);
+    }
+
+    fn name_length(&self) -> usize {
+        self.name.len()
+    }
+}
+
+/// Set while a `ShouldPanic` test is running, so the panic handler counts a panic as a pass
+static EXPECTING_PANIC: AtomicBool = AtomicBool::new(false);
+
+/// The tests being run, kept so the run can resume after an expected panic
+static TESTS: Once<&'static [&'static dyn Testable]> = Once::new();
+static NEXT_TEST: AtomicUsize = AtomicUsize::new(0);
+
+pub fn test_runner(tests: &'static [&'static dyn Testable]) {
     serial_println!("Running {} tests", tests.len());
+    TESTS.call_once(|| tests);
+    run_remaining_tests();
+}
+
+/// Run every test which hasn't been started yet, then exit QEMU
+fn run_remaining_tests() {
+    let tests = match TESTS.wait() {
+        Some(t) => *t,
+        None => panic!("test list not set"),
+    };
 
     let mut longest = 0;
     for test in tests {
         longest = max(longest, test.name_length());
     }
 
-    for test in tests {
-        test.run(longest);
+    loop {
+        let next = NEXT_TEST.fetch_add(1, Ordering::SeqCst);
+        match tests.get(next) {
+            Some(test) => test.run(longest),
+            None => break,
+        }
     }
 
     exit_qemu(QemuExitCode::Success);
 }
 
 pub fn test_panic_handler(_info: &PanicInfo) -> ! {
+    if EXPECTING_PANIC.swap(false, Ordering::SeqCst) {
+        watchdog::TEST_WATCHDOG.disarm();
+        serial_println!("[ok]");
+        // The test may have panicked with interrupts masked
+        x86_64::instructions::interrupts::enable();
+        run_remaining_tests();
+        hlt_loop();
+    }
+
+    // Serial output is parsed by the host, so only the screen gets the red
     serial_println!("[failed]\n");
     serial_println!("Error: {}\n", _info);
+    println_colored!(vga_buffer::Color::Red, "[failed] {}", _info);
     exit_qemu(QemuExitCode::Failed);
     hlt_loop();
 }
@@ -117,7 +244,10 @@ entry_point!(test_kernel_main);
 #[cfg(test)]
 #[no_mangle]
 fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
-    init(boot_info);
+    match test_boot_with_heap(boot_info) {
+        Ok(_) => {}
+        Err(err) => panic!("init heap failed: {:?}", err),
+    }
     test_main();
     hlt_loop();
 }
@@ -10,7 +10,7 @@ use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 use kernel::{
     allocator::{init_heap, FRAME_ALLOCATOR},
-    println,
+    keyboard, memory, print, println,
 };
 
 entry_point!(kernel_main);
@@ -19,6 +19,9 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     println!("Hello world{}", "!");
 
     kernel::init(boot_info);
+    if memory::install_kernel_stack_guard().is_none() {
+        println!("could not install kernel stack guard");
+    }
 
     #[cfg(test)]
     test_main();
@@ -33,17 +36,26 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
         Err(_) => panic!("init heap failed"),
     }
 
+    keyboard::init();
+
     let x = 34;
     println!("{:p}", &x);
 
-    kernel::hlt_loop();
+    // Echo whatever is typed
+    loop {
+        while let Some(c) = keyboard::read_char() {
+            print!("{}", c);
+        }
+        x86_64::instructions::hlt();
+    }
 }
 
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(_info: &PanicInfo) -> ! {
-    println!("{}", _info);
-    kernel::hlt_loop();
+    kernel::println_colored!(kernel::vga_buffer::Color::Red, "{}", _info);
+    kernel::klog::KERNEL_LOG.dump();
+    kernel::panic_action::PANIC_ACTION.run();
 }
 
 #[cfg(test)]
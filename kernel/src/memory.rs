@@ -1,12 +1,33 @@
+use core::sync::atomic::{fence, AtomicU64, Ordering};
+
 use spin::Once;
-use x86_64::registers::control::{Cr3, Cr3Flags};
+use x86_64::instructions::{interrupts, tlb};
+use x86_64::registers::control::{Cr3, Cr3Flags, Cr4, Cr4Flags};
 use x86_64::structures::paging::PhysFrame;
 
-use crate::pagetable::PageTable;
+use crate::allocator::{FrameAllocator, FrameDeallocator, FRAME_ALLOCATOR, HEAP_SIZE, HEAP_START};
+use crate::cpu;
+use crate::pagetable::{PageMapError, PageTable};
+use crate::paging::{Page, PageRangeInclusive, PageTableEntry, PageTableEntryFlags, Phys};
+use crate::phys_addr::PhysAddr;
+use crate::serial_println;
 use crate::virt_addr::VirtAddr;
 
+/// The furthest `install_kernel_stack_guard` will walk down looking for the bottom of the stack
+const MAX_KERNEL_STACK_PAGES: usize = 512;
+
+/// An otherwise unused page for `self_test` to map
+const SELF_TEST_ADDR: u64 = 0x5555_3000_0000;
+const SELF_TEST_SENTINEL: u64 = 0x5E1F_7E57_5E1F_7E57;
+
 static PHYSICAL_OFFSET: Once<u64> = Once::new();
 static KERNEL_PAGETABLE: Once<PageTable> = Once::new();
+/// The frame holding `KERNEL_PAGETABLE`
+static KERNEL_PAGETABLE_FRAME: Once<PhysFrame> = Once::new();
+/// The physical address of the active top level page table, or 0 if cr3 hasn't been read yet
+static ACTIVE_PAGETABLE: AtomicU64 = AtomicU64::new(0);
+/// The number of times cr3 has been written by `switch_pagetable`
+static PAGETABLE_SWITCHES: AtomicU64 = AtomicU64::new(0);
 
 /// Initialize the viritual memory system
 ///
@@ -27,16 +48,35 @@ pub unsafe fn init(physical_memory_offset: u64) {
     match KERNEL_PAGETABLE.wait() {
         Some(pagetable) => {
             let ptr = pagetable as *const PageTable;
-            let phys_addr = pagetable.translate_addr(ptr.into()).unwrap();
-            Cr3::write(
-                PhysFrame::from_start_address_unchecked(phys_addr),
-                Cr3Flags::empty(),
-            );
+            let frame = *KERNEL_PAGETABLE_FRAME.call_once(|| table_frame(pagetable, ptr.into()));
+            switch_pagetable(frame);
         }
         None => panic!("kernel page table was not initialized"),
     }
 }
 
+/// The frame of the kernel's own top level table, which kernel threads and the boot thread run on
+///
+/// Returns None until the memory system is initialized
+pub fn kernel_pagetable_frame() -> Option<PhysFrame> {
+    KERNEL_PAGETABLE_FRAME.wait().copied()
+}
+
+/// Find the frame holding the page table at `addr`, as mapped by `table`
+///
+/// This panics rather than risk loading a bad frame into cr3
+fn table_frame(table: &PageTable, addr: VirtAddr) -> PhysFrame {
+    let phys_addr = match table.translate_addr(addr) {
+        Some(a) => a,
+        None => panic!("page table at {:?} is not mapped", addr),
+    };
+
+    match PhysFrame::from_start_address(phys_addr.into()) {
+        Ok(f) => f,
+        Err(_) => panic!("page table at {:?} is not page aligned", phys_addr),
+    }
+}
+
 #[inline]
 pub fn get_offset() -> VirtAddr {
     match PHYSICAL_OFFSET.wait() {
@@ -50,8 +90,566 @@ pub fn get_offset() -> VirtAddr {
 /// This is unsafe as it can create aliased references if the active
 /// pagetable is referenced anywhere else
 pub unsafe fn load_active_pagetable<'a>() -> &'a mut PageTable {
-    let (page_table, _) = Cr3::read();
-    let frame = page_table.into();
+    let frame = active_pagetable_frame().into();
+
+    PageTable::load_mut_table(frame) // This is safe as the frame is the one loaded in cr3
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MemoryError {
+    /// The memory system or frame allocator hasn't been initialized
+    NotInitialized,
+    /// cr3 points at a frame outside the bootloader's memory map
+    InvalidCr3(PhysAddr),
+}
+
+/// Get the active pagetable like `load_active_pagetable`, but check the memory system is
+/// initialized and the cr3 frame is in the physical memory map first
+///
+/// This is still unsafe as it can create aliased references in the same way
+pub unsafe fn try_load_active_pagetable<'a>() -> Result<&'a mut PageTable, MemoryError> {
+    let alloc = match (PHYSICAL_OFFSET.wait(), FRAME_ALLOCATOR.wait()) {
+        (Some(_), Some(a)) => a,
+        _ => return Err(MemoryError::NotInitialized),
+    };
+
+    let frame = active_pagetable_frame();
+    if !alloc.lock().in_memory_map(frame) {
+        return Err(MemoryError::InvalidCr3(frame.start_address().into()));
+    }
+
+    Ok(PageTable::load_mut_table(frame.into())) // This is safe as the frame is the one loaded in cr3 and it's physical memory
+}
+
+/// The frame of the active top level page table
+///
+/// This only reads cr3 the first time it's called, after that `switch_pagetable` keeps it up to date
+pub fn active_pagetable_frame() -> PhysFrame {
+    let addr = ACTIVE_PAGETABLE.load(Ordering::Relaxed);
+    if addr != 0 {
+        return PhysFrame::containing_address(PhysAddr::new(addr).into());
+    }
+
+    let (frame, _) = Cr3::read();
+    ACTIVE_PAGETABLE.store(frame.start_address().as_u64(), Ordering::Relaxed);
+    frame
+}
+
+/// Load `frame` into cr3 as the active top level page table
+///
+/// This is unsafe as the caller must guarantee the table maps the kernel. Always switch tables
+/// with this rather than writing cr3 directly, otherwise `load_active_pagetable` will return a stale table
+pub unsafe fn switch_pagetable(frame: PhysFrame) {
+    interrupts::without_interrupts(|| {
+        // Make sure every page table write is visible before the walker starts using the new table
+        fence(Ordering::SeqCst);
+        Cr3::write(frame, Cr3Flags::empty());
+        ACTIVE_PAGETABLE.store(frame.start_address().as_u64(), Ordering::Relaxed);
+        PAGETABLE_SWITCHES.fetch_add(1, Ordering::Relaxed);
+    })
+}
+
+/// The number of times the active page table has been switched
+pub fn pagetable_switches() -> u64 {
+    PAGETABLE_SWITCHES.load(Ordering::Relaxed)
+}
+
+/// Unmap the lowest page of the current stack so an overflow faults instead of silently
+/// corrupting whatever is mapped below it
+///
+/// The page below the stack is already unmapped, but nothing reserves it so it could be mapped
+/// later. The guard is taken from the stack instead, which loses its lowest page and returns the
+/// frame to the allocator. Returns the address of the guard page, or None if the bottom of the
+/// stack couldn't be found or it's mapped by a huge page
+pub fn install_kernel_stack_guard() -> Option<VirtAddr> {
+    let marker = 0u8;
+    let current = VirtAddr::from(&marker as *const u8).align_down();
+    let table = unsafe { load_active_pagetable() };
+
+    let mut bottom = current;
+    let mut found = false;
+    for _ in 0..MAX_KERNEL_STACK_PAGES {
+        let below = match bottom.as_u64().checked_sub(4096) {
+            Some(addr) => VirtAddr::new(addr),
+            None => break,
+        };
+        if table.translate_addr(below).is_none() {
+            found = true;
+            break;
+        }
+        bottom = below;
+    }
+
+    // Unmapping the page we're running on would fault immediately
+    if !found || bottom == current {
+        return None;
+    }
+
+    // Unmapping a huge page would take the rest of the stack with it
+    table.leaf_entry_mut(bottom)?;
+    let frame = match table.unmap_page(Page::containing_address(bottom)) {
+        Ok(Phys::Size4Kb(f)) => f,
+        _ => return None,
+    };
+    if let Some(alloc) = FRAME_ALLOCATOR.wait() {
+        unsafe { alloc.lock().deallocate(frame) }; // Nothing below the stack pointer is in use
+    }
+
+    Some(bottom)
+}
+
+/// Warn over serial about ranges the kernel is about to map which are already mapped, e.g. by
+/// the bootloader, as mapping over them would silently corrupt whatever uses them
+///
+/// Only the heap range is checked for now. Returns the number of conflicting pages, and
+/// does nothing in release builds
+pub fn detect_conflicting_mappings() -> usize {
+    if !cfg!(debug_assertions) {
+        return 0;
+    }
+
+    let table = unsafe { load_active_pagetable() };
+    mapped_pages(
+        table,
+        VirtAddr::new(HEAP_START as u64),
+        HEAP_SIZE as u64,
+        |addr| {
+            serial_println!("warning: heap page {:?} is already mapped", addr);
+        },
+    )
+}
+
+/// Call `report` with each mapped page in the `size` bytes from `start`, returning how many there were
+fn mapped_pages<F: FnMut(VirtAddr)>(
+    table: &PageTable,
+    start: VirtAddr,
+    size: u64,
+    mut report: F,
+) -> usize {
+    let mut count = 0;
+    for offset in (0..size).step_by(4096) {
+        let addr = start + offset;
+        if table.translate_addr(addr).is_some() {
+            report(addr);
+            count += 1;
+        }
+    }
+
+    count
+}
+
+#[derive(Debug)]
+pub enum SelfTestError {
+    FrameAllocation,
+    Map(PageMapError),
+    /// The scratch page translated to the wrong frame
+    Translate,
+    /// The sentinel written through the scratch page didn't reach the frame
+    Readback,
+    UpdateFlags,
+    /// The scratch page still translates after being unmapped
+    StillMapped,
+}
+
+/// Exercise mapping, translating, updating and unmapping a scratch page in the active table
+///
+/// This runs against the real hardware page tables, so it can catch problems the unit tests
+/// with constructed tables miss
+pub fn self_test() -> Result<(), SelfTestError> {
+    let alloc = match FRAME_ALLOCATOR.wait() {
+        Some(a) => a,
+        None => return Err(SelfTestError::FrameAllocation),
+    };
+    let frame = match alloc.lock().allocate() {
+        Some(f) => f,
+        None => return Err(SelfTestError::FrameAllocation),
+    };
+
+    let addr = VirtAddr::new(SELF_TEST_ADDR);
+    let page = Page::containing_address(addr);
+    let table = unsafe { load_active_pagetable() };
+    let entry = PageTableEntry::new(frame, PageTableEntryFlags::kernel_rw());
+    let result = unsafe { table.map_page(page, entry, &mut *alloc.lock()) }; // The frame was just allocated so nothing else references it
+    if let Err(err) = result {
+        unsafe { alloc.lock().deallocate(frame) }; // The frame was never mapped
+        return Err(SelfTestError::Map(err));
+    }
+
+    // The scratch page is unmapped whether or not the checks pass, so a failure leaves nothing behind
+    let checked = check_scratch_page(table, page, frame);
+    match table.unmap_page(page) {
+        Ok(_) => unsafe { alloc.lock().deallocate(frame) }, // Nothing maps the frame any more
+        Err(_) => return Err(SelfTestError::StillMapped),
+    }
+    checked?;
+
+    if table.translate_addr(addr).is_some() {
+        return Err(SelfTestError::StillMapped);
+    }
+    Ok(())
+}
+
+/// Check the scratch page `page` translates to `frame`, writes reach it and its flags can be updated
+fn check_scratch_page(
+    table: &mut PageTable,
+    page: Page,
+    frame: PhysFrame,
+) -> Result<(), SelfTestError> {
+    let addr = page.as_virt_addr();
+    if table.translate_addr(addr) != Some(frame.start_address().into()) {
+        return Err(SelfTestError::Translate);
+    }
+
+    let ptr: *mut u64 = addr.as_mut_ptr();
+    let frame_ptr: *const u64 = (get_offset() + frame.start_address().as_u64()).as_ptr();
+    unsafe {
+        ptr.write_volatile(SELF_TEST_SENTINEL);
+        if frame_ptr.read_volatile() != SELF_TEST_SENTINEL {
+            return Err(SelfTestError::Readback);
+        }
+    }
+
+    if table
+        .update_flags(page, PageTableEntryFlags::kernel_ro())
+        .is_err()
+    {
+        return Err(SelfTestError::UpdateFlags);
+    }
+    match table.translate_with_flags(addr) {
+        Some((_, flags)) if flags == PageTableEntryFlags::kernel_ro() => Ok(()),
+        _ => Err(SelfTestError::UpdateFlags),
+    }
+}
+
+/// Switch the existing mapping of `addr` to uncached device memory
+///
+/// Returns false if the address isn't mapped by a 4KiB page
+pub fn remap_mmio(addr: VirtAddr) -> bool {
+    let table = unsafe { load_active_pagetable() };
 
-    PageTable::load_mut_table(frame) // This is safe as the physical address has been loaded directly from cr3
+    match table.leaf_entry_mut(addr) {
+        Some(entry) if entry.flags().contains(PageTableEntryFlags::PRESENT) => {
+            entry.set_flags(entry.flags() | PageTableEntryFlags::mmio());
+            tlb::flush(x86_64::VirtAddr::new(addr.as_u64()));
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Add NO_EXECUTE to every 4KiB page mapped in `pages`, for regions which only ever hold data
+///
+/// The heap is already mapped NO_EXECUTE by `init_heap`. Returns how many pages were changed,
+/// which is none if NX isn't enabled as the bit is reserved until it is
+pub fn set_no_execute(pages: PageRangeInclusive) -> usize {
+    if !cpu::nx_enabled() {
+        return 0;
+    }
+
+    let table = unsafe { load_active_pagetable() };
+    let mut changed = 0;
+    for page in pages {
+        if let Some((_, flags)) = table.translate_with_flags(page.as_virt_addr()) {
+            if table
+                .update_flags(page, flags | PageTableEntryFlags::NO_EXECUTE)
+                .is_ok()
+            {
+                changed += 1;
+            }
+        }
+    }
+
+    changed
+}
+
+/// Flush all non global entries from the TLB by reloading cr3
+///
+/// This is enough after changing any mapping which isn't marked GLOBAL
+#[inline]
+pub fn flush_all() {
+    tlb::flush_all();
+}
+
+/// Flush every entry from the TLB, including global ones
+///
+/// Global entries survive a cr3 reload, so this briefly clears CR4.PGE instead. It is only
+/// needed after changing GLOBAL mappings (e.g. the kernel's own) and is much more expensive
+pub fn flush_all_including_global() {
+    interrupts::without_interrupts(|| {
+        let cr4 = Cr4::read();
+        if !cr4.contains(Cr4Flags::PAGE_GLOBAL) {
+            tlb::flush_all();
+            return;
+        }
+
+        unsafe {
+            Cr4::write(cr4 - Cr4Flags::PAGE_GLOBAL);
+            Cr4::write(cr4);
+        }
+    })
+}
+
+/// Print the active cr3 frame and the present entries of the top level page table over serial
+///
+/// Returns the number of present top level entries
+pub fn dump_cr3() -> usize {
+    let (frame, flags) = Cr3::read();
+    serial_println!("CR3: {:?} {:?}", frame.start_address(), flags);
+
+    let table = unsafe { PageTable::load_table(frame.into()) }; // This is safe as the physical address has been loaded directly from cr3
+    dump_top_level(table)
+}
+
+fn dump_top_level(table: &PageTable) -> usize {
+    let mut present = 0;
+    for (i, entry) in table.present_entries() {
+        if let Some(f) = entry.frame(3) {
+            serial_println!(
+                "  {:>3}: {:?} {:?}",
+                usize::from(i),
+                f.start_address(),
+                entry.flags()
+            );
+            present += 1;
+        }
+    }
+
+    present
+}
+
+#[cfg(test)]
+mod tests {
+    use x86_64::{
+        registers::control::{Cr3, Cr4, Cr4Flags},
+        structures::paging::{PhysFrame, Size1GiB, Size4KiB},
+    };
+
+    use crate::{
+        allocator::{FrameAllocator, FrameDeallocator, FRAME_ALLOCATOR, HEAP_SIZE, HEAP_START},
+        pagetable::PageTable,
+        paging::{Page, PageTableEntry, PageTableEntryFlags, Phys},
+        phys_addr::PhysAddr,
+        virt_addr::VirtAddr,
+    };
+
+    use super::{
+        active_pagetable_frame, dump_top_level, flush_all_including_global, get_offset,
+        load_active_pagetable, mapped_pages, pagetable_switches, self_test, switch_pagetable,
+        table_frame, try_load_active_pagetable,
+    };
+
+    #[test_case]
+    fn try_load_active_pagetable_after_init() {
+        let table = match unsafe { try_load_active_pagetable() } {
+            Ok(t) => t as *const PageTable,
+            Err(err) => panic!("could not load the active page table: {:?}", err),
+        };
+        let expected = unsafe { load_active_pagetable() } as *const PageTable;
+        assert_eq!(table, expected);
+    }
+
+    #[test_case]
+    fn dump_counts_present_entries() {
+        let mut table = PageTable::new();
+        let frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(0x5000).into());
+
+        table[0] = PageTableEntry::new(frame, PageTableEntryFlags::PRESENT);
+        table[12] = PageTableEntry::new(frame, PageTableEntryFlags::PRESENT);
+        table[511] = PageTableEntry::new(
+            frame,
+            PageTableEntryFlags::PRESENT | PageTableEntryFlags::WRITABLE,
+        );
+        table[100] = PageTableEntry::new(frame, PageTableEntryFlags::WRITABLE);
+
+        assert_eq!(dump_top_level(&table), 3);
+    }
+
+    #[test_case]
+    fn global_remap_visible_after_full_flush() {
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+        let (first, second) = {
+            let mut alloc = alloc.lock();
+            match (alloc.allocate(), alloc.allocate()) {
+                (Some(a), Some(b)) => (a, b),
+                _ => panic!("could not allocate frames"),
+            }
+        };
+        unsafe {
+            let first_ptr: *mut u64 = (get_offset() + first.start_address().as_u64()).as_mut_ptr();
+            first_ptr.write_volatile(1);
+            let second_ptr: *mut u64 =
+                (get_offset() + second.start_address().as_u64()).as_mut_ptr();
+            second_ptr.write_volatile(2);
+        }
+
+        // Without PGE the GLOBAL flag is ignored, and a plain flush would pass this test too
+        let cr4 = Cr4::read();
+        unsafe { Cr4::write(cr4 | Cr4Flags::PAGE_GLOBAL) }; // Global pages only change how the TLB is flushed
+        assert!(Cr4::read().contains(Cr4Flags::PAGE_GLOBAL));
+
+        let addr = VirtAddr::new(0x5555_1000_0000);
+        let page = Page::containing_address(addr);
+        let flags = PageTableEntryFlags::PRESENT | PageTableEntryFlags::GLOBAL;
+        let table = unsafe { load_active_pagetable() };
+        let result =
+            unsafe { table.map_page(page, PageTableEntry::new(first, flags), &mut *alloc.lock()) };
+        match result {
+            Ok(_) => {}
+            Err(err) => panic!("error mapping page: {:?}", err),
+        }
+
+        let ptr: *const u64 = addr.as_ptr();
+        assert_eq!(unsafe { ptr.read_volatile() }, 1);
+
+        match table.leaf_entry_mut(addr) {
+            Some(entry) => *entry = PageTableEntry::new(second, flags),
+            None => panic!("mapped page has no leaf entry"),
+        }
+        flush_all_including_global();
+
+        assert_eq!(unsafe { ptr.read_volatile() }, 2);
+
+        match table.unmap_page(page) {
+            Ok(Phys::Size4Kb(f)) => assert_eq!(f, second),
+            result => panic!("unexpected unmap result: {:?}", result),
+        }
+        unsafe {
+            Cr4::write(cr4); // Put PGE back as it was, the test page is already unmapped
+            let mut alloc = alloc.lock();
+            alloc.deallocate(first); // Remapped away from before the page was unmapped
+            alloc.deallocate(second); // The page mapping it is gone
+        }
+    }
+
+    #[test_case]
+    fn switch_pagetable_updates_active_table() {
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+        let frame = match alloc.lock().allocate() {
+            Some(f) => f,
+            None => panic!("could not allocate frame"),
+        };
+
+        let old_frame = active_pagetable_frame();
+        unsafe {
+            let new_table = PageTable::load_mut_table(frame.into());
+            *new_table = load_active_pagetable().clone();
+            switch_pagetable(frame);
+        }
+
+        let table_ptr = unsafe { load_active_pagetable() } as *const PageTable;
+        assert_eq!(
+            table_ptr as u64,
+            (get_offset() + frame.start_address().as_u64()).as_u64()
+        );
+        assert_eq!(Cr3::read().0, frame);
+
+        unsafe { switch_pagetable(old_frame) };
+        assert_eq!(active_pagetable_frame(), old_frame);
+    }
+
+    #[test_case]
+    fn table_frame_through_1gb_page() {
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+        let pdpt_frame = match alloc.lock().allocate() {
+            Some(f) => f,
+            None => panic!("could not allocate frame"),
+        };
+
+        // Map the second GiB under level 4 entry 1 to physical 1GiB with a huge page
+        let mut table = PageTable::new();
+        table[1] = PageTableEntry::new(
+            pdpt_frame,
+            PageTableEntryFlags::PRESENT | PageTableEntryFlags::WRITABLE,
+        );
+        let pdpt = unsafe { PageTable::load_mut_table(pdpt_frame.into()) };
+        *pdpt = PageTable::new();
+        pdpt[1] = PageTableEntry::new(
+            PhysFrame::<Size1GiB>::containing_address(PhysAddr::new(0x4000_0000).into()),
+            PageTableEntryFlags::PRESENT | PageTableEntryFlags::HUGE_PAGE,
+        );
+
+        let addr = VirtAddr::new(0x80_0000_0000 + 0x4000_0000 + 0x12_3000);
+        assert_eq!(
+            table_frame(&table, addr).start_address().as_u64(),
+            0x4012_3000
+        );
+    }
+
+    #[test_case]
+    fn premapped_heap_page_reported() {
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+        let frame = match alloc.lock().allocate() {
+            Some(f) => f,
+            None => panic!("could not allocate frame"),
+        };
+
+        let mut table = PageTable::new();
+        let conflict = VirtAddr::new(HEAP_START as u64 + 0x2000);
+        let entry = PageTableEntry::new(frame, PageTableEntryFlags::kernel_rw());
+        let result = unsafe {
+            table.map_page(
+                Page::containing_address(conflict),
+                entry,
+                &mut *alloc.lock(),
+            )
+        };
+        match result {
+            Ok(_) => {}
+            Err(err) => panic!("error mapping page: {:?}", err),
+        }
+
+        let mut reported = None;
+        let count = mapped_pages(
+            &table,
+            VirtAddr::new(HEAP_START as u64),
+            HEAP_SIZE as u64,
+            |addr| reported = Some(addr),
+        );
+        assert_eq!(count, 1);
+        assert_eq!(reported, Some(conflict));
+    }
+
+    #[test_case]
+    fn cr3_only_written_through_switch_pagetable() {
+        // A bare cr3 write would leave the cached frame stale
+        assert_eq!(active_pagetable_frame(), Cr3::read().0);
+
+        let switches = pagetable_switches();
+        unsafe { switch_pagetable(active_pagetable_frame()) };
+        assert_eq!(pagetable_switches(), switches + 1);
+        assert_eq!(active_pagetable_frame(), Cr3::read().0);
+    }
+
+    #[test_case]
+    fn self_test_passes() {
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+        // The first run may build tables for the scratch page, which stay in place
+        let mut allocated = None;
+        for _ in 0..2 {
+            match self_test() {
+                Ok(_) => {}
+                Err(err) => panic!("paging self test failed: {:?}", err),
+            }
+            let now = alloc.lock().allocated_frames();
+            if let Some(before) = allocated {
+                assert_eq!(before, now);
+            }
+            allocated = Some(now);
+        }
+    }
 }
@@ -1,17 +1,55 @@
-use core::ops::{Index, IndexMut};
+use core::{
+    ops::{Index, IndexMut},
+    sync::atomic::{AtomicU64, Ordering},
+};
 
-use x86_64::PhysAddr;
+use x86_64::{instructions::tlb, structures::paging::PhysFrame};
 
 use crate::{
-    allocator::FrameAllocator,
+    allocator::{frame_refs, release_frame, share_frame, FrameAllocator, FrameDeallocator},
     memory::get_offset,
-    paging::{Page, PageTableEntry, PageTableEntryFlags, PageTableIndex, Phys},
+    paging::{Page, PageRangeInclusive, PageTableEntry, PageTableEntryFlags, PageTableIndex, Phys},
+    phys_addr::PhysAddr,
     println,
     virt_addr::VirtAddr,
 };
 
 const PAGE_TABLE_SIZE: usize = 512;
 
+/// Bumped whenever a mapping changes in a way that leaves stale TLB entries
+///
+/// There's a single cpu and every address space shares the kernel's higher half, so for now this
+/// is global. Once other cores exist they can compare it against the generation they last flushed
+/// at and flush lazily, and it can become per address space
+static TLB_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// The current TLB generation, see `TLB_GENERATION`
+pub fn tlb_generation() -> u64 {
+    TLB_GENERATION.load(Ordering::Acquire)
+}
+
+fn bump_tlb_generation() {
+    TLB_GENERATION.fetch_add(1, Ordering::AcqRel);
+}
+
+/// The number of single page TLB flushes made after mapping a page
+static PAGE_FLUSHES: AtomicU64 = AtomicU64::new(0);
+
+/// The number of single page TLB flushes made by `map_page` and friends
+pub fn page_flushes() -> u64 {
+    PAGE_FLUSHES.load(Ordering::Relaxed)
+}
+
+/// Invalidate any cached translation of `page`
+fn flush_page(page: Page) {
+    tlb::flush(x86_64::VirtAddr::new(page.as_u64()));
+    PAGE_FLUSHES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// A single level of the page table hierarchy
+///
+/// Cloning copies only this table's entries, so the clone shares every lower level table. For a
+/// top level table this duplicates the address space without copying any of its mappings
 #[repr(align(4096))]
 #[repr(C)]
 #[derive(Debug, Clone)]
@@ -26,6 +64,20 @@ impl PageTable {
         }
     }
 
+    /// Iterate over every entry in the table along with its index
+    pub fn entries(&self) -> impl Iterator<Item = (PageTableIndex, &PageTableEntry)> {
+        self.entries
+            .iter()
+            .enumerate()
+            .map(|(i, entry)| (PageTableIndex::new_truncate(i as u16), entry))
+    }
+
+    /// Iterate over the present entries in the table along with their indices
+    pub fn present_entries(&self) -> impl Iterator<Item = (PageTableIndex, &PageTableEntry)> {
+        self.entries()
+            .filter(|(_, entry)| entry.flags().contains(PageTableEntryFlags::PRESENT))
+    }
+
     /// Load a page table from a physical frame address
     ///
     /// This is unsafe because it transmutes the start address of the frame into a page table
@@ -53,29 +105,65 @@ impl PageTable {
 
     /// Translate a virtual address into a physical one
     pub fn translate_addr(&self, addr: VirtAddr) -> Option<PhysAddr> {
+        self.translate_with_flags(addr).map(|(phys, _)| phys)
+    }
+
+    /// Translate a virtual address into a physical one, along with the flags of the leaf
+    /// entry mapping it
+    ///
+    /// Only the leaf's flags are returned, the tables above it can still restrict access
+    pub fn translate_with_flags(&self, addr: VirtAddr) -> Option<(PhysAddr, PageTableEntryFlags)> {
         let mut table = self;
-        let mut phys_addr = PhysAddr::new(0);
 
         for i in 0..4 {
             let level = 3 - i;
-            let index = addr.page_table_index(level);
+            let entry = table[addr.page_table_index(level)];
 
-            match table[index].frame(level) {
-                Some(f) => match f {
-                    Phys::Size2Mb(_) | Phys::Size1Gb(_) => {
-                        phys_addr = f.start_address();
-                        break;
-                    }
-                    _ => {
-                        table = unsafe { PageTable::load_table(f) };
-                        phys_addr = f.start_address();
-                    }
-                },
-                None => return None,
+            match entry.frame(level)? {
+                // Huge pages are the leaf, so the offset covers the rest of the address
+                Phys::Size2Mb(f) => {
+                    return Some((
+                        PhysAddr::from(f.start_address()) + (addr.as_u64() & 0x1F_FFFF),
+                        entry.flags(),
+                    ))
+                }
+                Phys::Size1Gb(f) => {
+                    return Some((
+                        PhysAddr::from(f.start_address()) + (addr.as_u64() & 0x3FFF_FFFF),
+                        entry.flags(),
+                    ))
+                }
+                Phys::Size4Kb(f) if level == 0 => {
+                    return Some((
+                        PhysAddr::from(f.start_address()) + u64::from(addr.page_offset()),
+                        entry.flags(),
+                    ))
+                }
+                f => table = unsafe { PageTable::load_table(f) },
+            }
+        }
+
+        None
+    }
+
+    /// Whether `addr` is mapped, i.e. `translate_addr` would return Some
+    ///
+    /// The walk stops at the first entry which isn't present
+    pub fn is_mapped(&self, addr: VirtAddr) -> bool {
+        let mut table = self;
+
+        for i in 0..4 {
+            let level = 3 - i;
+            match table[addr.page_table_index(level)].frame(level) {
+                None => return false,
+                Some(Phys::Size4Kb(f)) if level > 0 => {
+                    table = unsafe { PageTable::load_table(Phys::Size4Kb(f)) }
+                }
+                Some(_) => return true,
             }
         }
 
-        Some(phys_addr + u64::from(addr.page_offset()))
+        false
     }
 
     /// Create a new page table mapping using allocator to allocate new page table frames
@@ -83,60 +171,233 @@ impl PageTable {
     ///
     /// This is unsafe because if we map to an existing frame
     /// we can create aliased mutable references
+    ///
+    /// Debug builds reject mapping physical frame 0, see `map_page_allow_zero_frame`
     pub unsafe fn map_page<T: FrameAllocator>(
         &mut self,
         page: Page,
         entry: PageTableEntry,
         allocator: &mut T,
     ) -> Result<(), PageMapError> {
-        self.map_page_inner(page, entry, allocator)
+        check_zero_frame(entry)?;
+        self.map_page_allow_zero_frame(page, entry, allocator)
+    }
+
+    /// Create a new page table mapping like `map_page`, including to physical frame 0
+    ///
+    /// Frame 0 holds the real mode IVT and BIOS data, so mapping it is almost always a bug.
+    /// Only use this where it's really wanted, e.g. identity mapping low memory
+    pub unsafe fn map_page_allow_zero_frame<T: FrameAllocator>(
+        &mut self,
+        page: Page,
+        entry: PageTableEntry,
+        allocator: &mut T,
+    ) -> Result<(), PageMapError> {
+        self.map_page_unflushed(page, entry, allocator)?;
+        flush_page(page);
+        Ok(())
+    }
+
+    /// Create a new page table mapping like `map_page`, without flushing the TLB
+    ///
+    /// This is for callers mapping many pages, they must flush the TLB themselves
+    /// (e.g. with a single `tlb::flush_all`) before the new mappings are used
+    pub unsafe fn map_page_no_flush<T: FrameAllocator>(
+        &mut self,
+        page: Page,
+        entry: PageTableEntry,
+        allocator: &mut T,
+    ) -> Result<(), PageMapError> {
+        check_zero_frame(entry)?;
+        self.map_page_unflushed(page, entry, allocator)
+    }
+
+    unsafe fn map_page_unflushed<T: FrameAllocator>(
+        &mut self,
+        page: Page,
+        entry: PageTableEntry,
+        allocator: &mut T,
+    ) -> Result<(), PageMapError> {
+        let result = self.map_page_inner(page, entry, allocator, None);
+        if result.is_ok() {
+            bump_tlb_generation();
+            self.debug_verify_entry(page, entry);
+        }
+
+        result
+    }
+
+    /// Create a new page table mapping like `map_page`, but if a page table frame can't be
+    /// allocated `on_oom` is invoked once to reclaim frames into the allocator before retrying
+    ///
+    /// This is unsafe because if we map to an existing frame
+    /// we can create aliased mutable references
+    pub unsafe fn map_page_with_reclaim<T: FrameAllocator, F: FnMut(&mut T)>(
+        &mut self,
+        page: Page,
+        entry: PageTableEntry,
+        allocator: &mut T,
+        mut on_oom: F,
+    ) -> Result<(), PageMapError> {
+        check_zero_frame(entry)?;
+        let result = self.map_page_inner(page, entry, allocator, Some(&mut on_oom));
+        if result.is_ok() {
+            bump_tlb_generation();
+            flush_page(page);
+            self.debug_verify_entry(page, entry);
+        }
+
+        result
+    }
+
+    /// Panic unless `page` translates to the start of `expected`
+    ///
+    /// This is a debugging aid, in debug builds every new mapping is checked with it
+    /// so mapping bugs panic where they happen rather than corrupting memory later
+    pub fn verify_mapping(&self, page: Page, expected: Phys) {
+        match self.translate_addr(page.as_virt_addr()) {
+            Some(addr) if addr == expected.start_address() => {}
+            Some(addr) => panic!(
+                "{:?} translates to {:?} instead of {:?}",
+                page,
+                addr,
+                expected.start_address()
+            ),
+            None => panic!("{:?} was mapped but doesn't translate", page),
+        }
+    }
+
+    fn debug_verify_entry(&self, page: Page, entry: PageTableEntry) {
+        if !cfg!(debug_assertions) || entry.flags().contains(PageTableEntryFlags::HUGE_PAGE) {
+            return;
+        }
+
+        if let Some(frame) = entry.frame(0) {
+            self.verify_mapping(page, frame);
+        }
+    }
+
+    /// Map `frame` at `page` as a single leaf entry, a huge page unless it is a 4KiB frame
+    ///
+    /// The walk stops at the level matching the frame's size, so `page` must be aligned to it.
+    /// The TLB is flushed for `page`, which covers the whole huge page
+    ///
+    /// This is unsafe because if we map to an existing frame
+    /// we can create aliased mutable references
+    pub unsafe fn map_huge_page<T: FrameAllocator>(
+        &mut self,
+        page: Page,
+        frame: Phys,
+        flags: PageTableEntryFlags,
+        allocator: &mut T,
+    ) -> Result<(), PageMapError> {
+        let (level, entry) = match frame {
+            Phys::Size4Kb(f) => {
+                return self.map_page(page, PageTableEntry::new(f, flags), allocator)
+            }
+            Phys::Size2Mb(f) => (
+                1,
+                PageTableEntry::new(f, flags | PageTableEntryFlags::HUGE_PAGE),
+            ),
+            Phys::Size1Gb(f) => (
+                2,
+                PageTableEntry::new(f, flags | PageTableEntryFlags::HUGE_PAGE),
+            ),
+        };
+        check_zero_frame(entry)?;
+
+        self.map_at_level(page, entry, level, allocator, None)?;
+        bump_tlb_generation();
+        flush_page(page);
+        Ok(())
+    }
+
+    /// Map `frame` at the page with the same virtual address as its physical address
+    ///
+    /// This is unsafe because if we map to an existing frame
+    /// we can create aliased mutable references
+    pub unsafe fn identity_map<T: FrameAllocator>(
+        &mut self,
+        frame: PhysFrame,
+        flags: PageTableEntryFlags,
+        allocator: &mut T,
+    ) -> Result<(), PageMapError> {
+        let addr = match VirtAddr::try_new(frame.start_address().as_u64()) {
+            Ok(addr) => addr,
+            Err(_) => return Err(PageMapError::NonCanonical),
+        };
+
+        let page = Page::containing_address(addr);
+        self.map_page(page, PageTableEntry::new(frame, flags), allocator)
     }
 
-    // TODO: Allow huge page mapping
-    // TODO: Handle huge pages properly
-    // TODO: Handle page table entry flushing correctly
     #[inline]
     fn map_page_inner<T: FrameAllocator>(
         &mut self,
         page: Page,
         new_entry: PageTableEntry,
         allocator: &mut T,
+        on_oom: Option<&mut dyn FnMut(&mut T)>,
+    ) -> Result<(), PageMapError> {
+        self.map_at_level(page, new_entry, 0, allocator, on_oom)
+    }
+
+    /// Write `new_entry` as the leaf at `leaf_level`, allocating any missing tables above it
+    fn map_at_level<T: FrameAllocator>(
+        &mut self,
+        page: Page,
+        new_entry: PageTableEntry,
+        leaf_level: usize,
+        allocator: &mut T,
+        mut on_oom: Option<&mut dyn FnMut(&mut T)>,
     ) -> Result<(), PageMapError> {
         let addr = page.as_virt_addr();
+        if !addr.is_canonical() {
+            return Err(PageMapError::NonCanonical);
+        }
+        // Level 0 entries map 4KiB, each level up covers 512 times more
+        if !addr.is_aligned(4096 << (9 * leaf_level)) {
+            return Err(PageMapError::Misaligned);
+        }
 
         let mut table = self;
 
-        for i in 0..3 {
-            let level = 3 - i;
+        for level in (leaf_level + 1..4).rev() {
             let index = addr.page_table_index(level);
 
             match table[index].frame(level) {
-                Some(f) => match f {
-                    Phys::Size2Mb(_) | Phys::Size1Gb(_) => {
-                        println!("HUGE?");
-                        // Set the table entry here so we can index the correct virtual address PTE level
-                        if table[addr.page_table_index(level)]
-                            .flags()
-                            .contains(PageTableEntryFlags::PRESENT)
-                        {
-                            return Err(PageMapError::PageAlreadyMapped);
+                // A huge page already covers this address
+                Some(Phys::Size2Mb(_)) | Some(Phys::Size1Gb(_)) => {
+                    return Err(PageMapError::PageAlreadyMapped);
+                }
+                Some(f) => {
+                    // Tables made for kernel pages must be opened up to reach a user page too
+                    let user = new_entry.flags() & PageTableEntryFlags::USER_ACCESSIBLE;
+                    let flags = table[index].flags();
+                    table[index].set_flags(flags | user);
+                    table = unsafe { PageTable::load_mut_table(f) };
+                }
+                None => {
+                    let mut new_frame = allocator.allocate();
+                    if new_frame.is_none() {
+                        // Only give the hook one chance per mapping
+                        if let Some(reclaim) = on_oom.take() {
+                            reclaim(allocator);
+                            new_frame = allocator.allocate();
                         }
-                        table[addr.page_table_index(level)] = new_entry;
-                        return Ok(());
-                    }
-                    Phys::Size4Kb(_) => {
-                        table = unsafe { PageTable::load_mut_table(f) };
                     }
-                },
-                None => {
-                    let new_frame = allocator.allocate();
+
                     match new_frame {
                         Some(f) => {
-                            // TODO: Ensure memory is cleared
-                            let entry = PageTableEntry::new(
-                                f,
-                                PageTableEntryFlags::PRESENT | PageTableEntryFlags::WRITABLE,
-                            );
+                            // Stale entries left in the frame would be treated as mappings
+                            let table_ptr: *mut u8 =
+                                (get_offset() + f.start_address().as_u64()).as_mut_ptr();
+                            unsafe { table_ptr.write_bytes(0, 4096) }; // This is safe as the frame was just allocated
+
+                            // Ring 3 can only reach a user page if every table above it allows it too
+                            let flags = PageTableEntryFlags::kernel_rw()
+                                | (new_entry.flags() & PageTableEntryFlags::USER_ACCESSIBLE);
+                            let entry = PageTableEntry::new(f, flags);
                             table[index] = entry;
                             table = unsafe { PageTable::load_mut_table(Phys::Size4Kb(f)) };
                         }
@@ -146,136 +407,1473 @@ impl PageTable {
             }
         }
 
-        if table[addr.page_table_index(0)]
+        // A present entry here is either a mapping or a table of smaller mappings
+        if table[addr.page_table_index(leaf_level)]
             .flags()
             .contains(PageTableEntryFlags::PRESENT)
         {
             return Err(PageMapError::PageAlreadyMapped);
         }
 
-        table[addr.page_table_index(0)] = new_entry;
+        table[addr.page_table_index(leaf_level)] = new_entry;
         Ok(())
     }
-}
 
-impl Index<usize> for PageTable {
-    type Output = PageTableEntry;
+    /// Map every page in `pages` to a newly allocated frame, flushing the TLB once at the end
+    ///
+    /// If any page fails the pages which were mapped are unmapped and their frames returned to
+    /// the allocator. Any intermediate tables which were created stay in place
+    pub fn map_range<T: FrameAllocator + FrameDeallocator>(
+        &mut self,
+        pages: PageRangeInclusive,
+        flags: PageTableEntryFlags,
+        allocator: &mut T,
+    ) -> Result<(), PageMapError> {
+        // The first page and how many have been mapped from it
+        let mut mapped: Option<(Page, u64)> = None;
+        for page in pages {
+            let result = match allocator.allocate() {
+                Some(frame) => {
+                    let entry = PageTableEntry::new(frame, flags);
+                    let result = unsafe { self.map_page_no_flush(page, entry, allocator) }; // The frame is fresh so nothing else maps it
+                    if result.is_err() {
+                        unsafe { allocator.deallocate(frame) }; // The frame was never mapped
+                    }
+                    result
+                }
+                None => Err(PageMapError::FrameAllocation),
+            };
 
-    #[inline]
-    fn index(&self, index: usize) -> &Self::Output {
-        &self.entries[index]
+            match (result, mapped) {
+                (Ok(_), Some((first, count))) => mapped = Some((first, count + 1)),
+                (Ok(_), None) => mapped = Some((page, 1)),
+                (Err(err), _) => {
+                    if let Some((first, count)) = mapped {
+                        for i in 0..count {
+                            if let Ok(Phys::Size4Kb(frame)) = self.unmap_page(first + i) {
+                                unsafe { allocator.deallocate(frame) }; // Nothing has used the page yet
+                            }
+                        }
+                    }
+                    return Err(err);
+                }
+            }
+        }
+
+        tlb::flush_all();
+        Ok(())
     }
-}
 
-impl Index<PageTableIndex> for PageTable {
-    type Output = PageTableEntry;
+    /// Replace the flags of the 4KiB page mapping `page`, keeping the frame it maps
+    ///
+    /// The entry stays PRESENT whatever flags are passed, use `unmap_page` to remove it
+    pub fn update_flags(
+        &mut self,
+        page: Page,
+        flags: PageTableEntryFlags,
+    ) -> Result<(), PageMapError> {
+        match self.leaf_entry_mut(page.as_virt_addr()) {
+            Some(entry) if entry.flags().contains(PageTableEntryFlags::PRESENT) => {
+                entry.set_flags(flags | PageTableEntryFlags::PRESENT);
+            }
+            _ => return Err(PageMapError::PageNotMapped),
+        }
 
-    #[inline]
-    fn index(&self, index: PageTableIndex) -> &Self::Output {
-        &self.entries[usize::from(index)]
+        bump_tlb_generation();
+        flush_page(page);
+        Ok(())
     }
-}
 
-impl IndexMut<usize> for PageTable {
-    #[inline]
-    fn index_mut(&mut self, index: usize) -> &mut Self::Output {
-        &mut self.entries[index]
-    }
-}
+    /// Unmap every mapped page in `range`, calling `out` with each frame that was freed so the
+    /// caller can deallocate it or drop its reference
+    ///
+    /// Pages which aren't mapped are skipped. The TLB is flushed once after all pages are unmapped
+    pub fn unmap_range<F: FnMut(Phys)>(&mut self, range: PageRangeInclusive, out: &mut F) {
+        for page in range {
+            if let Ok(frame) = self.unmap_page_inner(page) {
+                out(frame);
+            }
+        }
 
-impl IndexMut<PageTableIndex> for PageTable {
-    #[inline]
-    fn index_mut(&mut self, index: PageTableIndex) -> &mut Self::Output {
-        &mut self.entries[usize::from(index)]
+        bump_tlb_generation();
+        tlb::flush_all();
     }
-}
 
-// TODO: Parameterize with page size
-#[derive(Debug)]
-pub enum PageMapError {
-    FrameAllocation,
-    PageAlreadyMapped,
-}
+    /// Unmap `page`, returning the frame it mapped so the caller can deallocate it
+    pub fn unmap_page(&mut self, page: Page) -> Result<Phys, PageUnmapError> {
+        let frame = self.unmap_page_inner(page)?;
+        bump_tlb_generation();
+        tlb::flush(x86_64::VirtAddr::new(page.as_u64()));
 
-// TODO: Add huge page tests
-#[cfg(test)]
-mod tests {
-    use x86_64::{
-        structures::paging::{PhysFrame, Size4KiB},
-        PhysAddr,
-    };
+        Ok(frame)
+    }
 
-    use crate::{
-        allocator::FRAME_ALLOCATOR,
-        paging::{Page, PageTableEntry, PageTableEntryFlags},
-        virt_addr::VirtAddr,
-    };
+    /// Clear the leaf entry mapping `page` without flushing the TLB, returning the frame it mapped
+    fn unmap_page_inner(&mut self, page: Page) -> Result<Phys, PageUnmapError> {
+        let addr = page.as_virt_addr();
+        let mut table = self;
 
-    use super::{PageMapError, PageTable};
+        for i in 0..4 {
+            let level = 3 - i;
+            let index = addr.page_table_index(level);
 
-    #[test_case]
-    fn get_unmapped_address() {
-        let table = PageTable::new();
-        let addr = VirtAddr::new(1234);
-        let phys_addr = table.translate_addr(addr);
+            match table[index].frame(level) {
+                Some(Phys::Size4Kb(f)) if level > 0 => {
+                    table = unsafe { PageTable::load_mut_table(Phys::Size4Kb(f)) };
+                }
+                Some(frame) => {
+                    table[index] = PageTableEntry::new_zero();
+                    return Ok(frame);
+                }
+                None if level > 0 => return Err(PageUnmapError::ParentNotMapped(level)),
+                None => return Err(PageUnmapError::PageNotMapped),
+            }
+        }
 
-        match phys_addr {
-            Some(pa) => panic!("{:?} was mapped to {} unexpectedly", addr, pa.as_u64()),
-            None => (),
-        };
+        Err(PageUnmapError::PageNotMapped)
     }
 
-    #[test_case]
-    fn add_valid_entry() {
-        let mut table = PageTable::new();
-        let addr = VirtAddr::new(0xDEADBEEF);
-        let page = Page::containing_address(addr);
-        let frame = PhysFrame::<Size4KiB>::from_start_address(PhysAddr::new(4096)).unwrap();
-        let entry = PageTableEntry::new(frame, PageTableEntryFlags::PRESENT);
-
-        let alloc = match FRAME_ALLOCATOR.wait() {
-            Some(a) => a,
-            None => panic!("boot info allocator not initialized"),
+    /// Resolve a write fault on a copy on write page
+    ///
+    /// If the frame is still shared its contents are copied into a freshly allocated frame,
+    /// otherwise this mapping is the last owner and the frame is simply made writable again
+    pub fn resolve_cow<T: FrameAllocator>(
+        &mut self,
+        addr: VirtAddr,
+        allocator: &mut T,
+    ) -> Result<(), CowError> {
+        let entry = match self.leaf_entry_mut(addr) {
+            Some(e) => e,
+            None => return Err(CowError::PageNotMapped),
         };
 
-        let result = unsafe { table.map_page(page, entry, &mut *alloc.lock()) };
-        match result {
-            Ok(_) => {}
-            Err(err) => panic!("error mapping page: {:?}", err),
+        let flags = entry.flags();
+        if !flags.contains(PageTableEntryFlags::COPY_ON_WRITE) {
+            return Err(CowError::NotCopyOnWrite);
         }
+        let old_frame = match entry.frame(0) {
+            Some(Phys::Size4Kb(f)) => f,
+            _ => return Err(CowError::PageNotMapped),
+        };
 
-        let phys_addr = table.translate_addr(addr);
+        let flags = (flags - PageTableEntryFlags::COPY_ON_WRITE) | PageTableEntryFlags::WRITABLE;
+        if frame_refs(old_frame) > 1 {
+            let new_frame = match allocator.allocate() {
+                Some(f) => f,
+                None => return Err(CowError::FrameAllocation),
+            };
 
-        match phys_addr {
-            Some(pa) => assert_eq!(pa.as_u64(), addr.page_offset().as_u64() + 4096),
-            None => panic!("new page was not mapped to correct physical frame"),
+            let src: *const u8 = (get_offset() + old_frame.start_address().as_u64()).as_ptr();
+            let dst: *mut u8 = (get_offset() + new_frame.start_address().as_u64()).as_mut_ptr();
+            unsafe { core::ptr::copy_nonoverlapping(src, dst, 4096) }; // Both frames are mapped at the physical memory offset and never overlap
+
+            *entry = PageTableEntry::new(new_frame, flags);
+            release_frame(old_frame);
+        } else {
+            *entry = PageTableEntry::new(old_frame, flags);
         }
+
+        bump_tlb_generation();
+        tlb::flush(x86_64::VirtAddr::new(addr.as_u64()));
+        Ok(())
     }
 
-    #[test_case]
-    fn try_to_remap() {
-        let mut table = PageTable::new();
-        let addr = VirtAddr::new(0xABCD);
-        let alloc = match FRAME_ALLOCATOR.wait() {
-            Some(a) => a,
-            None => panic!("boot info allocator not initialized"),
-        };
+    /// Create a new top level table sharing this one's kernel mappings, with every user page
+    /// mapped copy on write like `clone_user_cow`
+    ///
+    /// The user part of the walk gets its own lower level tables, only the leaf frames are shared.
+    /// This takes `&mut self` rather than `&self`, as this table's writable pages are write
+    /// protected too
+    pub fn clone_cow<T: FrameAllocator + FrameDeallocator>(
+        &mut self,
+        allocator: &mut T,
+    ) -> Result<PageTable, PageMapError> {
+        let mut child = self.clone();
+        self.clone_user_cow(&mut child, allocator)?;
+        Ok(child)
+    }
 
-        let page = Page::containing_address(addr);
-        let frame = PhysFrame::<Size4KiB>::from_start_address(PhysAddr::new(0)).unwrap();
-        let entry = PageTableEntry::new(frame, PageTableEntryFlags::PRESENT);
+    /// Map every user page into `child` copy on write, so both tables share the frames
+    ///
+    /// Writable pages lose WRITABLE and gain COPY_ON_WRITE in both tables, so the first write from
+    /// either side faults and takes a private copy. Only top level entries with USER_ACCESSIBLE
+    /// are copied, the rest are kernel mappings which `child` should already share.
+    ///
+    /// If a mapping fails, everything already mapped into `child` is freed again with
+    /// `free_user_mappings` before the error is returned
+    pub fn clone_user_cow<T: FrameAllocator + FrameDeallocator>(
+        &mut self,
+        child: &mut PageTable,
+        allocator: &mut T,
+    ) -> Result<(), PageMapError> {
+        let user = PageTableEntryFlags::PRESENT | PageTableEntryFlags::USER_ACCESSIBLE;
+        // Don't let the child's mappings land in the parent's tables, or a failed clone free them
+        for i in 0..PAGE_TABLE_SIZE {
+            if self[i].flags().contains(user) {
+                child[i] = PageTableEntry::new_zero();
+            }
+        }
 
-        let result = unsafe { table.map_page(page, entry, &mut *alloc.lock()) };
-        match result {
-            Ok(_) => {}
+        let mut result = Ok(());
+        for i in 0..PAGE_TABLE_SIZE {
+            let entry = self[i];
+            if !entry.flags().contains(user) {
+                continue;
+            }
+
+            if let Some(frame @ Phys::Size4Kb(_)) = entry.frame(3) {
+                let table = unsafe { PageTable::load_mut_table(frame) };
+                result = table.clone_user_cow_level(2, (i as u64) << 39, child, allocator);
+                if result.is_err() {
+                    break;
+                }
+            }
+        }
+
+        // Pages already walked have been write protected even if the clone failed
+        bump_tlb_generation();
+        tlb::flush_all();
+        if result.is_err() {
+            child.free_user_mappings(allocator);
+        }
+        result
+    }
+
+    fn clone_user_cow_level<T: FrameAllocator>(
+        &mut self,
+        level: usize,
+        base: u64,
+        child: &mut PageTable,
+        allocator: &mut T,
+    ) -> Result<(), PageMapError> {
+        for i in 0..PAGE_TABLE_SIZE {
+            let entry = self[i];
+            let frame = match entry.frame(level) {
+                Some(f) => f,
+                None => continue,
+            };
+            let addr = base | (i as u64) << (12 + level * 9);
+
+            match frame {
+                Phys::Size4Kb(_) if level > 0 => {
+                    let table = unsafe { PageTable::load_mut_table(frame) };
+                    table.clone_user_cow_level(level - 1, addr, child, allocator)?;
+                }
+                Phys::Size4Kb(f) => {
+                    let mut flags = entry.flags();
+                    if flags.contains(PageTableEntryFlags::WRITABLE) {
+                        flags = (flags - PageTableEntryFlags::WRITABLE)
+                            | PageTableEntryFlags::COPY_ON_WRITE;
+                        self[i].set_flags(flags);
+                    }
+
+                    let page = Page::containing_address(unsafe { VirtAddr::new_unchecked(addr) }); // User addresses are in the lower half so need no sign extension
+                    unsafe { child.map_page(page, PageTableEntry::new(f, flags), allocator)? }; // The frame's reference count covers the new mapping
+                    share_frame(f);
+                }
+                // User huge pages are never created
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Unmap every user page, returning the frames no other mapping shares to `allocator` along
+    /// with the lower level tables which held them
+    ///
+    /// Only top level entries with USER_ACCESSIBLE are freed, the rest are kernel mappings which
+    /// are shared with every other address space. The table must not be the active one
+    pub fn free_user_mappings<T: FrameDeallocator>(&mut self, allocator: &mut T) {
+        let user = PageTableEntryFlags::PRESENT | PageTableEntryFlags::USER_ACCESSIBLE;
+        for i in 0..PAGE_TABLE_SIZE {
+            if !self[i].flags().contains(user) {
+                continue;
+            }
+
+            if let Some(frame @ Phys::Size4Kb(f)) = self[i].frame(3) {
+                let table = unsafe { PageTable::load_mut_table(frame) };
+                table.free_user_mappings_level(2, allocator);
+                unsafe { allocator.deallocate(f) }; // The table was only reachable through this entry
+            }
+            self[i] = PageTableEntry::new_zero();
+        }
+    }
+
+    fn free_user_mappings_level<T: FrameDeallocator>(&mut self, level: usize, allocator: &mut T) {
+        for i in 0..PAGE_TABLE_SIZE {
+            match self[i].frame(level) {
+                Some(frame @ Phys::Size4Kb(f)) if level > 0 => {
+                    let table = unsafe { PageTable::load_mut_table(frame) };
+                    table.free_user_mappings_level(level - 1, allocator);
+                    unsafe { allocator.deallocate(f) }; // The table was only reachable through this entry
+                }
+                Some(Phys::Size4Kb(f)) => {
+                    if release_frame(f) == 0 {
+                        unsafe { allocator.deallocate(f) }; // No other mapping shares the frame
+                    }
+                }
+                // User huge pages are never created
+                _ => {}
+            }
+            self[i] = PageTableEntry::new_zero();
+        }
+    }
+
+    /// Walk every present leaf mapping, calling `accessed` with each page whose ACCESSED
+    /// bit is set and clearing the bit so the next scan only reports fresh accesses
+    ///
+    /// Huge page leaves are reported by the page at their start address
+    pub fn scan_and_clear_accessed<F: FnMut(Page)>(&mut self, mut accessed: F) {
+        self.scan_and_clear_accessed_level(3, 0, &mut accessed);
+    }
+
+    fn scan_and_clear_accessed_level<F: FnMut(Page)>(
+        &mut self,
+        level: usize,
+        base: u64,
+        accessed: &mut F,
+    ) {
+        for i in 0..PAGE_TABLE_SIZE {
+            let entry = self[i];
+            let frame = match entry.frame(level) {
+                Some(f) => f,
+                None => continue,
+            };
+
+            let mut addr = base | (i as u64) << (12 + level * 9);
+            // Sign extend the top level index to get a canonical address
+            if addr & (1 << 47) != 0 {
+                addr |= 0xFFFF_0000_0000_0000;
+            }
+
+            match frame {
+                Phys::Size4Kb(_) if level > 0 => {
+                    let table = unsafe { PageTable::load_mut_table(frame) };
+                    table.scan_and_clear_accessed_level(level - 1, addr, accessed);
+                }
+                _ => {
+                    let flags = entry.flags();
+                    if flags.contains(PageTableEntryFlags::ACCESSED) {
+                        self[i].set_flags(flags - PageTableEntryFlags::ACCESSED);
+                        tlb::flush(x86_64::VirtAddr::new(addr));
+                        let addr = unsafe { VirtAddr::new_unchecked(addr) }; // Sign extended above
+                        accessed(Page::containing_address(addr));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Print every leaf mapping as `virt range -> phys range (flags)` with `println!`
+    ///
+    /// Runs of leaves which are contiguous both virtually and physically and share flags are
+    /// printed as one range. Without `verbose` only the number of runs and bytes mapped are printed
+    pub fn dump_mappings(&self, verbose: bool) {
+        let mut dump = MappingDump {
+            current: None,
+            runs: 0,
+            bytes: 0,
+            verbose,
+        };
+        self.dump_mappings_level(3, 0, &mut dump);
+        dump.finish_run();
+
+        println!("{} mapped runs, {} bytes", dump.runs, dump.bytes);
+    }
+
+    fn dump_mappings_level(&self, level: usize, base: u64, dump: &mut MappingDump) {
+        for (i, entry) in self.present_entries() {
+            let frame = match entry.frame(level) {
+                Some(f) => f,
+                None => continue,
+            };
+
+            let mut addr = base | u64::from(i) << (12 + level * 9);
+            // Sign extend the top level index to get a canonical address
+            if addr & (1 << 47) != 0 {
+                addr |= 0xFFFF_0000_0000_0000;
+            }
+
+            match frame {
+                Phys::Size4Kb(_) if level > 0 => {
+                    let table = unsafe { PageTable::load_table(frame) };
+                    table.dump_mappings_level(level - 1, addr, dump);
+                }
+                _ => dump.add(MappingRun {
+                    virt: addr,
+                    phys: frame.start_address().as_u64(),
+                    len: 4096 << (9 * level),
+                    // These change as the page is used, so they'd split runs for no reason
+                    flags: entry.flags()
+                        - PageTableEntryFlags::ACCESSED
+                        - PageTableEntryFlags::DIRTY,
+                }),
+            }
+        }
+    }
+
+    /// Walk to the level 1 entry for `addr`
+    ///
+    /// Returns None if an intermediate table is missing or the address is covered by a huge page
+    pub fn leaf_entry_mut(&mut self, addr: VirtAddr) -> Option<&mut PageTableEntry> {
+        let mut table = self;
+
+        for i in 0..3 {
+            let level = 3 - i;
+            let index = addr.page_table_index(level);
+
+            match table[index].frame(level) {
+                Some(Phys::Size4Kb(f)) => {
+                    table = unsafe { PageTable::load_mut_table(Phys::Size4Kb(f)) };
+                }
+                Some(_) | None => return None,
+            }
+        }
+
+        Some(&mut table[addr.page_table_index(0)])
+    }
+}
+
+impl Index<usize> for PageTable {
+    type Output = PageTableEntry;
+
+    #[inline]
+    fn index(&self, index: usize) -> &Self::Output {
+        &self.entries[index]
+    }
+}
+
+impl Index<PageTableIndex> for PageTable {
+    type Output = PageTableEntry;
+
+    #[inline]
+    fn index(&self, index: PageTableIndex) -> &Self::Output {
+        &self.entries[usize::from(index)]
+    }
+}
+
+impl IndexMut<usize> for PageTable {
+    #[inline]
+    fn index_mut(&mut self, index: usize) -> &mut Self::Output {
+        &mut self.entries[index]
+    }
+}
+
+impl IndexMut<PageTableIndex> for PageTable {
+    #[inline]
+    fn index_mut(&mut self, index: PageTableIndex) -> &mut Self::Output {
+        &mut self.entries[usize::from(index)]
+    }
+}
+
+// TODO: Parameterize with page size
+#[derive(Debug)]
+pub enum PageMapError {
+    FrameAllocation,
+    PageAlreadyMapped,
+    /// There's no 4KiB mapping to change
+    PageNotMapped,
+    /// The page's address isn't canonical so it can never be accessed
+    NonCanonical,
+    /// The entry maps physical frame 0, which is almost certainly a bug
+    ReservedFrame,
+    /// The page isn't aligned to the size of the frame being mapped
+    Misaligned,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PageUnmapError {
+    /// The leaf entry isn't present
+    PageNotMapped,
+    /// The table at this level, which would hold the leaf, isn't present
+    ParentNotMapped(usize),
+}
+
+/// In debug builds, reject a present leaf entry pointing at physical frame 0
+fn check_zero_frame(entry: PageTableEntry) -> Result<(), PageMapError> {
+    if !cfg!(debug_assertions) || !entry.flags().contains(PageTableEntryFlags::PRESENT) {
+        return Ok(());
+    }
+
+    match entry.frame(0) {
+        Some(frame) if frame.start_address().as_u64() == 0 => Err(PageMapError::ReservedFrame),
+        _ => Ok(()),
+    }
+}
+
+/// Leaf mappings which are contiguous in both address spaces and share flags
+struct MappingRun {
+    virt: u64,
+    phys: u64,
+    len: u64,
+    flags: PageTableEntryFlags,
+}
+
+/// The state of a `dump_mappings` walk
+struct MappingDump {
+    current: Option<MappingRun>,
+    runs: usize,
+    bytes: u64,
+    verbose: bool,
+}
+
+impl MappingDump {
+    /// Extend the current run with `leaf` if it follows on, otherwise start a new run
+    fn add(&mut self, leaf: MappingRun) {
+        if let Some(run) = self.current.as_mut() {
+            if leaf.virt == run.virt + run.len
+                && leaf.phys == run.phys + run.len
+                && leaf.flags == run.flags
+            {
+                run.len += leaf.len;
+                return;
+            }
+        }
+
+        self.finish_run();
+        self.current = Some(leaf);
+    }
+
+    fn finish_run(&mut self) {
+        if let Some(run) = self.current.take() {
+            self.runs += 1;
+            self.bytes += run.len;
+            if self.verbose {
+                println!(
+                    "{:#x}-{:#x} -> {:#x}-{:#x} ({:?})",
+                    run.virt,
+                    run.virt + run.len,
+                    run.phys,
+                    run.phys + run.len,
+                    run.flags
+                );
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CowError {
+    PageNotMapped,
+    NotCopyOnWrite,
+    FrameAllocation,
+}
+
+// TODO: Add huge page tests
+#[cfg(test)]
+mod tests {
+    use alloc::string::String;
+    use x86_64::structures::paging::{PhysFrame, Size2MiB, Size4KiB};
+
+    use crate::{
+        allocator::{
+            frame_refs, BootInfoAllocator, FrameAllocator, FrameDeallocator, ZeroAllocator,
+            FRAME_ALLOCATOR,
+        },
+        klog::{KERNEL_LOG, LOG_CAPACITY},
+        memory::get_offset,
+        paging::{Page, PageRangeInclusive, PageTableEntry, PageTableEntryFlags, Phys},
+        phys_addr::PhysAddr,
+        virt_addr::VirtAddr,
+    };
+
+    use super::{page_flushes, tlb_generation, PageMapError, PageTable, PageUnmapError};
+
+    /// Hands out frames from a small pool which starts out empty
+    struct PoolAllocator {
+        frames: [Option<PhysFrame>; 3],
+    }
+
+    impl FrameAllocator for PoolAllocator {
+        fn allocate(&mut self) -> Option<PhysFrame> {
+            self.frames.iter_mut().find_map(|f| f.take())
+        }
+    }
+
+    #[test_case]
+    fn present_entries_yields_populated_slots() {
+        let mut table = PageTable::new();
+        let frame = PhysFrame::<Size4KiB>::from_start_address(PhysAddr::new(4096).into()).unwrap();
+
+        table[3] = PageTableEntry::new(frame, PageTableEntryFlags::PRESENT);
+        table[200] = PageTableEntry::new(frame, PageTableEntryFlags::PRESENT);
+        table[511] = PageTableEntry::new(frame, PageTableEntryFlags::PRESENT);
+        table[7] = PageTableEntry::new(frame, PageTableEntryFlags::WRITABLE);
+
+        let mut present = table.present_entries().map(|(i, _)| usize::from(i));
+        assert_eq!(present.next(), Some(3));
+        assert_eq!(present.next(), Some(200));
+        assert_eq!(present.next(), Some(511));
+        assert_eq!(present.next(), None);
+        assert_eq!(table.entries().count(), 512);
+    }
+
+    #[test_case]
+    fn map_non_canonical_page() {
+        let mut table = PageTable::new();
+        let addr = unsafe { VirtAddr::new_unchecked(0x0000_8000_0000_0000) };
+        let page = Page::containing_address(addr);
+        let frame = PhysFrame::<Size4KiB>::from_start_address(PhysAddr::new(4096).into()).unwrap();
+        let entry = PageTableEntry::new(frame, PageTableEntryFlags::PRESENT);
+
+        match unsafe { table.map_page(page, entry, &mut ZeroAllocator) } {
+            Ok(_) => panic!("non canonical page was mapped"),
+            Err(PageMapError::NonCanonical) => {}
+            Err(err) => panic!("unexpected error: {:?}", err),
+        }
+    }
+
+    #[test_case]
+    fn clone_shares_sub_tables() {
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+        let mut table = PageTable::new();
+        let page = Page::containing_address(VirtAddr::new(0xDEADBEEF));
+        let frame = PhysFrame::<Size4KiB>::from_start_address(PhysAddr::new(4096).into()).unwrap();
+        let entry = PageTableEntry::new(frame, PageTableEntryFlags::PRESENT);
+        let result = unsafe { table.map_page(page, entry, &mut *alloc.lock()) };
+        match result {
+            Ok(_) => {}
+            Err(err) => panic!("error mapping page: {:?}", err),
+        }
+
+        let copy = table.clone();
+        for ((_, original), (_, copied)) in table.entries().zip(copy.entries()) {
+            assert_eq!(
+                original.frame(3).map(|f| f.start_address()),
+                copied.frame(3).map(|f| f.start_address())
+            );
+            assert_eq!(original.flags(), copied.flags());
+        }
+        assert_eq!(
+            copy.translate_addr(page.as_virt_addr()),
+            Some(PhysAddr::new(4096))
+        );
+    }
+
+    #[test_case]
+    fn user_page_tables_are_user_accessible() {
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+        let mut table = PageTable::new();
+        let addr = VirtAddr::new(0x2000_0000_0000);
+        let frame = PhysFrame::<Size4KiB>::from_start_address(PhysAddr::new(4096).into()).unwrap();
+        let entry = PageTableEntry::new(frame, PageTableEntryFlags::user_rw());
+        let result =
+            unsafe { table.map_page(Page::containing_address(addr), entry, &mut *alloc.lock()) };
+        match result {
+            Ok(_) => {}
+            Err(err) => panic!("error mapping page: {:?}", err),
+        }
+
+        assert!(table[addr.page_table_index(3)]
+            .flags()
+            .contains(PageTableEntryFlags::USER_ACCESSIBLE));
+    }
+
+    #[test_case]
+    fn user_page_opens_kernel_tables() {
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+        let mut table = PageTable::new();
+        let frame = PhysFrame::<Size4KiB>::from_start_address(PhysAddr::new(4096).into()).unwrap();
+        // Both pages share every table down to the last level
+        let kernel = VirtAddr::new(0x2200_0000_0000);
+        let user = VirtAddr::new(0x2200_0000_1000);
+        let mappings = [
+            (kernel, PageTableEntryFlags::kernel_rw()),
+            (user, PageTableEntryFlags::user_rw()),
+        ];
+        for (addr, flags) in mappings {
+            let entry = PageTableEntry::new(frame, flags);
+            let page = Page::containing_address(addr);
+            match unsafe { table.map_page(page, entry, &mut *alloc.lock()) } {
+                Ok(_) => {}
+                Err(err) => panic!("error mapping page: {:?}", err),
+            }
+        }
+
+        let mut level_table = &table;
+        for level in (1..4).rev() {
+            let entry = level_table[user.page_table_index(level)];
+            assert!(entry.flags().contains(PageTableEntryFlags::USER_ACCESSIBLE));
+            level_table = match entry.frame(level) {
+                Some(f) => unsafe { PageTable::load_table(f) },
+                None => panic!("level {} table missing", level),
+            };
+        }
+        let (_, kernel_flags) = match table.translate_with_flags(kernel) {
+            Some(m) => m,
+            None => panic!("{:?} isn't mapped", kernel),
+        };
+        assert!(!kernel_flags.contains(PageTableEntryFlags::USER_ACCESSIBLE));
+    }
+
+    #[test_case]
+    fn clone_cow_write_protects_both_tables() {
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+        let mut alloc = alloc.lock();
+        let frame = match alloc.allocate() {
+            Some(f) => f,
+            None => panic!("could not allocate frame"),
+        };
+
+        let mut parent = PageTable::new();
+        let addr = VirtAddr::new(0x2100_0000_0000);
+        let entry = PageTableEntry::new(frame, PageTableEntryFlags::user_rw());
+        let result = unsafe { parent.map_page(Page::containing_address(addr), entry, &mut *alloc) };
+        match result {
+            Ok(_) => {}
+            Err(err) => panic!("error mapping page: {:?}", err),
+        }
+
+        let mut child = match parent.clone_cow(&mut *alloc) {
+            Ok(t) => t,
+            Err(err) => panic!("error cloning table: {:?}", err),
+        };
+
+        for table in [&parent, &child] {
+            let (phys, flags) = match table.translate_with_flags(addr) {
+                Some(m) => m,
+                None => panic!("{:?} isn't mapped", addr),
+            };
+            assert_eq!(phys, frame.start_address().into());
+            assert!(!flags.contains(PageTableEntryFlags::WRITABLE));
+            assert!(flags.contains(PageTableEntryFlags::COPY_ON_WRITE));
+        }
+        // The walk is duplicated rather than shared
+        let index = addr.page_table_index(3);
+        let parent_l3 = parent[index].frame(3).map(|f| f.start_address());
+        let child_l3 = child[index].frame(3).map(|f| f.start_address());
+        assert_ne!(parent_l3, child_l3);
+
+        child.free_user_mappings(&mut *alloc);
+        parent.free_user_mappings(&mut *alloc);
+    }
+
+    #[test_case]
+    fn get_unmapped_address() {
+        let table = PageTable::new();
+        let addr = VirtAddr::new(1234);
+        let phys_addr = table.translate_addr(addr);
+
+        match phys_addr {
+            Some(pa) => panic!("{:?} was mapped to {} unexpectedly", addr, pa.as_u64()),
+            None => (),
+        };
+    }
+
+    #[test_case]
+    fn add_valid_entry() {
+        let mut table = PageTable::new();
+        let addr = VirtAddr::new(0xDEADBEEF);
+        let page = Page::containing_address(addr);
+        let frame = PhysFrame::<Size4KiB>::from_start_address(PhysAddr::new(4096).into()).unwrap();
+        let entry = PageTableEntry::new(frame, PageTableEntryFlags::PRESENT);
+
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+
+        let result = unsafe { table.map_page(page, entry, &mut *alloc.lock()) };
+        match result {
+            Ok(_) => {}
+            Err(err) => panic!("error mapping page: {:?}", err),
+        }
+
+        let phys_addr = table.translate_addr(addr);
+
+        match phys_addr {
+            Some(pa) => assert_eq!(pa.as_u64(), addr.page_offset().as_u64() + 4096),
+            None => panic!("new page was not mapped to correct physical frame"),
+        }
+    }
+
+    #[test_case]
+    fn try_to_remap() {
+        let mut table = PageTable::new();
+        let addr = VirtAddr::new(0xABCD);
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+
+        let page = Page::containing_address(addr);
+        let frame = PhysFrame::<Size4KiB>::from_start_address(PhysAddr::new(4096).into()).unwrap();
+        let entry = PageTableEntry::new(frame, PageTableEntryFlags::PRESENT);
+
+        let result = unsafe { table.map_page(page, entry, &mut *alloc.lock()) };
+        match result {
+            Ok(_) => {}
+            Err(err) => panic!("error mapping page: {:?}", err),
+        }
+
+        let result = unsafe { table.map_page(page, entry, &mut *alloc.lock()) };
+        match result {
+            Ok(_) => panic!("page should not be remapped"),
+            Err(PageMapError::PageAlreadyMapped) => {}
+            Err(err) => panic!("error mapping page: {:?}", err),
+        }
+    }
+
+    #[test_case]
+    fn map_zero_frame() {
+        let mut table = PageTable::new();
+        let page = Page::containing_address(VirtAddr::new(0x6000));
+        let frame = PhysFrame::<Size4KiB>::from_start_address(PhysAddr::new(0).into()).unwrap();
+        let entry = PageTableEntry::new(frame, PageTableEntryFlags::PRESENT);
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+
+        let result = unsafe { table.map_page(page, entry, &mut *alloc.lock()) };
+        match (result, cfg!(debug_assertions)) {
+            (Err(PageMapError::ReservedFrame), true) | (Ok(_), false) => {}
+            (result, _) => panic!("unexpected result mapping frame 0: {:?}", result),
+        }
+
+        let result = unsafe { table.map_page_allow_zero_frame(page, entry, &mut *alloc.lock()) };
+        match (result, cfg!(debug_assertions)) {
+            (Ok(_), true) | (Err(PageMapError::PageAlreadyMapped), false) => {}
+            (result, _) => panic!("unexpected result mapping frame 0: {:?}", result),
+        }
+        assert_eq!(
+            table.translate_addr(page.as_virt_addr()),
+            Some(PhysAddr::new(0))
+        );
+    }
+
+    #[test_case]
+    fn reclaim_on_oom() {
+        let mut table = PageTable::new();
+        let addr = VirtAddr::new(0x1234_5000);
+        let page = Page::containing_address(addr);
+        let frame = PhysFrame::<Size4KiB>::from_start_address(PhysAddr::new(4096).into()).unwrap();
+        let entry = PageTableEntry::new(frame, PageTableEntryFlags::PRESENT);
+
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+
+        let mut pool = PoolAllocator {
+            frames: [None, None, None],
+        };
+        let mut calls = 0;
+        let result = unsafe {
+            table.map_page_with_reclaim(page, entry, &mut pool, |pool| {
+                calls += 1;
+                for f in pool.frames.iter_mut() {
+                    *f = alloc.lock().allocate();
+                }
+            })
+        };
+        match result {
+            Ok(_) => {}
+            Err(err) => panic!("error mapping page: {:?}", err),
+        }
+
+        assert_eq!(calls, 1);
+        match table.translate_addr(addr) {
+            Some(pa) => assert_eq!(pa.as_u64(), 4096),
+            None => panic!("new page was not mapped to correct physical frame"),
+        }
+    }
+
+    #[test_case]
+    fn scan_and_clear_accessed_pages() {
+        let mut table = PageTable::new();
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+
+        let frame = PhysFrame::<Size4KiB>::from_start_address(PhysAddr::new(4096).into()).unwrap();
+        let accessed = PageTableEntryFlags::PRESENT | PageTableEntryFlags::ACCESSED;
+        let pages = [
+            (Page::containing_address(VirtAddr::new(0x1000)), accessed),
+            (
+                Page::containing_address(VirtAddr::new(0x4000_0000)),
+                accessed,
+            ),
+            (
+                Page::containing_address(VirtAddr::new(0x8000)),
+                PageTableEntryFlags::PRESENT,
+            ),
+        ];
+        for (page, flags) in pages {
+            let entry = PageTableEntry::new(frame, flags);
+            let result = unsafe { table.map_page(page, entry, &mut *alloc.lock()) };
+            match result {
+                Ok(_) => {}
+                Err(err) => panic!("error mapping page: {:?}", err),
+            }
+        }
+
+        let mut found = [None; 3];
+        let mut count = 0;
+        table.scan_and_clear_accessed(|page| {
+            found[count] = Some(page);
+            count += 1;
+        });
+
+        assert_eq!(count, 2);
+        assert_eq!(found[0], Some(pages[0].0));
+        assert_eq!(found[1], Some(pages[1].0));
+
+        let mut count = 0;
+        table.scan_and_clear_accessed(|_| count += 1);
+        assert_eq!(count, 0);
+    }
+
+    #[test_case]
+    fn unmap_range_reports_frames() {
+        let mut table = PageTable::new();
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+
+        let start = Page::containing_address(VirtAddr::new(0x7000_0000));
+        let end = start + 5;
+        for (i, page) in PageRangeInclusive::new(start, end).enumerate() {
+            let frame = PhysFrame::<Size4KiB>::containing_address(
+                PhysAddr::new(0x10000 + i as u64 * 4096).into(),
+            );
+            let entry = PageTableEntry::new(frame, PageTableEntryFlags::PRESENT);
+            let result = unsafe { table.map_page(page, entry, &mut *alloc.lock()) };
+            match result {
+                Ok(_) => {}
+                Err(err) => panic!("error mapping page: {:?}", err),
+            }
+        }
+
+        let mut freed = 0;
+        table.unmap_range(PageRangeInclusive::new(start, end), &mut |frame| {
+            assert_eq!(frame.start_address().as_u64(), 0x10000 + freed * 4096);
+            freed += 1;
+        });
+        assert_eq!(freed, 5);
+
+        for page in PageRangeInclusive::new(start, end) {
+            match table.translate_addr(page.as_virt_addr()) {
+                Some(pa) => panic!("{:?} is still mapped to {}", page, pa.as_u64()),
+                None => {}
+            }
+        }
+    }
+
+    #[test_case]
+    fn map_and_unmap_bump_tlb_generation() {
+        let mut table = PageTable::new();
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+
+        let page = Page::containing_address(VirtAddr::new(0x7100_0000));
+        let frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(0x10000).into());
+        let entry = PageTableEntry::new(frame, PageTableEntryFlags::PRESENT);
+
+        let before_map = tlb_generation();
+        let result = unsafe { table.map_page(page, entry, &mut *alloc.lock()) };
+        match result {
+            Ok(_) => {}
+            Err(err) => panic!("error mapping page: {:?}", err),
+        }
+        assert!(tlb_generation() > before_map);
+
+        let before_unmap = tlb_generation();
+        table.unmap_range(PageRangeInclusive::new(page, page + 1), &mut |_| {});
+        assert!(tlb_generation() > before_unmap);
+    }
+
+    #[test_case]
+    fn unmap_page_returns_frame() {
+        let mut table = PageTable::new();
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+
+        let page = Page::containing_address(VirtAddr::new(0x7200_0000));
+        let frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(0x10000).into());
+        let entry = PageTableEntry::new(frame, PageTableEntryFlags::PRESENT);
+        let result = unsafe { table.map_page(page, entry, &mut *alloc.lock()) };
+        match result {
+            Ok(_) => {}
             Err(err) => panic!("error mapping page: {:?}", err),
         }
 
+        match table.unmap_page(page) {
+            Ok(f) => assert_eq!(f.start_address(), frame.start_address().into()),
+            Err(err) => panic!("error unmapping page: {:?}", err),
+        }
+        assert!(table.translate_addr(page.as_virt_addr()).is_none());
+        assert!(matches!(
+            table.unmap_page(page),
+            Err(PageUnmapError::PageNotMapped)
+        ));
+    }
+
+    #[test_case]
+    fn unmap_page_reports_missing_parent() {
+        let mut table = PageTable::new();
+        let page = Page::containing_address(VirtAddr::new(0x7300_0000));
+
+        assert!(matches!(
+            table.unmap_page(page),
+            Err(PageUnmapError::ParentNotMapped(3))
+        ));
+    }
+
+    #[test_case]
+    fn map_page_flushes_only_that_page() {
+        let mut table = PageTable::new();
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+
+        let page = Page::containing_address(VirtAddr::new(0x7400_0000));
+        let frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(0x10000).into());
+        let entry = PageTableEntry::new(frame, PageTableEntryFlags::PRESENT);
+
+        let before = page_flushes();
         let result = unsafe { table.map_page(page, entry, &mut *alloc.lock()) };
         match result {
-            Ok(_) => panic!("page should not be remapped"),
-            Err(PageMapError::PageAlreadyMapped) => {}
+            Ok(_) => {}
+            Err(err) => panic!("error mapping page: {:?}", err),
+        }
+        assert_eq!(page_flushes(), before + 1);
+
+        let before = page_flushes();
+        let result = unsafe { table.map_page_no_flush(page + 1, entry, &mut *alloc.lock()) };
+        match result {
+            Ok(_) => {}
+            Err(err) => panic!("error mapping page: {:?}", err),
+        }
+        assert_eq!(page_flushes(), before);
+    }
+
+    #[test_case]
+    fn map_2m_huge_page() {
+        let mut table = PageTable::new();
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+
+        let page = Page::containing_address(VirtAddr::new(0x4000_0000));
+        let frame = PhysFrame::<Size2MiB>::containing_address(PhysAddr::new(0x20_0000).into());
+        let result = unsafe {
+            table.map_huge_page(
+                page,
+                Phys::Size2Mb(frame),
+                PageTableEntryFlags::PRESENT,
+                &mut *alloc.lock(),
+            )
+        };
+        match result {
+            Ok(_) => {}
+            Err(err) => panic!("error mapping huge page: {:?}", err),
+        }
+
+        let addr = VirtAddr::new(0x4012_3456);
+        assert_eq!(table.translate_addr(addr), Some(PhysAddr::new(0x32_3456)));
+
+        // A 4KiB page inside the huge page is already mapped
+        let inner = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(0x10000).into());
+        let entry = PageTableEntry::new(inner, PageTableEntryFlags::PRESENT);
+        let result = unsafe { table.map_page(page + 1, entry, &mut *alloc.lock()) };
+        assert!(matches!(result, Err(PageMapError::PageAlreadyMapped)));
+    }
+
+    #[test_case]
+    fn reject_misaligned_huge_page() {
+        let mut table = PageTable::new();
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+
+        let page = Page::containing_address(VirtAddr::new(0x4000_1000));
+        let frame = PhysFrame::<Size2MiB>::containing_address(PhysAddr::new(0x20_0000).into());
+        let result = unsafe {
+            table.map_huge_page(
+                page,
+                Phys::Size2Mb(frame),
+                PageTableEntryFlags::PRESENT,
+                &mut *alloc.lock(),
+            )
+        };
+        assert!(matches!(result, Err(PageMapError::Misaligned)));
+    }
+
+    #[test_case]
+    fn new_tables_are_zeroed() {
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+        let mut dirty = PoolAllocator { frames: [None; 3] };
+        for slot in dirty.frames.iter_mut() {
+            let frame = match alloc.lock().allocate() {
+                Some(f) => f,
+                None => panic!("could not allocate frame"),
+            };
+            let ptr: *mut u8 = (get_offset() + frame.start_address().as_u64()).as_mut_ptr();
+            unsafe { ptr.write_bytes(0xFF, 4096) }; // This is safe as the frame was just allocated
+            *slot = Some(frame);
+        }
+
+        let mut table = PageTable::new();
+        let page = Page::containing_address(VirtAddr::new(0x7500_0000));
+        let frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(0x10000).into());
+        let entry = PageTableEntry::new(frame, PageTableEntryFlags::PRESENT);
+        let result = unsafe { table.map_page(page, entry, &mut dirty) };
+        match result {
+            Ok(_) => {}
+            Err(err) => panic!("error mapping page: {:?}", err),
+        }
+
+        assert_eq!(
+            table.translate_addr(page.as_virt_addr()),
+            Some(frame.start_address().into())
+        );
+        // Garbage entries would make the neighbouring pages look mapped
+        assert!(table.translate_addr((page + 1).as_virt_addr()).is_none());
+        assert!(table
+            .translate_addr(VirtAddr::new(0x7500_0000 + 0x20_0000))
+            .is_none());
+    }
+
+    #[test_case]
+    fn translate_with_flags_returns_leaf_flags() {
+        let mut table = PageTable::new();
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+
+        let page = Page::containing_address(VirtAddr::new(0x7600_0000));
+        let frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(0x10000).into());
+        let entry = PageTableEntry::new(frame, PageTableEntryFlags::kernel_rw());
+        let result = unsafe { table.map_page(page, entry, &mut *alloc.lock()) };
+        match result {
+            Ok(_) => {}
+            Err(err) => panic!("error mapping page: {:?}", err),
+        }
+
+        match table.translate_with_flags(page.as_virt_addr() + 0x123) {
+            Some((addr, flags)) => {
+                assert_eq!(addr, PhysAddr::new(0x10123));
+                assert!(flags.contains(PageTableEntryFlags::WRITABLE));
+                assert!(!flags.contains(PageTableEntryFlags::USER_ACCESSIBLE));
+            }
+            None => panic!("mapped page doesn't translate"),
+        }
+        assert!(table
+            .translate_with_flags((page + 1).as_virt_addr())
+            .is_none());
+    }
+
+    #[test_case]
+    fn translate_with_flags_huge_page() {
+        let mut table = PageTable::new();
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+
+        let page = Page::containing_address(VirtAddr::new(0x4000_0000));
+        let frame = PhysFrame::<Size2MiB>::containing_address(PhysAddr::new(0x20_0000).into());
+        let result = unsafe {
+            table.map_huge_page(
+                page,
+                Phys::Size2Mb(frame),
+                PageTableEntryFlags::kernel_ro(),
+                &mut *alloc.lock(),
+            )
+        };
+        match result {
+            Ok(_) => {}
+            Err(err) => panic!("error mapping huge page: {:?}", err),
+        }
+
+        match table.translate_with_flags(VirtAddr::new(0x4010_0000)) {
+            Some((addr, flags)) => {
+                assert_eq!(addr, PhysAddr::new(0x30_0000));
+                assert!(flags.contains(PageTableEntryFlags::HUGE_PAGE));
+                assert!(!flags.contains(PageTableEntryFlags::WRITABLE));
+            }
+            None => panic!("huge page doesn't translate"),
+        }
+    }
+
+    #[test_case]
+    fn map_range_maps_every_page() {
+        let mut table = PageTable::new();
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+
+        let first: Page = Page::containing_address(VirtAddr::new(0x5000_0000));
+        let pages = PageRangeInclusive::new(first, first + 4);
+        let result = table.map_range(pages, PageTableEntryFlags::kernel_rw(), &mut *alloc.lock());
+        match result {
+            Ok(_) => {}
+            Err(err) => panic!("error mapping range: {:?}", err),
+        }
+
+        for i in 0..4 {
+            assert!(table.translate_addr((first + i).as_virt_addr()).is_some());
+        }
+        assert!(table.translate_addr((first + 4).as_virt_addr()).is_none());
+    }
+
+    /// Forwards to another allocator until `remaining` frames have been handed out, then fails
+    struct FailAfter<'a> {
+        inner: &'a mut BootInfoAllocator,
+        remaining: usize,
+    }
+
+    impl FrameAllocator for FailAfter<'_> {
+        fn allocate(&mut self) -> Option<PhysFrame> {
+            self.remaining = self.remaining.checked_sub(1)?;
+            self.inner.allocate()
+        }
+    }
+
+    impl FrameDeallocator for FailAfter<'_> {
+        unsafe fn deallocate(&mut self, frame: PhysFrame) {
+            self.inner.deallocate(frame);
+        }
+    }
+
+    #[test_case]
+    fn failed_map_range_returns_frames() {
+        let mut table = PageTable::new();
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+        let mut alloc = alloc.lock();
+        let first: Page = Page::containing_address(VirtAddr::new(0x5000_0000));
+        let flags = PageTableEntryFlags::kernel_rw();
+
+        // Map and unmap a page first so the intermediate tables exist before counting frames
+        match table.map_range(
+            PageRangeInclusive::new(first, first + 1),
+            flags,
+            &mut *alloc,
+        ) {
+            Ok(_) => {}
+            Err(err) => panic!("error mapping range: {:?}", err),
+        }
+        match table.unmap_page(first) {
+            Ok(Phys::Size4Kb(frame)) => unsafe { alloc.deallocate(frame) }, // The page was only just mapped
+            result => panic!("unexpected unmap result: {:?}", result),
+        }
+        let allocated = alloc.allocated_frames();
+
+        let mut failing = FailAfter {
+            inner: &mut alloc,
+            remaining: 4,
+        };
+        match table.map_range(
+            PageRangeInclusive::new(first, first + 8),
+            flags,
+            &mut failing,
+        ) {
+            Err(PageMapError::FrameAllocation) => {}
+            result => panic!("unexpected result: {:?}", result),
+        }
+
+        assert_eq!(alloc.allocated_frames(), allocated);
+        for i in 0..8 {
+            assert!(table.translate_addr((first + i).as_virt_addr()).is_none());
+        }
+    }
+
+    #[test_case]
+    fn failed_clone_cow_frees_child_tables() {
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+        let mut alloc = alloc.lock();
+
+        // The pages need separate level 2 & 1 tables, so cloning the second one needs 2 frames
+        let mut parent = PageTable::new();
+        let addrs = [
+            VirtAddr::new(0x2200_0000_0000),
+            VirtAddr::new(0x2200_4000_0000),
+        ];
+        let mut frames = [None; 2];
+        for (addr, slot) in addrs.iter().zip(frames.iter_mut()) {
+            let frame = match alloc.allocate() {
+                Some(f) => f,
+                None => panic!("could not allocate frame"),
+            };
+            let entry = PageTableEntry::new(frame, PageTableEntryFlags::user_rw());
+            let page = Page::containing_address(*addr);
+            match unsafe { parent.map_page(page, entry, &mut *alloc) } {
+                Ok(_) => {}
+                Err(err) => panic!("error mapping page: {:?}", err),
+            }
+            *slot = Some(frame);
+        }
+        let allocated = alloc.allocated_frames();
+
+        let mut failing = FailAfter {
+            inner: &mut alloc,
+            remaining: 4,
+        };
+        match parent.clone_cow(&mut failing) {
+            Err(PageMapError::FrameAllocation) => {}
+            Err(err) => panic!("unexpected error: {:?}", err),
+            Ok(_) => panic!("clone succeeded without enough frames"),
+        }
+
+        assert_eq!(alloc.allocated_frames(), allocated);
+        for frame in frames.iter().flatten() {
+            assert_eq!(frame_refs(*frame), 1);
+        }
+        parent.free_user_mappings(&mut *alloc);
+    }
+
+    #[test_case]
+    fn identity_map_frame() {
+        let mut table = PageTable::new();
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+
+        let frame = PhysFrame::containing_address(PhysAddr::new(0x8000).into());
+        let result = unsafe {
+            table.identity_map(frame, PageTableEntryFlags::kernel_rw(), &mut *alloc.lock())
+        };
+        match result {
+            Ok(_) => {}
+            Err(err) => panic!("error identity mapping frame: {:?}", err),
+        }
+
+        assert_eq!(
+            table.translate_addr(VirtAddr::new(0x8000)),
+            Some(PhysAddr::new(0x8000))
+        );
+    }
+
+    #[test_case]
+    fn update_flags_makes_page_read_only() {
+        let mut table = PageTable::new();
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+
+        let page: Page = Page::containing_address(VirtAddr::new(0x5000_0000));
+        let frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(0x9000).into());
+        let entry = PageTableEntry::new(frame, PageTableEntryFlags::kernel_rw());
+        match unsafe { table.map_page(page, entry, &mut *alloc.lock()) } {
+            Ok(_) => {}
+            Err(err) => panic!("error mapping page: {:?}", err),
+        }
+
+        match table.update_flags(page, PageTableEntryFlags::kernel_ro()) {
+            Ok(_) => {}
+            Err(err) => panic!("error updating flags: {:?}", err),
+        }
+
+        match table.translate_with_flags(page.as_virt_addr()) {
+            Some((addr, flags)) => {
+                assert_eq!(addr, PhysAddr::new(0x9000));
+                assert!(flags.contains(PageTableEntryFlags::PRESENT));
+                assert!(!flags.contains(PageTableEntryFlags::WRITABLE));
+            }
+            None => panic!("page was unmapped"),
+        }
+
+        match table.update_flags(page + 1, PageTableEntryFlags::kernel_ro()) {
+            Err(PageMapError::PageNotMapped) => {}
+            result => panic!("unexpected result updating unmapped page: {:?}", result),
+        }
+    }
+
+    #[test_case]
+    fn is_mapped_matches_translation() {
+        let mut table = PageTable::new();
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+
+        let page: Page = Page::containing_address(VirtAddr::new(0x5000_0000));
+        let frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(0x9000).into());
+        let entry = PageTableEntry::new(frame, PageTableEntryFlags::kernel_rw());
+        match unsafe { table.map_page(page, entry, &mut *alloc.lock()) } {
+            Ok(_) => {}
             Err(err) => panic!("error mapping page: {:?}", err),
         }
+
+        assert!(table.is_mapped(page.as_virt_addr() + 0x123u64));
+        // Shares every table with the mapped page except the leaf
+        assert!(!table.is_mapped((page + 1).as_virt_addr()));
+        // Has no tables at all
+        assert!(!table.is_mapped(VirtAddr::new(0x7000_0000_0000)));
+    }
+
+    #[test_case]
+    fn dump_mappings_lists_each_run() {
+        let mut table = PageTable::new();
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+
+        // Two adjacent pages which merge into one run, and one on its own
+        let mappings = [
+            (0x5000_0000, 0x9000),
+            (0x5000_1000, 0xA000),
+            (0x6000_0000, 0xB000),
+        ];
+        for (virt, phys) in mappings {
+            let page = Page::containing_address(VirtAddr::new(virt));
+            let frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(phys).into());
+            let entry = PageTableEntry::new(frame, PageTableEntryFlags::kernel_rw());
+            match unsafe { table.map_page(page, entry, &mut *alloc.lock()) } {
+                Ok(_) => {}
+                Err(err) => panic!("error mapping page: {:?}", err),
+            }
+        }
+
+        table.dump_mappings(true);
+
+        let mut log = [0; LOG_CAPACITY];
+        let len = KERNEL_LOG.copy_to(&mut log);
+        // The oldest bytes may be part of a cut off character
+        let log = String::from_utf8_lossy(&log[..len]);
+        let tail = match log.rfind("0x50000000-") {
+            Some(start) => &log[start..],
+            None => panic!("first run missing from dump:\n{}", log),
+        };
+        assert!(tail.starts_with("0x50000000-0x50002000 -> 0x9000-0xb000 ("));
+        assert!(tail.contains("0x60000000-0x60001000 -> 0xb000-0xc000 ("));
+        assert!(tail.contains("2 mapped runs, 12288 bytes"));
     }
 }
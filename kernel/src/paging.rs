@@ -1,12 +1,9 @@
-use core::ops::Add;
+use core::{marker::PhantomData, ops::Add};
 
 use bitflags::bitflags;
-use x86_64::{
-    structures::paging::{PageSize, PhysFrame, Size1GiB, Size2MiB, Size4KiB},
-    PhysAddr,
-};
+use x86_64::structures::paging::{PageSize, PhysFrame, Size1GiB, Size2MiB, Size4KiB};
 
-use crate::virt_addr::VirtAddr;
+use crate::{phys_addr::PhysAddr, virt_addr::VirtAddr};
 
 /// Guaranteed to hold only values from 0..4096
 #[derive(Debug)]
@@ -91,20 +88,96 @@ bitflags! {
         const DIRTY = 1 << 6;
         const HUGE_PAGE = 1 << 7;
         const GLOBAL = 1 << 8;
+        /// Software defined, marks a read only page shared copy on write
+        const COPY_ON_WRITE = 1 << 9;
         const NO_EXECUTE = 1 << 63;
     }
 }
 
+impl PageTableEntryFlags {
+    /// Present and writable, for kernel data
+    #[inline]
+    pub fn kernel_rw() -> Self {
+        PageTableEntryFlags::PRESENT | PageTableEntryFlags::WRITABLE
+    }
+
+    /// Present and read only, for kernel constants
+    #[inline]
+    pub fn kernel_ro() -> Self {
+        PageTableEntryFlags::PRESENT
+    }
+
+    /// Present, writable and accessible from ring 3
+    #[inline]
+    pub fn user_rw() -> Self {
+        PageTableEntryFlags::PRESENT
+            | PageTableEntryFlags::WRITABLE
+            | PageTableEntryFlags::USER_ACCESSIBLE
+    }
+
+    /// Present, writable and uncached, for device memory
+    #[inline]
+    pub fn mmio() -> Self {
+        PageTableEntryFlags::PRESENT
+            | PageTableEntryFlags::WRITABLE
+            | PageTableEntryFlags::DISABLE_CACHE
+    }
+
+    /// Present, writable and write combining, for framebuffers
+    ///
+    /// This relies on the PAT layout installed by `pat::init`
+    #[inline]
+    pub fn write_combining() -> Self {
+        PageTableEntryFlags::PRESENT
+            | PageTableEntryFlags::WRITABLE
+            | PageTableEntryFlags::WRITE_THROUGH
+    }
+}
+
+/// The physical address bit which marks memory as encrypted, i.e. the AMD SME C-bit
+///
+/// This is None until SME is detected and enabled, which makes `PageTableEntry::encrypted` a no-op
+pub const ENCRYPTION_BIT: Option<u8> = None;
+
 #[derive(Debug, Clone, Copy)]
 #[repr(transparent)]
 pub struct PageTableEntry(u64);
 
+/// HUGE_PAGE was set for a 4KiB frame or missing for a 2MiB or 1GiB one, holds the frame size
+#[derive(Debug, PartialEq)]
+pub struct HugePageMismatch(pub u64);
+
 impl PageTableEntry {
-    // TODO - Validate huge page flag with passed page size
+    /// Create an entry, debug builds panic if HUGE_PAGE doesn't match the frame size
+    ///
+    /// 2MiB and 1GiB frames need HUGE_PAGE, while a 4KiB entry would read it as the PAT bit.
+    /// Use `try_new` to get an error instead
     pub fn new<S: PageSize>(frame: PhysFrame<S>, flags: PageTableEntryFlags) -> Self {
+        debug_assert!(
+            huge_page_matches::<S>(flags),
+            "HUGE_PAGE doesn't match a {:#x} byte frame in {:?}",
+            S::SIZE,
+            flags
+        );
         PageTableEntry(frame.start_address().as_u64() | flags.bits)
     }
 
+    /// Create an entry, returning an error if HUGE_PAGE doesn't match the frame size
+    pub fn try_new<S: PageSize>(
+        frame: PhysFrame<S>,
+        flags: PageTableEntryFlags,
+    ) -> Result<Self, HugePageMismatch> {
+        match huge_page_matches::<S>(flags) {
+            true => Ok(PageTableEntry(frame.start_address().as_u64() | flags.bits)),
+            false => Err(HugePageMismatch(S::SIZE)),
+        }
+    }
+
+    /// Create a new entry, always setting the PRESENT flag
+    pub fn present<S: PageSize>(frame: PhysFrame<S>, flags: PageTableEntryFlags) -> Self {
+        PageTableEntry::new(frame, flags | PageTableEntryFlags::PRESENT)
+    }
+
     // TODO: Better name for this
     pub fn new_zero() -> Self {
         PageTableEntry(0)
@@ -117,37 +190,75 @@ impl PageTableEntry {
 
         if self.flags().contains(PageTableEntryFlags::HUGE_PAGE) {
             match level {
-                1 => Some(Phys::Size1Gb(PhysFrame::<Size1GiB>::containing_address(
-                    self.addr(),
+                1 => Some(Phys::Size2Mb(PhysFrame::<Size2MiB>::containing_address(
+                    self.addr().into(),
                 ))),
-                2 => Some(Phys::Size2Mb(PhysFrame::<Size2MiB>::containing_address(
-                    self.addr(),
+                2 => Some(Phys::Size1Gb(PhysFrame::<Size1GiB>::containing_address(
+                    self.addr().into(),
                 ))),
                 _ => panic!("huge page mapped at level {}", level + 1),
             }
         } else {
-            Some(Phys::Size4Kb(PhysFrame::containing_address(self.addr())))
+            Some(Phys::Size4Kb(PhysFrame::containing_address(
+                self.addr().into(),
+            )))
         }
     }
 
+    /// Set a physical address bit above the frame, such as the SME C-bit
+    #[inline]
+    pub fn with_phys_bit(self, bit: u8) -> Self {
+        PageTableEntry(self.0 | 1 << bit)
+    }
+
+    /// Mark the mapping as encrypted memory, pass the result to `map_page` to map it encrypted
+    ///
+    /// Without SME there's no encryption bit configured and the entry is unchanged
+    #[inline]
+    pub fn encrypted(self) -> Self {
+        match ENCRYPTION_BIT {
+            Some(bit) => self.with_phys_bit(bit),
+            None => self,
+        }
+    }
+
+    /// The raw value of the entry as the cpu sees it
+    #[inline]
+    pub fn raw(self) -> u64 {
+        self.0
+    }
+
     #[inline]
     fn addr(self) -> PhysAddr {
-        PhysAddr::new(self.0 & 0x000F_FFFF_FFFF_F000)
+        let mut addr = self.0 & 0x000F_FFFF_FFFF_F000;
+        // The encryption bit isn't part of the frame address
+        if let Some(bit) = ENCRYPTION_BIT {
+            addr &= !(1 << bit);
+        }
+
+        PhysAddr::new(addr)
     }
 
     #[inline]
     pub fn flags(self) -> PageTableEntryFlags {
         PageTableEntryFlags::from_bits_truncate(self.0)
     }
+
+    /// Replace the entry's flags, keeping the frame it points to
+    #[inline]
+    pub fn set_flags(&mut self, flags: PageTableEntryFlags) {
+        self.0 = self.addr().as_u64() | flags.bits;
+    }
 }
 
 impl Phys {
     pub fn start_address(self) -> PhysAddr {
-        match self {
+        let start = match self {
             Phys::Size4Kb(f) => f.start_address(),
             Phys::Size2Mb(f) => f.start_address(),
             Phys::Size1Gb(f) => f.start_address(),
-        }
+        };
+        start.into()
     }
 }
 
@@ -157,15 +268,15 @@ impl From<PhysFrame> for Phys {
     }
 }
 
+/// A virtual page of size `S`, 4KiB unless otherwise specified
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 #[repr(transparent)]
-pub struct Page(VirtAddr);
+pub struct Page<S: PageSize = Size4KiB>(VirtAddr, PhantomData<S>);
 
-// TODO: Parameterize pages with size
-impl Page {
+impl<S: PageSize> Page<S> {
     #[inline]
     pub fn containing_address(addr: VirtAddr) -> Self {
-        Page(addr.align_down())
+        Page(addr.align_down_to(S::SIZE), PhantomData)
     }
 
     #[inline]
@@ -179,12 +290,26 @@ impl Page {
     }
 }
 
-impl Add<u64> for Page {
-    type Output = Page;
+impl Page {
+    /// The 4KiB page reached through these 4 level page table indices
+    #[inline]
+    pub fn from_page_table_indices(
+        l4: PageTableIndex,
+        l3: PageTableIndex,
+        l2: PageTableIndex,
+        l1: PageTableIndex,
+    ) -> Self {
+        let offset = PageOffset::new_truncate(0);
+        Page(VirtAddr::from_indices(l4, l3, l2, l1, offset), PhantomData)
+    }
+}
+
+impl<S: PageSize> Add<u64> for Page<S> {
+    type Output = Page<S>;
 
     #[inline]
     fn add(self, rhs: u64) -> Self::Output {
-        Page::containing_address(self.0 + 4096 * rhs)
+        Page::containing_address(self.0 + S::SIZE * rhs)
     }
 }
 
@@ -214,40 +339,56 @@ impl Iterator for PageRangeInclusive {
             None
         }
     }
-}
 
-// trait PageSize {
-//     const SIZE: u64;
-// }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = match self.start < self.end {
+            true => ((self.end.as_virt_addr() - self.start.as_virt_addr()) / 4096) as usize,
+            false => 0,
+        };
+
+        (len, Some(len))
+    }
+}
 
-// #[derive(Debug)]
-// enum Size4KB {}
+/// `len` counts the pages left to iterate, 0 when the start isn't below the end
+impl ExactSizeIterator for PageRangeInclusive {}
 
-// impl PageSize for Size4KB {
-//     const SIZE: u64 = 512;
-// }
+/// Whether HUGE_PAGE is set exactly when `S` is a huge page size
+#[inline]
+fn huge_page_matches<S: PageSize>(flags: PageTableEntryFlags) -> bool {
+    flags.contains(PageTableEntryFlags::HUGE_PAGE) == (S::SIZE != Size4KiB::SIZE)
+}
 
 #[cfg(test)]
 mod tests {
-    use x86_64::{
-        structures::paging::{PhysFrame, Size2MiB, Size4KiB},
-        PhysAddr,
-    };
+    use x86_64::structures::paging::{PageSize, PhysFrame, Size1GiB, Size2MiB, Size4KiB};
 
-    use crate::virt_addr::VirtAddr;
+    use crate::{phys_addr::PhysAddr, virt_addr::VirtAddr, ShouldPanic};
 
     use super::{
-        Page, PageOffset, PageRangeInclusive, PageTableEntry, PageTableEntryFlags, PageTableIndex,
+        HugePageMismatch, Page, PageOffset, PageRangeInclusive, PageTableEntry,
+        PageTableEntryFlags, PageTableIndex,
     };
 
     #[test_case]
     fn add_4kb_page() {
         let addr = VirtAddr::new(4096);
-        let page = Page::containing_address(addr);
+        let page: Page = Page::containing_address(addr);
 
         assert_eq!((page + 5).as_u64(), 24_576);
     }
 
+    #[test_case]
+    fn huge_page_alignment_and_stepping() {
+        let page = Page::<Size2MiB>::containing_address(VirtAddr::new(0x4012_3456));
+        assert_eq!(page.as_u64(), 0x4000_0000);
+        assert_eq!((page + 3).as_u64(), 0x4060_0000);
+
+        let page = Page::<Size1GiB>::containing_address(VirtAddr::new(0x7FFF_FFFF));
+        assert_eq!(page.as_u64(), 0x4000_0000);
+        assert_eq!((page + 1).as_u64(), 0x8000_0000);
+    }
+
     #[test_case]
     fn iterate_inclusive_page_range() {
         let start_page = Page::containing_address(VirtAddr::new(0));
@@ -263,6 +404,40 @@ mod tests {
         assert_eq!(c, 4);
     }
 
+    #[test_case]
+    fn page_from_page_table_indices() {
+        let addr = VirtAddr::new(0xFFFF_E677_BF54_D244);
+        let page = Page::from_page_table_indices(
+            addr.page_table_index(3),
+            addr.page_table_index(2),
+            addr.page_table_index(1),
+            addr.page_table_index(0),
+        );
+
+        assert_eq!(page, Page::containing_address(addr));
+    }
+
+    #[test_case]
+    fn inclusive_page_range_len() {
+        let start_page = Page::containing_address(VirtAddr::new(0));
+        let end_page = Page::containing_address(VirtAddr::new(20_000));
+        let mut page_range = PageRangeInclusive::new(start_page, end_page);
+
+        assert_eq!(page_range.len(), 4);
+        assert_eq!(page_range.size_hint(), (4, Some(4)));
+        page_range.next();
+        assert_eq!(page_range.len(), 3);
+    }
+
+    #[test_case]
+    fn empty_inclusive_page_range_len() {
+        let start_page = Page::containing_address(VirtAddr::new(20_000));
+        let end_page = Page::containing_address(VirtAddr::new(0));
+
+        assert_eq!(PageRangeInclusive::new(start_page, end_page).len(), 0);
+        assert_eq!(PageRangeInclusive::new(start_page, start_page).len(), 0);
+    }
+
     #[test_case]
     fn iterate_reverse_inclusive_page_range() {
         let start_page = Page::containing_address(VirtAddr::new(20_000));
@@ -287,7 +462,7 @@ mod tests {
     #[test_case]
     fn unmapped_page_returns_none() {
         let pte = PageTableEntry::new(
-            PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(0)),
+            PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(0).into()),
             PageTableEntryFlags { bits: 0 },
         );
 
@@ -301,7 +476,7 @@ mod tests {
     #[test_case]
     fn mapped_page_returns_frame() {
         let pte = PageTableEntry::new(
-            PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(4096)),
+            PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(4096).into()),
             PageTableEntryFlags::PRESENT,
         );
 
@@ -314,11 +489,10 @@ mod tests {
         };
     }
 
-    // TODO: Test huge page panics when panicking tests are supported
     #[test_case]
     fn mapped_hugepage_returns_frame() {
         let pte = PageTableEntry::new(
-            PhysFrame::<Size2MiB>::containing_address(PhysAddr::new(8000)),
+            PhysFrame::<Size2MiB>::containing_address(PhysAddr::new(8000).into()),
             PageTableEntryFlags::PRESENT | PageTableEntryFlags::HUGE_PAGE,
         );
 
@@ -331,6 +505,62 @@ mod tests {
         };
     }
 
+    #[test_case]
+    const HUGE_PAGE_AT_LEVEL_ZERO: ShouldPanic = ShouldPanic::new(
+        concat!(module_path!(), "::huge_page_at_level_zero"),
+        huge_page_at_level_zero,
+    );
+
+    fn huge_page_at_level_zero() {
+        // Built by hand, `new` rejects this combination
+        let flags = PageTableEntryFlags::PRESENT | PageTableEntryFlags::HUGE_PAGE;
+        let pte = PageTableEntry(4096 | flags.bits());
+
+        pte.frame(0);
+    }
+
+    #[test_case]
+    fn phys_bit_set_in_raw_entry() {
+        let pte = PageTableEntry::new(
+            PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(4096).into()),
+            PageTableEntryFlags::PRESENT,
+        )
+        .with_phys_bit(47);
+
+        assert_eq!(pte.raw(), 1 << 47 | 4096 | 1);
+    }
+
+    #[test_case]
+    fn encrypted_is_noop_without_sme() {
+        let pte = PageTableEntry::new(
+            PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(4096).into()),
+            PageTableEntryFlags::PRESENT,
+        );
+
+        assert_eq!(pte.encrypted().raw(), pte.raw());
+    }
+
+    #[test_case]
+    fn present_entry_sets_present() {
+        let pte = PageTableEntry::present(
+            PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(4096).into()),
+            PageTableEntryFlags::WRITABLE,
+        );
+
+        assert_eq!(
+            pte.flags(),
+            PageTableEntryFlags::PRESENT | PageTableEntryFlags::WRITABLE
+        );
+    }
+
+    #[test_case]
+    fn flag_presets() {
+        assert_eq!(PageTableEntryFlags::kernel_rw().bits(), 0b11);
+        assert_eq!(PageTableEntryFlags::kernel_ro().bits(), 0b1);
+        assert_eq!(PageTableEntryFlags::user_rw().bits(), 0b111);
+        assert_eq!(PageTableEntryFlags::mmio().bits(), 0b10011);
+    }
+
     #[test_case]
     fn page_table_index_truncate() {
         let index = 1234;
@@ -346,4 +576,34 @@ mod tests {
 
         assert_eq!(u16::from(offset), 57);
     }
+
+    #[test_case]
+    fn try_new_accepts_matching_huge_page_flag() {
+        let flags = PageTableEntryFlags::PRESENT;
+        let huge = flags | PageTableEntryFlags::HUGE_PAGE;
+
+        let small = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(0x1000).into());
+        assert!(PageTableEntry::try_new(small, flags).is_ok());
+        let medium = PhysFrame::<Size2MiB>::containing_address(PhysAddr::new(0x20_0000).into());
+        assert!(PageTableEntry::try_new(medium, huge).is_ok());
+        let large = PhysFrame::<Size1GiB>::containing_address(PhysAddr::new(0x4000_0000).into());
+        assert!(PageTableEntry::try_new(large, huge).is_ok());
+    }
+
+    #[test_case]
+    fn try_new_rejects_mismatched_huge_page_flag() {
+        let flags = PageTableEntryFlags::PRESENT;
+        let huge = flags | PageTableEntryFlags::HUGE_PAGE;
+
+        let small = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(0x1000).into());
+        assert_eq!(
+            PageTableEntry::try_new(small, huge).err(),
+            Some(HugePageMismatch(Size4KiB::SIZE))
+        );
+        let medium = PhysFrame::<Size2MiB>::containing_address(PhysAddr::new(0x20_0000).into());
+        assert_eq!(
+            PageTableEntry::try_new(medium, flags).err(),
+            Some(HugePageMismatch(Size2MiB::SIZE))
+        );
+    }
 }
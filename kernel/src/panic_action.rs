@@ -0,0 +1,81 @@
+use x86_64::{
+    instructions::{interrupts, port::Port, tables::lidt},
+    structures::DescriptorTablePointer,
+    VirtAddr,
+};
+
+use crate::{exit_qemu, hlt_loop, QemuExitCode};
+
+/// What the kernel does once it has reported a panic
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PanicAction {
+    /// Stop so the machine can be inspected
+    Halt,
+    Reboot,
+    /// Exit QEMU with a failure code, for automated runs
+    ExitQemu,
+}
+
+/// The panic action chosen at build time with the `panic_reboot` and `panic_exit_qemu` features
+pub const PANIC_ACTION: PanicAction = if cfg!(feature = "panic_exit_qemu") {
+    PanicAction::ExitQemu
+} else if cfg!(feature = "panic_reboot") {
+    PanicAction::Reboot
+} else {
+    PanicAction::Halt
+};
+
+impl PanicAction {
+    /// The code QEMU should exit with, if this action exits QEMU
+    pub fn exit_code(self) -> Option<QemuExitCode> {
+        match self {
+            PanicAction::ExitQemu => Some(QemuExitCode::Failed),
+            _ => None,
+        }
+    }
+
+    pub fn run(self) -> ! {
+        if let Some(code) = self.exit_code() {
+            exit_qemu(code);
+        }
+
+        if self == PanicAction::Reboot {
+            reboot();
+        }
+
+        hlt_loop();
+    }
+}
+
+/// Reset the machine through the PCI reset control register, falling back to a triple fault
+pub fn reboot() -> ! {
+    interrupts::disable();
+    unsafe { Port::<u8>::new(0xCF9).write(0x06) }; // A full reset is what we want here
+
+    // With an empty IDT any exception escalates to a triple fault, which resets the cpu
+    let empty = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::new(0),
+    };
+    unsafe { lidt(&empty) };
+    interrupts::int3();
+
+    hlt_loop();
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::QemuExitCode;
+
+    use super::PanicAction;
+
+    #[test_case]
+    fn exit_qemu_action_fails() {
+        assert_eq!(
+            PanicAction::ExitQemu.exit_code(),
+            Some(QemuExitCode::Failed)
+        );
+        assert_eq!(PanicAction::Halt.exit_code(), None);
+        assert_eq!(PanicAction::Reboot.exit_code(), None);
+    }
+}
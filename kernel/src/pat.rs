@@ -0,0 +1,120 @@
+use core::arch::{asm, x86_64::__cpuid};
+
+use x86_64::{instructions::tlb, registers::model_specific::Msr};
+
+use crate::paging::PageTableEntryFlags;
+
+const IA32_PAT: u32 = 0x277;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MemoryType {
+    Uncacheable = 0,
+    WriteCombining = 1,
+    WriteThrough = 4,
+    WriteProtected = 5,
+    WriteBack = 6,
+    /// Uncacheable, but can be overridden to write combining by the MTRRs
+    Uncached = 7,
+}
+
+/// The PAT installed by `init`, indexed by the PAT, PCD & PWT bits of a 4KiB page table entry
+///
+/// This is the power on default except for entry 1, so PWT on its own selects write combining
+pub const PAT_LAYOUT: [MemoryType; 8] = [
+    MemoryType::WriteBack,
+    MemoryType::WriteCombining,
+    MemoryType::Uncached,
+    MemoryType::Uncacheable,
+    MemoryType::WriteBack,
+    MemoryType::WriteThrough,
+    MemoryType::Uncached,
+    MemoryType::Uncacheable,
+];
+
+/// Program the PAT MSR with `PAT_LAYOUT`, if the CPU supports it
+pub fn init() {
+    if !is_supported() {
+        return;
+    }
+
+    unsafe {
+        Msr::new(IA32_PAT).write(encode(PAT_LAYOUT));
+        // Nothing can be cached with the old memory types
+        asm!("wbinvd", options(nostack, preserves_flags));
+    }
+    tlb::flush_all();
+}
+
+#[inline]
+pub fn is_supported() -> bool {
+    let features = unsafe { __cpuid(1) }; // cpuid leaf 1 is always available on x86_64
+    features.edx & (1 << 16) != 0
+}
+
+/// Read the raw value of the PAT MSR
+pub fn read() -> u64 {
+    unsafe { Msr::new(IA32_PAT).read() } // Reading the PAT has no side effects
+}
+
+/// The memory type `PAT_LAYOUT` selects for a 4KiB page table entry with these flags
+pub fn memory_type(flags: PageTableEntryFlags) -> MemoryType {
+    // For 4KiB entries bit 7 selects the PAT high half rather than a huge page
+    let mut index = 0;
+    if flags.contains(PageTableEntryFlags::WRITE_THROUGH) {
+        index |= 1;
+    }
+    if flags.contains(PageTableEntryFlags::DISABLE_CACHE) {
+        index |= 2;
+    }
+    if flags.contains(PageTableEntryFlags::HUGE_PAGE) {
+        index |= 4;
+    }
+
+    PAT_LAYOUT[index]
+}
+
+fn encode(layout: [MemoryType; 8]) -> u64 {
+    layout
+        .iter()
+        .enumerate()
+        .fold(0, |pat, (i, ty)| pat | (*ty as u64) << (i * 8))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::paging::PageTableEntryFlags;
+
+    use super::{encode, is_supported, memory_type, read, MemoryType, PAT_LAYOUT};
+
+    #[test_case]
+    fn pat_msr_configured() {
+        if !is_supported() {
+            return;
+        }
+
+        assert_eq!(read(), encode(PAT_LAYOUT));
+        assert_eq!(read(), 0x0007_0406_0007_0106);
+    }
+
+    #[test_case]
+    fn write_combining_preset() {
+        let flags = PageTableEntryFlags::write_combining();
+
+        assert!(flags.contains(PageTableEntryFlags::WRITE_THROUGH));
+        assert!(!flags.contains(PageTableEntryFlags::DISABLE_CACHE));
+        assert_eq!(memory_type(flags), MemoryType::WriteCombining);
+    }
+
+    #[test_case]
+    fn default_flags_are_write_back() {
+        assert_eq!(
+            memory_type(PageTableEntryFlags::kernel_rw()),
+            MemoryType::WriteBack
+        );
+        assert_eq!(
+            memory_type(PageTableEntryFlags::mmio()),
+            MemoryType::Uncached
+        );
+    }
+}
@@ -0,0 +1,148 @@
+use core::ops::Add;
+
+/// The cpu supports at most 52 bits of physical address
+const PHYS_ADDR_MASK: u64 = 0x000F_FFFF_FFFF_FFFF;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(transparent)]
+pub struct PhysAddr(u64);
+
+impl PhysAddr {
+    /// Create an address, debug builds panic if any of bits 52 to 63 are set
+    ///
+    /// Release builds clear them instead, like `VirtAddr::new` sign extends rather than panicking
+    pub fn new(addr: u64) -> PhysAddr {
+        debug_assert!(
+            addr & !PHYS_ADDR_MASK == 0,
+            "{:#x} is not a valid physical address",
+            addr
+        );
+        PhysAddr::new_truncate(addr)
+    }
+
+    /// Create an address by clearing bits 52 to 63
+    #[inline]
+    pub const fn new_truncate(addr: u64) -> PhysAddr {
+        PhysAddr(addr & PHYS_ADDR_MASK)
+    }
+
+    /// Align downwards to the nearest frame boundary
+    #[inline]
+    pub fn align_down(&self) -> PhysAddr {
+        self.align_down_to(4096)
+    }
+
+    /// Align downwards to a multiple of `align`, which must be a power of two
+    #[inline]
+    pub fn align_down_to(&self, align: u64) -> PhysAddr {
+        debug_assert!(
+            align.is_power_of_two(),
+            "{:#x} is not a power of two",
+            align
+        );
+        PhysAddr(self.0 & !(align - 1))
+    }
+
+    /// Align upwards to the nearest frame boundary, an aligned address is returned unchanged
+    ///
+    /// Debug builds panic if the aligned address would be outside the physical address space
+    #[inline]
+    pub fn align_up(&self) -> PhysAddr {
+        PhysAddr::new((self.0 + 4095) & !4095)
+    }
+
+    /// Whether the address is a multiple of `align`, which must be a power of two
+    #[inline]
+    pub fn is_aligned(&self, align: u64) -> bool {
+        debug_assert!(
+            align.is_power_of_two(),
+            "{:#x} is not a power of two",
+            align
+        );
+        self.0 & (align - 1) == 0
+    }
+
+    #[inline]
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+impl Add<u64> for PhysAddr {
+    type Output = PhysAddr;
+
+    #[inline]
+    fn add(self, rhs: u64) -> Self::Output {
+        PhysAddr::new(self.0 + rhs)
+    }
+}
+
+impl From<x86_64::PhysAddr> for PhysAddr {
+    #[inline]
+    fn from(addr: x86_64::PhysAddr) -> Self {
+        PhysAddr(addr.as_u64()) // The x86_64 crate applies the same 52 bit limit
+    }
+}
+
+impl From<PhysAddr> for x86_64::PhysAddr {
+    #[inline]
+    fn from(addr: PhysAddr) -> Self {
+        x86_64::PhysAddr::new(addr.as_u64())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PhysAddr;
+
+    #[test_case]
+    fn align_down() {
+        let addr = PhysAddr::new(0x0000_E677_BF54_D244);
+        let aligned = addr.align_down();
+        assert_eq!(aligned.as_u64(), 0x0000_E677_BF54_D000);
+    }
+
+    #[test_case]
+    fn align_up() {
+        let addr = PhysAddr::new(0x0000_E677_BF54_D244);
+        let aligned = addr.align_up();
+        assert_eq!(aligned.as_u64(), 0x0000_E677_BF54_E000);
+
+        let already = PhysAddr::new(0x0000_E677_BF54_D000);
+        assert_eq!(already.align_up(), already);
+    }
+
+    #[test_case]
+    fn is_aligned() {
+        let addr = PhysAddr::new(0x20_0000);
+        assert!(addr.is_aligned(4096));
+        assert!(addr.is_aligned(0x20_0000));
+        assert!(!addr.is_aligned(0x40_0000));
+        assert!(!PhysAddr::new(0x20_0008).is_aligned(16));
+    }
+
+    #[test_case]
+    fn align_down_to_huge_pages() {
+        let addr = PhysAddr::new(0x20_0001);
+        assert_eq!(addr.align_down_to(0x20_0000).as_u64(), 0x20_0000);
+
+        let addr = PhysAddr::new(0x7FFF_FFFF);
+        assert_eq!(addr.align_down_to(0x4000_0000).as_u64(), 0x4000_0000);
+    }
+
+    #[test_case]
+    fn new_truncate_clears_high_bits() {
+        assert_eq!(
+            PhysAddr::new_truncate(0xFFF0_0000_0000_1234).as_u64(),
+            0x1234
+        );
+    }
+
+    #[test_case]
+    fn convert_to_and_from_x86_64() {
+        let addr = PhysAddr::new(0x1234_5000);
+        let other: x86_64::PhysAddr = addr.into();
+        assert_eq!(other.as_u64(), 0x1234_5000);
+        assert_eq!(PhysAddr::from(other), addr);
+    }
+}
@@ -1,22 +1,80 @@
+use alloc::{boxed::Box, string::String, vec, vec::Vec};
+use core::sync::atomic::{AtomicBool, Ordering};
 use lazy_static::lazy_static;
 use spin::Mutex;
+use x86_64::{instructions::interrupts, structures::paging::PhysFrame};
 
-use crate::{pagetable::PageTable, println};
+use crate::{
+    allocator::{FrameAllocator, FRAME_ALLOCATOR},
+    context::{switch_context, Context},
+    cpu,
+    elf::{Elf, ElfError},
+    gdt,
+    memory::{
+        active_pagetable_frame, get_offset, kernel_pagetable_frame, load_active_pagetable,
+        switch_pagetable,
+    },
+    pagetable::{PageMapError, PageTable},
+    paging::{Page, PageTableEntry, PageTableEntryFlags},
+    print, println, serial_print, serial_println,
+    sync::{DebugMutex, IrqMutex, IrqMutexGuard},
+    trap::{enter_user_mode, TrapFrame},
+    virt_addr::VirtAddr,
+};
 
-const NPROC: usize = 2;
+/// The number of process slots created up front
+const NPROC: usize = 4;
+/// The number of open files each process can have
+const NFILE: usize = 8;
+const KERNEL_STACK_SIZE: usize = 4096 * 4;
+
+/// The top of a user process's stack, in a top level slot the kernel doesn't use
+const USER_STACK_TOP: u64 = 0x7000_0000_0000;
+const USER_STACK_SIZE: u64 = 4096 * 4;
+/// The end of the lower half, user mappings must sit below it
+const USER_ADDR_LIMIT: u64 = 0x8000_0000_0000;
+/// The number of top level slots covering the lower half
+const USER_TOP_LEVEL_SLOTS: usize = 256;
+/// Interrupts enabled, plus the reserved bit which is always set
+const USER_RFLAGS: u64 = 0x202;
 
 lazy_static! {
-    static ref PROCESS_LIST: [Mutex<Process>; NPROC] = init_process_list_internal();
+    /// Every process slot, a slot is reused once its process is reaped
+    ///
+    /// The list starts with NPROC slots and grows on the heap when they're all in use. Slots are
+    /// leaked so they never move, e.g. `schedule` keeps pointers to their contexts. The timer
+    /// interrupt locks slots to preempt, so they're IrqMutexes like the list
+    static ref PROCESS_LIST: IrqMutex<Vec<&'static IrqMutex<Process>>> =
+        IrqMutex::new(init_process_list_internal());
 }
 static NEXT_PID: Mutex<u64> = Mutex::new(0);
 
+/// The slot of the process currently running on the CPU, None while the boot thread runs
+static CURRENT: IrqMutex<Option<usize>> = IrqMutex::new(None);
+/// Where the boot thread is saved while a process runs
+static BOOT_CONTEXT: DebugMutex<Context> = DebugMutex::new(Context::new());
+/// The process which adopts orphans, it may never exit
+static INIT_PID: Mutex<Option<u64>> = Mutex::new(None);
+/// Whether the timer interrupt switches to the next ready process
+static PREEMPT: AtomicBool = AtomicBool::new(false);
+
 #[allow(dead_code)]
 #[derive(Debug)]
 struct Process {
     state: State,
     exit_code: i32,
     process_id: u64,
+    parent_pid: Option<u64>,
     pagetable: PageTable,
+    /// The frame holding `pagetable` to load into cr3, None for kernel threads which run on
+    /// the kernel's own table
+    address_space: Option<PhysFrame>,
+    /// The user registers, saved on every syscall so `fork` copies where the process really is
+    trap_frame: TrapFrame,
+    context: Context,
+    kernel_stack: Vec<u8>,
+    name: String,
+    fds: [Option<FdKind>; NFILE],
 }
 
 impl Process {
@@ -25,14 +83,81 @@ impl Process {
             state: State::Available,
             exit_code: 0,
             process_id: 0,
+            parent_pid: None,
             pagetable: PageTable::new(),
+            address_space: None,
+            trap_frame: TrapFrame::default(),
+            context: Context::new(),
+            kernel_stack: Vec::new(),
+            name: String::new(),
+            fds: [None; NFILE],
         }
     }
+
+    #[allow(dead_code)]
+    fn state(&self) -> State {
+        self.state
+    }
+
+    /// The registers saved the last time the process was interrupted
+    #[allow(dead_code)]
+    fn trap_frame(&self) -> &TrapFrame {
+        &self.trap_frame
+    }
+
+    fn trap_frame_mut(&mut self) -> &mut TrapFrame {
+        &mut self.trap_frame
+    }
+
+    /// Open `kind` at the lowest free file descriptor, returning None if the table is full
+    #[allow(dead_code)]
+    fn alloc_fd(&mut self, kind: FdKind) -> Option<usize> {
+        let fd = self.fds.iter().position(|f| f.is_none())?;
+        self.fds[fd] = Some(kind);
+        Some(fd)
+    }
+
+    fn get_fd(&self, fd: usize) -> Option<FdKind> {
+        *self.fds.get(fd)?
+    }
+
+    /// Close `fd`, returning what it referred to
+    #[allow(dead_code)]
+    fn close_fd(&mut self, fd: usize) -> Option<FdKind> {
+        self.fds.get_mut(fd)?.take()
+    }
+
+    /// Move the process into a new state
+    ///
+    /// Only transitions allowed by the process lifecycle are accepted, anything else
+    /// is a logic bug in the caller and is rejected without modifying the process
+    fn set_state(&mut self, new: State) -> Result<(), InvalidTransition> {
+        let valid = matches!(
+            (self.state, new),
+            (State::Available, State::Ready)
+                | (State::Ready, State::Running)
+                | (State::Running, State::Ready)
+                | (State::Running, State::Blocked)
+                | (State::Running, State::Zombie)
+                | (State::Blocked, State::Ready)
+                | (State::Zombie, State::Available)
+        );
+
+        if !valid {
+            return Err(InvalidTransition {
+                from: self.state,
+                to: new,
+            });
+        }
+
+        self.state = new;
+        Ok(())
+    }
 }
 
 #[allow(dead_code)]
-#[derive(Debug)]
-enum State {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
     Available,
     Ready,
     Running,
@@ -40,28 +165,913 @@ enum State {
     Zombie,
 }
 
+/// What a file descriptor refers to
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FdKind {
+    Serial,
+    Vga,
+}
+
+impl FdKind {
+    pub fn write(self, s: &str) {
+        match self {
+            FdKind::Serial => {
+                serial_print!("{}", s);
+            }
+            FdKind::Vga => print!("{}", s),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ProcessError {
+    NotFound,
+    /// The init process can't exit
+    IsInit,
+    /// The process isn't in a state where the operation is allowed
+    InvalidState,
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+struct InvalidTransition {
+    from: State,
+    to: State,
+}
+
 pub fn init_process_list() {
     println!("{:p}", &PROCESS_LIST);
 }
 
-fn init_process_list_internal() -> [Mutex<Process>; NPROC] {
-    [Mutex::new(Process::new()), Mutex::new(Process::new())]
+fn init_process_list_internal() -> Vec<&'static IrqMutex<Process>> {
+    (0..NPROC).map(|_| new_slot()).collect()
+}
+
+fn new_slot() -> &'static IrqMutex<Process> {
+    Box::leak(Box::new(IrqMutex::new(Process::new())))
+}
+
+/// The process slot at `index`
+fn process_slot(index: usize) -> &'static IrqMutex<Process> {
+    PROCESS_LIST.lock()[index]
+}
+
+/// A snapshot of every process slot, so they can be locked without holding the list
+fn process_slots() -> Vec<&'static IrqMutex<Process>> {
+    PROCESS_LIST.lock().clone()
+}
+
+/// Lock the first available process slot, adding a new slot if they're all in use
+fn claim_slot() -> IrqMutexGuard<'static, Process> {
+    for proc in process_slots() {
+        let p = proc.lock();
+        if p.state == State::Available {
+            return p;
+        }
+    }
+
+    let proc = new_slot();
+    let p = proc.lock();
+    PROCESS_LIST.lock().push(proc);
+    p
+}
+
+/// The PID of the running process, None while the boot thread runs
+pub fn current_pid() -> Option<u64> {
+    let current = *CURRENT.lock();
+    current.map(|slot| process_slot(slot).lock().process_id)
+}
+
+/// Record the registers the running process entered a syscall with
+///
+/// The boot thread has no process to save them in, so its frames are dropped
+pub fn save_trap_frame(frame: &TrapFrame) {
+    let current = *CURRENT.lock();
+    if let Some(slot) = current {
+        *process_slot(slot).lock().trap_frame_mut() = *frame;
+    }
+}
+
+/// Look up `fd` in the running process's file table
+///
+/// The boot thread has no file table, its stdout is the screen and its stderr the serial port
+pub fn current_fd(fd: usize) -> Option<FdKind> {
+    let current = *CURRENT.lock();
+    match current {
+        Some(slot) => process_slot(slot).lock().get_fd(fd),
+        None => match fd {
+            1 => Some(FdKind::Vga),
+            2 => Some(FdKind::Serial),
+            _ => None,
+        },
+    }
+}
+
+/// Make `pid` the init process, which adopts the children of any process that exits
+pub fn set_init(pid: u64) -> Result<(), ProcessError> {
+    match find_slot(pid) {
+        Some(_) => {
+            *INIT_PID.lock() = Some(pid);
+            Ok(())
+        }
+        None => Err(ProcessError::NotFound),
+    }
+}
+
+/// Exit the running process `pid`, recording its exit code and handing its children to init
+pub fn exit_process(pid: u64, code: i32) -> Result<(), ProcessError> {
+    let init = *INIT_PID.lock();
+    if init == Some(pid) {
+        return Err(ProcessError::IsInit);
+    }
+
+    let slot = match find_slot(pid) {
+        Some(s) => s,
+        None => return Err(ProcessError::NotFound),
+    };
+    {
+        let mut p = process_slot(slot).lock();
+        if p.set_state(State::Zombie).is_err() {
+            return Err(ProcessError::InvalidState);
+        }
+        p.exit_code = code;
+    }
+
+    for proc in process_slots() {
+        let mut p = proc.lock();
+        if p.state != State::Available && p.parent_pid == Some(pid) {
+            p.parent_pid = init;
+        }
+    }
+
+    Ok(())
 }
 
-pub fn allocate_process() {
-    for proc in PROCESS_LIST.iter() {
+/// Free the address space of every zombie process and return its slot to the pool,
+/// returning the number of processes reaped
+///
+/// The running process is left alone, it's still on its kernel stack until it switches away
+pub fn reap_zombies() -> usize {
+    let alloc = match FRAME_ALLOCATOR.wait() {
+        Some(a) => a,
+        None => panic!("boot info allocator not initialized"),
+    };
+    let current = *CURRENT.lock();
+
+    let mut reaped = 0;
+    for (slot, proc) in process_slots().into_iter().enumerate() {
         let mut p = proc.lock();
-        match p.state {
-            State::Available => {
-                let mut next_pid = NEXT_PID.lock();
+        if p.state != State::Zombie || current == Some(slot) {
+            continue;
+        }
+
+        p.pagetable.free_user_mappings(&mut *alloc.lock());
+        p.pagetable = PageTable::new();
+        p.address_space = None;
+        p.kernel_stack = Vec::new();
+        p.fds = [None; NFILE];
+        p.set_state(State::Available).unwrap(); // Zombie -> Available is always valid
+        reaped += 1;
+    }
+
+    reaped
+}
+
+/// Find the slot of the live process with `pid`
+fn find_slot(pid: u64) -> Option<usize> {
+    process_slots().into_iter().position(|proc| {
+        let p = proc.lock();
+        p.state != State::Available && p.process_id == pid
+    })
+}
+
+/// Iterate the PIDs of all processes currently in `state`
+///
+/// Each process is only locked while it is being checked, so the snapshot isn't atomic
+pub fn iter_in_state(state: State) -> impl Iterator<Item = u64> {
+    process_slots().into_iter().filter_map(move |proc| {
+        let p = proc.lock();
+        if p.state == state {
+            Some(p.process_id)
+        } else {
+            None
+        }
+    })
+}
+
+/// Create a kernel thread which starts running `entry` the first time it's scheduled
+///
+/// Returns the new thread's PID
+pub fn spawn_kernel(entry: fn() -> !, name: &str) -> u64 {
+    let parent = current_pid();
+    let mut p = claim_slot();
+
+    let mut next_pid = NEXT_PID.lock();
+    let pid = *next_pid;
+    *next_pid += 1;
+
+    p.set_state(State::Ready).unwrap(); // Available -> Ready is always valid
+    p.process_id = pid;
+    p.parent_pid = parent;
+    p.name = String::from(name);
+    p.kernel_stack = vec![0; KERNEL_STACK_SIZE];
+    p.context = Context::kernel_thread(&mut p.kernel_stack, entry);
+
+    pid
+}
+
+#[derive(Debug)]
+pub enum ExecError {
+    Elf(ElfError),
+    FrameAllocation,
+    /// The range is outside the lower half or overlaps the kernel's mappings, or the entry point
+    /// isn't in an executable segment
+    BadAddress(u64),
+    Map(PageMapError),
+}
+
+/// Load `elf_bytes` into a new process with its own address space and make it the init process
+///
+/// Returns the PID of the new process, which is ready to run
+pub fn exec_init(elf_bytes: &[u8]) -> Result<u64, ExecError> {
+    let pid = exec(elf_bytes, "init")?;
+    *INIT_PID.lock() = Some(pid);
+
+    Ok(pid)
+}
+
+/// Load `elf_bytes` into a new process with its own address space, as a child of the running process
+///
+/// The address space starts as a copy of the kernel's top level table, so it shares every kernel
+/// mapping and the program & its stack must sit in top level slots the kernel doesn't use.
+/// The process drops to ring 3 at the ELF's entry point the first time it's scheduled.
+/// Returns the PID of the new process, which is ready to run
+pub fn exec(elf_bytes: &[u8], name: &str) -> Result<u64, ExecError> {
+    let elf = match Elf::parse(elf_bytes) {
+        Ok(e) => e,
+        Err(err) => return Err(ExecError::Elf(err)),
+    };
+    let alloc = match FRAME_ALLOCATOR.wait() {
+        Some(a) => a,
+        None => return Err(ExecError::FrameAllocation),
+    };
+
+    let parent = current_pid();
+    let mut p = claim_slot();
+
+    let kernel_table = unsafe { load_active_pagetable() }; // Only used to copy the top level entries
+    p.pagetable = kernel_table.clone();
+    // Anything user accessible belongs to another address space, the process must own its user slots
+    for index in 0..USER_TOP_LEVEL_SLOTS {
+        if p.pagetable[index]
+            .flags()
+            .contains(PageTableEntryFlags::USER_ACCESSIBLE)
+        {
+            p.pagetable[index] = PageTableEntry::new_zero();
+        }
+    }
+
+    if let Err(err) = load_image(&mut p.pagetable, &elf) {
+        p.pagetable.free_user_mappings(&mut *alloc.lock());
+        p.pagetable = PageTable::new();
+        return Err(err);
+    }
+
+    let mut next_pid = NEXT_PID.lock();
+    let pid = *next_pid;
+    *next_pid += 1;
+
+    p.set_state(State::Ready).unwrap(); // Available -> Ready is always valid
+    p.process_id = pid;
+    p.parent_pid = parent;
+    p.name = String::from(name);
+    p.address_space = table_frame(&p.pagetable);
+    p.trap_frame = TrapFrame {
+        rip: elf.entry(),
+        cs: u64::from(gdt::user_code_selector().0),
+        rflags: USER_RFLAGS,
+        rsp: USER_STACK_TOP,
+        ss: u64::from(gdt::user_data_selector().0),
+        ..TrapFrame::default()
+    };
+    p.kernel_stack = vec![0; KERNEL_STACK_SIZE];
+    p.context = Context::kernel_thread(&mut p.kernel_stack, user_process_entry);
+
+    Ok(pid)
+}
+
+/// Map the load segments of `elf` and a user stack into `table`
+fn load_image(table: &mut PageTable, elf: &Elf) -> Result<(), ExecError> {
+    // Check everything before mapping anything, so the kernel's tables are never touched
+    for segment in elf.load_segments() {
+        check_user_range(table, segment.vaddr, segment.mem_size)?;
+    }
+    check_user_range(table, USER_STACK_TOP - USER_STACK_SIZE, USER_STACK_SIZE)?;
+    check_entry(elf)?;
+
+    for segment in elf.load_segments() {
+        let mut flags = PageTableEntryFlags::PRESENT | PageTableEntryFlags::USER_ACCESSIBLE;
+        if segment.writable() {
+            flags |= PageTableEntryFlags::WRITABLE;
+        }
+        if !segment.executable() && cpu::nx_enabled() {
+            flags |= PageTableEntryFlags::NO_EXECUTE;
+        }
+        let data = elf.segment_data(&segment);
+        map_user_range(table, segment.vaddr, segment.mem_size, flags, data)?;
+    }
+
+    let mut stack_flags = PageTableEntryFlags::user_rw();
+    if cpu::nx_enabled() {
+        stack_flags |= PageTableEntryFlags::NO_EXECUTE;
+    }
+    let stack_bottom = USER_STACK_TOP - USER_STACK_SIZE;
+    map_user_range(table, stack_bottom, USER_STACK_SIZE, stack_flags, &[])
+}
+
+/// Where a process loaded by `exec` starts running, `schedule` has already loaded its address
+/// space. Drops to ring 3 with the instruction & stack pointers from its trap frame
+fn user_process_entry() -> ! {
+    let slot = match *CURRENT.lock() {
+        Some(s) => s,
+        None => panic!("user process entry run by the boot thread"),
+    };
+    let (frame, stack_top) = {
+        let p = process_slot(slot).lock();
+        (p.trap_frame, kernel_stack_top(&p))
+    };
+
+    // Traps from ring 3 land at the top of this stack, the frames below here are never returned to
+    unsafe { gdt::set_kernel_interrupt_stack(stack_top) };
+    enter_user_mode(VirtAddr::new(frame.rip), VirtAddr::new(frame.rsp))
+}
+
+/// The address just past the end of `p`'s kernel stack
+fn kernel_stack_top(p: &Process) -> x86_64::VirtAddr {
+    let range = p.kernel_stack.as_ptr_range();
+    // Keep the top 16 byte aligned, as the cpu pushes interrupt frames there
+    x86_64::VirtAddr::from_ptr(range.end).align_down(16u64)
+}
+
+/// Check `start..start + size` is in the lower half and only covers empty top level slots
+fn check_user_range(table: &PageTable, start: u64, size: u64) -> Result<(), ExecError> {
+    let end = match start.checked_add(size) {
+        Some(end) if end <= USER_ADDR_LIMIT => end,
+        _ => return Err(ExecError::BadAddress(start)),
+    };
+    if size == 0 {
+        return Ok(());
+    }
+
+    let first = usize::from(VirtAddr::new(start).page_table_index(3));
+    let last = usize::from(VirtAddr::new(end - 1).page_table_index(3));
+    for index in first..=last {
+        if table[index].flags().contains(PageTableEntryFlags::PRESENT) {
+            return Err(ExecError::BadAddress(start));
+        }
+    }
+
+    Ok(())
+}
+
+/// Check the entry point is in the lower half, which also makes it canonical, and inside an
+/// executable segment, so the first instruction ring 3 runs is one the program loaded
+fn check_entry(elf: &Elf) -> Result<(), ExecError> {
+    let entry = elf.entry();
+    let in_code = elf.load_segments().any(|segment| {
+        segment.executable() && entry >= segment.vaddr && entry - segment.vaddr < segment.mem_size
+    });
+
+    if entry >= USER_ADDR_LIMIT || !in_code {
+        return Err(ExecError::BadAddress(entry));
+    }
+    Ok(())
+}
+
+/// Back `start..start + size` with zeroed frames and copy `data` to the start of the range
+///
+/// Pages which are already mapped, e.g. shared by two segments, are written through the existing frame
+fn map_user_range(
+    table: &mut PageTable,
+    start: u64,
+    size: u64,
+    flags: PageTableEntryFlags,
+    data: &[u8],
+) -> Result<(), ExecError> {
+    let alloc = match FRAME_ALLOCATOR.wait() {
+        Some(a) => a,
+        None => return Err(ExecError::FrameAllocation),
+    };
+
+    let end = start + size;
+    let data_end = start + data.len() as u64;
+    let mut page_start = start & !0xFFF;
+    while page_start < end {
+        let page = Page::containing_address(VirtAddr::new(page_start));
+        let frame_start = match table.translate_addr(page.as_virt_addr()) {
+            Some(addr) => addr,
+            None => {
+                let mut alloc = alloc.lock();
+                let frame = match alloc.allocate() {
+                    Some(f) => f,
+                    None => return Err(ExecError::FrameAllocation),
+                };
+                let frame_ptr: *mut u8 =
+                    (get_offset() + frame.start_address().as_u64()).as_mut_ptr();
+                unsafe { frame_ptr.write_bytes(0, 4096) }; // This is safe as the frame was just allocated
+
+                let entry = PageTableEntry::new(frame, flags);
+                let result = unsafe { table.map_page(page, entry, &mut *alloc) }; // Nothing else references the frame
+                if let Err(err) = result {
+                    return Err(ExecError::Map(err));
+                }
+                frame.start_address().into()
+            }
+        };
 
-                p.state = State::Ready;
-                p.process_id = *next_pid;
-                p.pagetable = PageTable::new();
+        let copy_start = core::cmp::max(page_start, start);
+        let copy_end = core::cmp::min(page_start + 4096, data_end);
+        if copy_start < copy_end {
+            let src = &data[(copy_start - start) as usize..(copy_end - start) as usize];
+            let dst: *mut u8 =
+                (get_offset() + frame_start.as_u64() + (copy_start - page_start)).as_mut_ptr();
+            unsafe { dst.copy_from_nonoverlapping(src.as_ptr(), src.len()) }; // The copy never crosses the end of the frame
+        }
+
+        page_start += 4096;
+    }
+
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum ForkError {
+    NotFound,
+    /// The parent has already exited
+    Exited,
+    FrameAllocation,
+    Map(PageMapError),
+}
+
+/// Create a child of `parent_pid` which shares its memory copy on write
+///
+/// The child resumes from the trap frame the parent last made a syscall with, with 0 in rax, while the parent's rax is set to
+/// the child's PID, as fork returns. Returns the child's PID
+pub fn fork(parent_pid: u64) -> Result<u64, ForkError> {
+    let parent_slot = match find_slot(parent_pid) {
+        Some(s) => s,
+        None => return Err(ForkError::NotFound),
+    };
+    let alloc = match FRAME_ALLOCATOR.wait() {
+        Some(a) => a,
+        None => return Err(ForkError::FrameAllocation),
+    };
+
+    let mut child = claim_slot();
+    let mut parent = process_slot(parent_slot).lock();
+    if parent.state == State::Zombie {
+        return Err(ForkError::Exited);
+    }
+
+    child.pagetable = match parent.pagetable.clone_cow(&mut *alloc.lock()) {
+        Ok(t) => t,
+        Err(err) => return Err(ForkError::Map(err)),
+    };
+
+    let mut next_pid = NEXT_PID.lock();
+    let pid = *next_pid;
+    *next_pid += 1;
+
+    child.set_state(State::Ready).unwrap(); // Available -> Ready is always valid
+    child.process_id = pid;
+    child.parent_pid = Some(parent_pid);
+    child.name = parent.name.clone();
+    child.fds = parent.fds;
+    child.address_space = table_frame(&child.pagetable);
+    child.trap_frame = parent.trap_frame;
+    child.trap_frame.rax = 0;
+    parent.trap_frame.rax = pid;
+
+    Ok(pid)
+}
+
+/// The frame holding `pid`'s top level page table, to load into cr3
+pub fn pagetable_frame(pid: u64) -> Option<PhysFrame> {
+    let slot = find_slot(pid)?;
+    let p = process_slot(slot).lock();
+    table_frame(&p.pagetable)
+}
+
+/// The frame holding `table`, which lives in a process slot
+fn table_frame(table: &PageTable) -> Option<PhysFrame> {
+    let addr = VirtAddr::from(table as *const PageTable);
+
+    let kernel_table = unsafe { load_active_pagetable() }; // Every address space maps the process list the same way
+    let phys = kernel_table.translate_addr(addr)?;
+    PhysFrame::from_start_address(phys.into()).ok()
+}
+
+/// Print the PID, state and name of every live process over serial
+pub fn print_processes() {
+    for proc in process_slots() {
+        let p = proc.lock();
+        if p.state != State::Available {
+            serial_println!("{:>4} {:?} {}", p.process_id, p.state, p.name);
+        }
+    }
+}
+
+/// The registers `pid` will resume with
+pub fn trap_frame_of(pid: u64) -> Option<TrapFrame> {
+    let slot = find_slot(pid)?;
+    let p = process_slot(slot).lock();
+    Some(*p.trap_frame())
+}
+
+/// The exit code `pid` recorded, None unless it's a zombie waiting to be reaped
+pub fn exit_code_of(pid: u64) -> Option<i32> {
+    let slot = find_slot(pid)?;
+    let p = process_slot(slot).lock();
+    match p.state {
+        State::Zombie => Some(p.exit_code),
+        _ => None,
+    }
+}
+
+/// Switch to the next ready process, round robin
+///
+/// The boot thread takes a turn after the last process slot, and keeps running
+/// if no processes are ready
+pub fn schedule() {
+    interrupts::without_interrupts(|| {
+        let mut current = CURRENT.lock();
+        let next = match *current {
+            Some(slot) => next_ready(slot + 1),
+            None => next_ready(0),
+        };
+        if next.is_none() && current.is_none() {
+            return;
+        }
+
+        let old: *mut Context = match *current {
+            Some(slot) => {
+                let mut p = process_slot(slot).lock();
+                if p.state == State::Running {
+                    p.set_state(State::Ready).unwrap(); // Running -> Ready is always valid
+                }
+                &mut p.context
+            }
+            None => &mut *BOOT_CONTEXT.lock(),
+        };
+        let (new, table): (*const Context, _) = match next {
+            Some(slot) => {
+                let mut p = process_slot(slot).lock();
+                p.set_state(State::Running).unwrap(); // next_ready only returns ready processes
+                if p.address_space.is_some() {
+                    // The stack is on the heap, which every address space maps
+                    unsafe { gdt::set_kernel_interrupt_stack(kernel_stack_top(&p)) };
+                }
+                (&p.context, p.address_space.or_else(kernel_pagetable_frame))
+            }
+            None => (&*BOOT_CONTEXT.lock(), kernel_pagetable_frame()),
+        };
+
+        *current = next;
+        drop(current);
+
+        match table {
+            // Every address space maps the kernel, including the stack we're running on
+            Some(frame) if frame != active_pagetable_frame() => unsafe { switch_pagetable(frame) },
+            _ => {}
+        }
+
+        // The contexts live in statics so they stay valid after the locks are dropped,
+        // and interrupts are disabled so nothing else touches them during the switch
+        unsafe { switch_context(old, new) };
+    })
+}
+
+/// Choose whether the timer interrupt preempts the running thread, it's off at boot
+///
+/// Only turn this on once every ready process has a context to switch to, processes
+/// created by `allocate_process` or `fork` don't have one yet
+pub fn set_preemption(enabled: bool) {
+    PREEMPT.store(enabled, Ordering::SeqCst);
+}
+
+/// Switch to the next ready process if preemption is enabled, called on every timer tick
+pub fn preempt() {
+    if PREEMPT.load(Ordering::SeqCst) {
+        schedule();
+    }
+}
+
+/// Find the first ready process slot at or after `start`
+fn next_ready(start: usize) -> Option<usize> {
+    let count = PROCESS_LIST.lock().len();
+    (start..count).find(|&index| process_slot(index).lock().state == State::Ready)
+}
+
+/// Claim a process slot with an empty page table, returning the new process's PID
+pub fn allocate_process() -> u64 {
+    let parent = current_pid();
+    let mut p = claim_slot();
+    let mut next_pid = NEXT_PID.lock();
+    let pid = *next_pid;
+    *next_pid += 1;
+
+    p.set_state(State::Ready).unwrap(); // Available -> Ready is always valid
+    p.process_id = pid;
+    p.parent_pid = parent;
+    p.pagetable = PageTable::new();
+
+    pid
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        allocator::{FrameAllocator, FRAME_ALLOCATOR},
+        paging::{Page, PageTableEntry, PageTableEntryFlags},
+        trap::TrapFrame,
+        virt_addr::VirtAddr,
+    };
+
+    use alloc::vec::Vec;
+
+    use super::{
+        allocate_process, exit_process, find_slot, iter_in_state, process_slot, process_slots,
+        reap_zombies, set_init, FdKind, Process, ProcessError, State, INIT_PID, NFILE, NPROC,
+    };
+
+    #[test_case]
+    fn alloc_fd_uses_lowest_free_slot() {
+        let mut p = Process::new();
+
+        assert_eq!(p.alloc_fd(FdKind::Serial), Some(0));
+        assert_eq!(p.alloc_fd(FdKind::Vga), Some(1));
+        assert_eq!(p.alloc_fd(FdKind::Serial), Some(2));
+
+        assert_eq!(p.close_fd(1), Some(FdKind::Vga));
+        assert_eq!(p.get_fd(1), None);
+        assert_eq!(p.alloc_fd(FdKind::Serial), Some(1));
+        assert_eq!(p.get_fd(1), Some(FdKind::Serial));
+    }
+
+    #[test_case]
+    fn fd_table_full() {
+        let mut p = Process::new();
+        for _ in 0..NFILE {
+            assert!(p.alloc_fd(FdKind::Serial).is_some());
+        }
+
+        assert_eq!(p.alloc_fd(FdKind::Vga), None);
+        assert_eq!(p.get_fd(NFILE), None);
+    }
+
+    #[test_case]
+    fn valid_lifecycle_transitions() {
+        let mut p = Process::new();
+
+        let transitions = [
+            State::Ready,
+            State::Running,
+            State::Blocked,
+            State::Ready,
+            State::Running,
+            State::Zombie,
+            State::Available,
+        ];
+        for state in transitions {
+            match p.set_state(state) {
+                Ok(_) => {}
+                Err(err) => panic!("valid transition was rejected: {:?}", err),
+            }
+        }
+    }
+
+    #[test_case]
+    fn state_reflects_transitions() {
+        let mut p = Process::new();
+        assert_eq!(p.state(), State::Available);
+
+        p.set_state(State::Ready).unwrap();
+        assert_eq!(p.state(), State::Ready);
+        assert_ne!(p.state(), State::Running);
+
+        p.set_state(State::Running).unwrap();
+        assert_eq!(p.state(), State::Running);
+    }
+
+    #[test_case]
+    fn zombie_cannot_run() {
+        let mut p = Process::new();
+        p.state = State::Zombie;
+
+        match p.set_state(State::Running) {
+            Ok(_) => panic!("zombie process was allowed to run"),
+            Err(_) => assert!(matches!(p.state, State::Zombie)),
+        }
+    }
+
+    #[test_case]
+    fn available_cannot_run() {
+        let mut p = Process::new();
+
+        match p.set_state(State::Running) {
+            Ok(_) => panic!("unallocated process was allowed to run"),
+            Err(_) => assert!(matches!(p.state, State::Available)),
+        }
+    }
+
+    #[test_case]
+    fn blocked_cannot_exit_directly() {
+        let mut p = Process::new();
+        p.state = State::Blocked;
+
+        match p.set_state(State::Zombie) {
+            Ok(_) => panic!("blocked process was allowed to become a zombie"),
+            Err(_) => assert!(matches!(p.state, State::Blocked)),
+        }
+    }
+
+    #[test_case]
+    fn trap_frame_syscall_return() {
+        let mut p = Process::new();
+        *p.trap_frame_mut() = TrapFrame {
+            rax: 60,
+            rdi: 42,
+            ..Default::default()
+        };
+
+        assert_eq!(p.trap_frame().syscall_number(), 60);
+        assert_eq!(p.trap_frame().syscall_args()[0], 42);
+
+        p.trap_frame_mut().set_return_value(0);
+        assert_eq!(p.trap_frame().return_value(), 0);
+    }
+
+    #[test_case]
+    fn iterate_processes_in_state() {
+        {
+            let mut first = process_slot(0).lock();
+            first.state = State::Ready;
+            first.process_id = 100;
+
+            let mut second = process_slot(1).lock();
+            second.state = State::Blocked;
+            second.process_id = 101;
+        }
+
+        let mut ready = iter_in_state(State::Ready);
+        assert_eq!(ready.next(), Some(100));
+        assert_eq!(ready.next(), None);
+
+        let mut blocked = iter_in_state(State::Blocked);
+        assert_eq!(blocked.next(), Some(101));
+        assert_eq!(blocked.next(), None);
+
+        assert_eq!(iter_in_state(State::Running).next(), None);
+
+        for proc in process_slots() {
+            proc.lock().state = State::Available;
+        }
+    }
+
+    #[test_case]
+    fn orphans_reparented_to_init() {
+        {
+            let mut init = process_slot(0).lock();
+            init.state = State::Ready;
+            init.process_id = 200;
+
+            let mut parent = process_slot(1).lock();
+            parent.state = State::Running;
+            parent.process_id = 201;
 
-                *next_pid += 1;
+            let mut child = process_slot(2).lock();
+            child.state = State::Ready;
+            child.process_id = 202;
+            child.parent_pid = Some(201);
+        }
+
+        assert_eq!(set_init(200), Ok(()));
+        assert_eq!(exit_process(201, 7), Ok(()));
+
+        {
+            let parent = process_slot(1).lock();
+            assert_eq!(parent.state, State::Zombie);
+            assert_eq!(parent.exit_code, 7);
+        }
+        assert_eq!(process_slot(2).lock().parent_pid, Some(200));
+
+        assert_eq!(exit_process(200, 0), Err(ProcessError::IsInit));
+        assert_eq!(set_init(201234), Err(ProcessError::NotFound));
+
+        *INIT_PID.lock() = None;
+        for proc in process_slots() {
+            let mut p = proc.lock();
+            p.state = State::Available;
+            p.parent_pid = None;
+        }
+    }
+
+    #[test_case]
+    fn process_list_grows_past_nproc() {
+        for _ in 0..NPROC + 2 {
+            allocate_process();
+        }
+        assert!(process_slots().len() >= NPROC + 2);
+
+        let ready: Vec<_> = process_slots()
+            .into_iter()
+            .filter(|proc| proc.lock().state == State::Ready)
+            .collect();
+        assert_eq!(ready.len(), NPROC + 2);
+        for (i, proc) in ready.iter().enumerate() {
+            let p = proc.lock();
+            assert_eq!(p.pagetable.present_entries().count(), 0);
+            for other in &ready[i + 1..] {
+                let other = other.lock();
+                assert_ne!(p.process_id, other.process_id);
+                assert!(!core::ptr::eq(&p.pagetable, &other.pagetable));
+            }
+        }
+
+        for proc in process_slots() {
+            proc.lock().state = State::Available;
+        }
+        assert_eq!(process_slot(0).lock().state, State::Available);
+    }
+
+    #[test_case]
+    fn allocate_process_returns_pid() {
+        let first = allocate_process();
+        let second = allocate_process();
+        assert_eq!(second, first + 1);
+
+        // Only one slot is claimed per call
+        assert_eq!(iter_in_state(State::Ready).count(), 2);
+        assert_ne!(find_slot(first), find_slot(second));
+
+        // The list grows rather than running out of slots
+        let third = allocate_process();
+        assert_eq!(third, second + 1);
+
+        for proc in process_slots() {
+            proc.lock().state = State::Available;
+        }
+    }
+
+    #[test_case]
+    fn reaped_slot_is_reusable() {
+        let pid = allocate_process();
+        let slot = match find_slot(pid) {
+            Some(s) => s,
+            None => panic!("allocated process has no slot"),
+        };
+
+        {
+            let alloc = match FRAME_ALLOCATOR.wait() {
+                Some(a) => a,
+                None => panic!("boot info allocator not initialized"),
+            };
+            let mut alloc = alloc.lock();
+            let frame = match alloc.allocate() {
+                Some(f) => f,
+                None => panic!("could not allocate frame"),
+            };
+
+            let mut p = process_slot(slot).lock();
+            p.state = State::Running;
+            let page = Page::containing_address(VirtAddr::new(0x5555_3000_0000));
+            let entry = PageTableEntry::new(frame, PageTableEntryFlags::user_rw());
+            if let Err(err) = unsafe { p.pagetable.map_page(page, entry, &mut *alloc) } {
+                panic!("error mapping page: {:?}", err);
             }
-            _ => continue,
+        }
+
+        assert_eq!(exit_process(pid, 42), Ok(()));
+        {
+            let p = process_slot(slot).lock();
+            assert_eq!(p.state, State::Zombie);
+            assert_eq!(p.exit_code, 42);
+        }
+
+        assert_eq!(reap_zombies(), 1);
+        {
+            let p = process_slot(slot).lock();
+            assert_eq!(p.state, State::Available);
+            assert_eq!(p.pagetable.present_entries().count(), 0);
+        }
+        assert_eq!(find_slot(pid), None);
+
+        let reused = allocate_process();
+        assert_eq!(find_slot(reused), Some(slot));
+
+        for proc in process_slots() {
+            proc.lock().state = State::Available;
         }
     }
 }
@@ -0,0 +1,139 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::serial_println;
+
+/// The number of equal sized buckets the profiled range is split into
+pub const BUCKETS: usize = 16;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static RANGE_START: AtomicU64 = AtomicU64::new(0);
+static RANGE_END: AtomicU64 = AtomicU64::new(0);
+
+/// Samples in each bucket of the profiled range
+static SAMPLES: [AtomicU64; BUCKETS] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+/// Samples which landed outside the profiled range
+static OUTSIDE: AtomicU64 = AtomicU64::new(0);
+
+/// Start sampling where the cpu is on every timer tick, counting samples in `start..end`
+///
+/// Any previous samples are cleared
+pub fn start(start: u64, end: u64) {
+    ENABLED.store(false, Ordering::SeqCst);
+    for bucket in SAMPLES.iter() {
+        bucket.store(0, Ordering::Relaxed);
+    }
+    OUTSIDE.store(0, Ordering::Relaxed);
+    RANGE_START.store(start, Ordering::Relaxed);
+    RANGE_END.store(end, Ordering::Relaxed);
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+pub fn stop() {
+    ENABLED.store(false, Ordering::SeqCst);
+}
+
+/// Record that the cpu was at `rip`, this is called from the timer interrupt
+pub fn sample(rip: u64) {
+    if !ENABLED.load(Ordering::Acquire) {
+        return;
+    }
+
+    let start = RANGE_START.load(Ordering::Relaxed);
+    let end = RANGE_END.load(Ordering::Relaxed);
+    match bucket(start, end, rip) {
+        Some(b) => SAMPLES[b].fetch_add(1, Ordering::Relaxed),
+        None => OUTSIDE.fetch_add(1, Ordering::Relaxed),
+    };
+}
+
+/// Which bucket of `start..end` holds `rip`
+fn bucket(start: u64, end: u64, rip: u64) -> Option<usize> {
+    if rip < start || rip >= end {
+        return None;
+    }
+
+    let bucket_size = ((end - start) / BUCKETS as u64).max(1);
+    Some((((rip - start) / bucket_size) as usize).min(BUCKETS - 1))
+}
+
+/// The samples in each bucket, followed by the samples outside the profiled range
+pub fn samples() -> ([u64; BUCKETS], u64) {
+    let mut buckets = [0; BUCKETS];
+    for (count, bucket) in buckets.iter_mut().zip(SAMPLES.iter()) {
+        *count = bucket.load(Ordering::Relaxed);
+    }
+
+    (buckets, OUTSIDE.load(Ordering::Relaxed))
+}
+
+/// Print the address range and sample count of every non empty bucket over serial
+pub fn report() {
+    let start = RANGE_START.load(Ordering::Relaxed);
+    let end = RANGE_END.load(Ordering::Relaxed);
+    let bucket_size = ((end - start) / BUCKETS as u64).max(1);
+    let (buckets, outside) = samples();
+
+    for (i, &count) in buckets.iter().enumerate().filter(|(_, &c)| c > 0) {
+        let bucket_start = start + i as u64 * bucket_size;
+        serial_println!(
+            "{:#x}..{:#x}: {}",
+            bucket_start,
+            bucket_start + bucket_size,
+            count
+        );
+    }
+    serial_println!("outside: {}", outside);
+}
+
+#[cfg(test)]
+mod tests {
+    use x86_64::instructions::hlt;
+
+    use crate::cpu::current_rip;
+
+    use super::{bucket, samples, start, stop, BUCKETS};
+
+    #[test_case]
+    fn bucket_boundaries() {
+        assert_eq!(bucket(0x1000, 0x2000, 0xFFF), None);
+        assert_eq!(bucket(0x1000, 0x2000, 0x1000), Some(0));
+        assert_eq!(bucket(0x1000, 0x2000, 0x1100), Some(1));
+        assert_eq!(bucket(0x1000, 0x2000, 0x1FFF), Some(BUCKETS - 1));
+        assert_eq!(bucket(0x1000, 0x2000, 0x2000), None);
+    }
+
+    #[test_case]
+    fn samples_busy_loop() {
+        let rip = current_rip();
+        start(rip - 0x10_0000, rip + 0x10_0000);
+
+        // Each timer tick samples the instruction after the hlt
+        let mut total = 0;
+        while total < 3 {
+            hlt();
+            let (buckets, outside) = samples();
+            total = buckets.iter().sum::<u64>() + outside;
+        }
+        stop();
+
+        let (buckets, _) = samples();
+        assert!(buckets.iter().sum::<u64>() >= 3);
+    }
+}
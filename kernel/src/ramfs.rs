@@ -0,0 +1,133 @@
+use alloc::{string::String, vec::Vec};
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+lazy_static! {
+    /// The file system lives on the heap, so it can only be used once the heap is initialized
+    static ref RAMFS: Mutex<Ramfs> = Mutex::new(Ramfs::new());
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RamfsError {
+    NotFound,
+    AlreadyExists,
+    /// The file descriptor isn't open
+    BadFd,
+}
+
+/// A flat, in memory file system, every file lives at the top level
+struct Ramfs {
+    inodes: Vec<Inode>,
+    open_files: Vec<Option<OpenFile>>,
+}
+
+struct Inode {
+    name: String,
+    data: Vec<u8>,
+}
+
+#[derive(Clone, Copy)]
+struct OpenFile {
+    inode: usize,
+    offset: usize,
+}
+
+impl Ramfs {
+    fn new() -> Self {
+        Ramfs {
+            inodes: Vec::new(),
+            open_files: Vec::new(),
+        }
+    }
+
+    fn lookup(&self, path: &str) -> Option<usize> {
+        self.inodes.iter().position(|inode| inode.name == path)
+    }
+
+    /// Open `inode` at the lowest free file descriptor
+    fn open_inode(&mut self, inode: usize) -> usize {
+        let file = OpenFile { inode, offset: 0 };
+        match self.open_files.iter().position(|f| f.is_none()) {
+            Some(fd) => {
+                self.open_files[fd] = Some(file);
+                fd
+            }
+            None => {
+                self.open_files.push(Some(file));
+                self.open_files.len() - 1
+            }
+        }
+    }
+
+    fn open_file(&mut self, fd: usize) -> Result<&mut OpenFile, RamfsError> {
+        match self.open_files.get_mut(fd) {
+            Some(Some(file)) => Ok(file),
+            _ => Err(RamfsError::BadFd),
+        }
+    }
+}
+
+/// Create an empty file at `path` and open it
+pub fn create(path: &str) -> Result<usize, RamfsError> {
+    let mut fs = RAMFS.lock();
+    if fs.lookup(path).is_some() {
+        return Err(RamfsError::AlreadyExists);
+    }
+
+    fs.inodes.push(Inode {
+        name: String::from(path),
+        data: Vec::new(),
+    });
+    let inode = fs.inodes.len() - 1;
+    Ok(fs.open_inode(inode))
+}
+
+/// Open the file at `path`, starting at its beginning
+pub fn open(path: &str) -> Result<usize, RamfsError> {
+    let mut fs = RAMFS.lock();
+    match fs.lookup(path) {
+        Some(inode) => Ok(fs.open_inode(inode)),
+        None => Err(RamfsError::NotFound),
+    }
+}
+
+pub fn close(fd: usize) -> Result<(), RamfsError> {
+    let mut fs = RAMFS.lock();
+    fs.open_file(fd)?;
+    fs.open_files[fd] = None;
+    Ok(())
+}
+
+/// Write all of `buf` at the file's current offset, growing the file if needed
+///
+/// Returns the number of bytes written
+pub fn write(fd: usize, buf: &[u8]) -> Result<usize, RamfsError> {
+    let mut fs = RAMFS.lock();
+    let file = *fs.open_file(fd)?;
+
+    let data = &mut fs.inodes[file.inode].data;
+    let end = file.offset + buf.len();
+    if data.len() < end {
+        data.resize(end, 0);
+    }
+    data[file.offset..end].copy_from_slice(buf);
+
+    fs.open_file(fd)?.offset = end;
+    Ok(buf.len())
+}
+
+/// Read from the file's current offset into `buf`
+///
+/// Returns the number of bytes read, which is 0 at the end of the file
+pub fn read(fd: usize, buf: &mut [u8]) -> Result<usize, RamfsError> {
+    let mut fs = RAMFS.lock();
+    let file = *fs.open_file(fd)?;
+
+    let data = &fs.inodes[file.inode].data;
+    let start = file.offset.min(data.len());
+    let len = buf.len().min(data.len() - start);
+    buf[..len].copy_from_slice(&data[start..start + len]);
+
+    fs.open_file(fd)?.offset = start + len;
+    Ok(len)
+}
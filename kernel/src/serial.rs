@@ -2,10 +2,13 @@ use lazy_static::lazy_static;
 use spin::Mutex;
 use uart_16550::SerialPort;
 use x86_64::instructions::interrupts;
+use x86_64::instructions::port::{Port, PortReadOnly};
+
+const COM1: u16 = 0x3F8;
 
 lazy_static! {
     pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
+        let mut serial_port = unsafe { SerialPort::new(COM1) };
         serial_port.init();
         Mutex::new(serial_port)
     };
@@ -15,6 +18,7 @@ lazy_static! {
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
 
+    crate::klog::_record(args);
     interrupts::without_interrupts(|| {
         SERIAL1
             .lock()
@@ -23,6 +27,53 @@ pub fn _print(args: ::core::fmt::Arguments) {
     })
 }
 
+/// Write to COM1 by polling the UART directly, without any locks or prior initialization
+///
+/// This is for diagnosing early boot, before the gdt and interrupts are set up. Once the kernel
+/// is initialized use `serial_print!` instead, as output from the two can interleave
+pub fn early_print(s: &str) {
+    early_write(s.as_bytes());
+}
+
+/// Write raw bytes to COM1 the same way as `early_print`
+pub fn early_write(bytes: &[u8]) {
+    let mut data = Port::<u8>::new(COM1);
+    let mut line_status = PortReadOnly::<u8>::new(COM1 + 5);
+
+    for &byte in bytes {
+        unsafe {
+            // Wait for the transmit buffer to be empty
+            while line_status.read() & 0x20 == 0 {
+                core::hint::spin_loop();
+            }
+            data.write(byte);
+        }
+    }
+}
+
+/// Read a byte from COM1 if one has arrived, polling the UART directly like `early_write`
+pub fn try_read_byte() -> Option<u8> {
+    let mut data = PortReadOnly::<u8>::new(COM1);
+    let mut line_status = PortReadOnly::<u8>::new(COM1 + 5);
+
+    unsafe {
+        if line_status.read() & 0x01 == 0 {
+            return None;
+        }
+        Some(data.read())
+    }
+}
+
+/// Mark the start of a boot stage over serial, when built with the `early_log` feature
+#[inline]
+pub fn early_stage(stage: &str) {
+    if cfg!(feature = "early_log") {
+        early_print("[boot] ");
+        early_print(stage);
+        early_print("\n");
+    }
+}
+
 /// Prints to the host via the serial interface
 #[macro_export]
 macro_rules! serial_print {
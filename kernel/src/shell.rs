@@ -0,0 +1,188 @@
+use crate::{
+    allocator, memory, process, serial, serial_print, serial_println, virt_addr::VirtAddr,
+};
+
+/// The longest command line `debug_shell` accepts
+const SHELL_LINE_SIZE: usize = 64;
+
+/// A command understood by `debug_shell`
+#[derive(Debug, PartialEq)]
+pub enum Command<'a> {
+    /// Print frame & heap usage
+    Mem,
+    /// Print the mapped ranges of the active page table
+    Maps,
+    /// List the live processes
+    Ps,
+    /// Translate an address through the active page table
+    Translate(VirtAddr),
+    Empty,
+    Unknown(&'a str),
+}
+
+impl<'a> Command<'a> {
+    pub fn parse(line: &'a str) -> Self {
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some(c) => c,
+            None => return Command::Empty,
+        };
+
+        match (command, words.next(), words.next()) {
+            ("mem", None, _) => Command::Mem,
+            ("maps", None, _) => Command::Maps,
+            ("ps", None, _) => Command::Ps,
+            ("t", Some(addr), None) => match parse_addr(addr) {
+                Some(addr) => Command::Translate(addr),
+                None => Command::Unknown(line.trim()),
+            },
+            _ => Command::Unknown(line.trim()),
+        }
+    }
+
+    pub fn run(&self) {
+        match self {
+            Command::Mem => allocator::print_mem_stats(),
+            Command::Maps => {
+                let table = unsafe { memory::load_active_pagetable() }; // Only read while the shell runs
+                table.dump_mappings(true);
+            }
+            Command::Ps => process::print_processes(),
+            Command::Translate(addr) => {
+                let table = unsafe { memory::load_active_pagetable() }; // Only read while the shell runs
+                match table.translate_addr(*addr) {
+                    Some(phys) => {
+                        serial_println!("{:?} -> {:?}", addr, phys);
+                    }
+                    None => {
+                        serial_println!("{:?} is not mapped", addr);
+                    }
+                }
+            }
+            Command::Empty => {}
+            Command::Unknown(line) => {
+                serial_println!("unknown command: {}", line);
+                serial_println!("commands: mem, maps, ps, t <addr>");
+            }
+        }
+    }
+}
+
+/// Parse a canonical hex address, with or without a leading 0x
+fn parse_addr(s: &str) -> Option<VirtAddr> {
+    let digits = s.strip_prefix("0x").unwrap_or(s);
+    let addr = u64::from_str_radix(digits, 16).ok()?;
+    let addr = unsafe { VirtAddr::new_unchecked(addr) }; // Checked below
+    if addr.is_canonical() {
+        Some(addr)
+    } else {
+        None
+    }
+}
+
+/// Run a command loop on COM1 for inspecting the running kernel, see `Command`
+///
+/// Input is polled, so this never returns and nothing else runs on this cpu apart from interrupts
+pub fn debug_shell() -> ! {
+    let mut line = [0u8; SHELL_LINE_SIZE];
+    loop {
+        serial_print!("> ");
+        let mut len = 0;
+        loop {
+            let byte = match serial::try_read_byte() {
+                Some(b) => b,
+                None => {
+                    core::hint::spin_loop();
+                    continue;
+                }
+            };
+
+            match byte {
+                b'\r' | b'\n' => break,
+                // Backspace or delete
+                0x08 | 0x7F if len > 0 => {
+                    len -= 1;
+                    serial_print!("\x08 \x08");
+                }
+                0x20..=0x7E if len < SHELL_LINE_SIZE => {
+                    line[len] = byte;
+                    len += 1;
+                    serial_print!("{}", byte as char);
+                }
+                _ => {}
+            }
+        }
+        serial_println!();
+
+        // Only printable ascii is stored so this is always valid utf-8
+        match core::str::from_utf8(&line[..len]) {
+            Ok(s) => Command::parse(s).run(),
+            Err(_) => {
+                serial_println!("invalid input");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::String;
+
+    use super::Command;
+    use crate::{
+        klog::{KERNEL_LOG, LOG_CAPACITY},
+        virt_addr::VirtAddr,
+    };
+
+    /// The last line written to the kernel log
+    fn last_log_line() -> String {
+        let mut log = [0; LOG_CAPACITY];
+        let len = KERNEL_LOG.copy_to(&mut log);
+        // The oldest bytes may be part of a cut off character
+        let log = String::from_utf8_lossy(&log[..len]);
+        match log.trim_end().lines().last() {
+            Some(line) => String::from(line),
+            None => String::new(),
+        }
+    }
+
+    #[test_case]
+    fn parse_mem_command() {
+        assert_eq!(Command::parse("mem"), Command::Mem);
+        assert_eq!(Command::parse("  mem "), Command::Mem);
+    }
+
+    #[test_case]
+    fn parse_translate_command() {
+        assert_eq!(
+            Command::parse("t 0xb8000"),
+            Command::Translate(VirtAddr::new(0xb8000))
+        );
+        assert_eq!(
+            Command::parse("t 0x800000000000"),
+            Command::Unknown("t 0x800000000000")
+        );
+        assert_eq!(Command::parse("t"), Command::Unknown("t"));
+    }
+
+    #[test_case]
+    fn parse_other_commands() {
+        assert_eq!(Command::parse("maps"), Command::Maps);
+        assert_eq!(Command::parse("ps"), Command::Ps);
+        assert_eq!(Command::parse(""), Command::Empty);
+        assert_eq!(Command::parse("ps aux"), Command::Unknown("ps aux"));
+    }
+    #[test_case]
+    fn run_mem_prints_stats() {
+        Command::Mem.run();
+        assert!(last_log_line().starts_with("heap allocations by size: "));
+    }
+
+    #[test_case]
+    fn run_maps_dumps_active_table() {
+        Command::Maps.run();
+        let summary = last_log_line();
+        assert!(summary.contains("mapped runs"));
+        assert!(summary.ends_with(" bytes"));
+    }
+}
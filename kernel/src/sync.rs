@@ -0,0 +1,253 @@
+use core::ops::{Deref, DerefMut};
+#[cfg(debug_assertions)]
+use core::sync::atomic::{AtomicU32, Ordering};
+
+#[cfg(debug_assertions)]
+use spin::{Mutex, MutexGuard};
+use x86_64::instructions::interrupts;
+
+#[cfg(debug_assertions)]
+use crate::cpu;
+
+/// A spinlock which panics if the cpu holding it tries to take it again, rather than hanging
+///
+/// In release builds this is a plain `spin::Mutex`
+#[cfg(debug_assertions)]
+pub struct DebugMutex<T> {
+    inner: Mutex<T>,
+    /// The id of the cpu holding the lock, or NO_OWNER
+    owner: AtomicU32,
+}
+
+#[cfg(not(debug_assertions))]
+pub type DebugMutex<T> = spin::Mutex<T>;
+#[cfg(not(debug_assertions))]
+pub type DebugMutexGuard<'a, T> = spin::MutexGuard<'a, T>;
+
+#[cfg(debug_assertions)]
+const NO_OWNER: u32 = u32::MAX;
+
+#[cfg(debug_assertions)]
+impl<T> DebugMutex<T> {
+    pub const fn new(value: T) -> Self {
+        DebugMutex {
+            inner: Mutex::new(value),
+            owner: AtomicU32::new(NO_OWNER),
+        }
+    }
+
+    pub fn lock(&self) -> DebugMutexGuard<'_, T> {
+        let cpu = cpu::id();
+        if self.owner.load(Ordering::Acquire) == cpu {
+            panic!("recursive lock of a DebugMutex on cpu {}", cpu);
+        }
+
+        let guard = self.inner.lock();
+        self.owner.store(cpu, Ordering::Release);
+        DebugMutexGuard {
+            guard,
+            owner: &self.owner,
+        }
+    }
+
+    /// Take the lock if it's free, returning None rather than spinning or panicking if it's held
+    pub fn try_lock(&self) -> Option<DebugMutexGuard<'_, T>> {
+        let guard = self.inner.try_lock()?;
+        self.owner.store(cpu::id(), Ordering::Release);
+        Some(DebugMutexGuard {
+            guard,
+            owner: &self.owner,
+        })
+    }
+}
+
+#[cfg(debug_assertions)]
+pub struct DebugMutexGuard<'a, T> {
+    guard: MutexGuard<'a, T>,
+    owner: &'a AtomicU32,
+}
+
+#[cfg(debug_assertions)]
+impl<'a, T> Deref for DebugMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+#[cfg(debug_assertions)]
+impl<'a, T> DerefMut for DebugMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+#[cfg(debug_assertions)]
+impl<'a, T> Drop for DebugMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        // This runs before the inner guard releases the lock
+        self.owner.store(NO_OWNER, Ordering::Release);
+    }
+}
+
+/// A spinlock which disables interrupts while it's held
+///
+/// Use this for data interrupt handlers also lock, otherwise a handler which interrupts the
+/// lock holder spins forever. Exceptions such as page faults aren't masked, so a fault handler
+/// which takes the lock can still deadlock if the holder faults
+pub struct IrqMutex<T> {
+    inner: DebugMutex<T>,
+}
+
+impl<T> IrqMutex<T> {
+    pub const fn new(value: T) -> Self {
+        IrqMutex {
+            inner: DebugMutex::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> IrqMutexGuard<'_, T> {
+        let enabled = interrupts::are_enabled();
+        interrupts::disable();
+
+        IrqMutexGuard {
+            guard: Some(self.inner.lock()),
+            enabled,
+        }
+    }
+
+    /// Take the lock if it's free, interrupts are left alone if it's held
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<'_, T>> {
+        let enabled = interrupts::are_enabled();
+        interrupts::disable();
+
+        match self.inner.try_lock() {
+            Some(guard) => Some(IrqMutexGuard {
+                guard: Some(guard),
+                enabled,
+            }),
+            None => {
+                if enabled {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+}
+
+pub struct IrqMutexGuard<'a, T> {
+    /// Always Some until the guard is dropped
+    guard: Option<DebugMutexGuard<'a, T>>,
+    /// Whether interrupts were enabled before the lock was taken
+    enabled: bool,
+}
+
+impl<'a, T> Deref for IrqMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match &self.guard {
+            Some(guard) => guard,
+            None => unreachable!(),
+        }
+    }
+}
+
+impl<'a, T> DerefMut for IrqMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        match &mut self.guard {
+            Some(guard) => guard,
+            None => unreachable!(),
+        }
+    }
+}
+
+impl<'a, T> Drop for IrqMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        // Release the lock before an interrupt can arrive and try to take it
+        self.guard.take();
+        if self.enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use x86_64::instructions::interrupts;
+
+    use crate::allocator::{FrameAllocator, FRAME_ALLOCATOR};
+
+    use super::{DebugMutex, IrqMutex};
+
+    #[test_case]
+    fn debug_mutex_relocks_after_release() {
+        let mutex = DebugMutex::new(0);
+        *mutex.lock() += 1;
+        *mutex.lock() += 1;
+
+        assert_eq!(*mutex.lock(), 2);
+    }
+
+    #[test_case]
+    fn try_lock_fails_while_held() {
+        let mutex = IrqMutex::new(0);
+
+        {
+            let _held = mutex.lock();
+            assert!(mutex.try_lock().is_none());
+            assert!(!interrupts::are_enabled());
+        }
+
+        assert!(interrupts::are_enabled());
+        match mutex.try_lock() {
+            Some(mut value) => *value += 1,
+            None => panic!("free lock could not be taken"),
+        }
+        assert_eq!(*mutex.lock(), 1);
+    }
+
+    #[test_case]
+    fn lock_masks_interrupts() {
+        let mutex = IrqMutex::new(0);
+        assert!(interrupts::are_enabled());
+
+        {
+            let mut value = mutex.lock();
+            *value += 1;
+            assert!(!interrupts::are_enabled());
+        }
+
+        assert!(interrupts::are_enabled());
+        assert_eq!(*mutex.lock(), 1);
+    }
+
+    #[test_case]
+    fn lock_keeps_interrupts_disabled() {
+        let mutex = IrqMutex::new(0);
+
+        interrupts::without_interrupts(|| {
+            drop(mutex.lock());
+            assert!(!interrupts::are_enabled());
+        });
+    }
+
+    #[test_case]
+    fn handler_allocation_while_interrupts_enabled() {
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+
+        // Interrupts only come back once the lock is released, so a handler allocating as soon
+        // as one arrives can take the lock rather than spinning on it forever
+        let first = alloc.lock().allocate();
+        assert!(interrupts::are_enabled());
+        let from_handler = interrupts::without_interrupts(|| alloc.lock().allocate());
+
+        assert!(first.is_some());
+        assert!(from_handler.is_some());
+    }
+}
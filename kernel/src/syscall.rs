@@ -0,0 +1,275 @@
+use alloc::string::String;
+use core::cmp::min;
+
+use crate::{
+    memory::load_active_pagetable,
+    process::{self, ProcessError},
+    usercopy::copy_from_user,
+    virt_addr::VirtAddr,
+};
+
+pub const SYS_WRITE: u64 = 1;
+pub const SYS_EXIT: u64 = 60;
+
+const EPERM: isize = 1;
+const ESRCH: isize = 3;
+const EBADF: isize = 9;
+const EFAULT: isize = 14;
+const ENOSYS: isize = 38;
+
+/// The most bytes `sys_write` copies out of user memory at once
+const WRITE_CHUNK_SIZE: usize = 256;
+
+/// Run syscall `num`, returning its result or a negated error number
+pub fn syscall(num: u64, a1: u64, a2: u64, a3: u64) -> isize {
+    match num {
+        SYS_WRITE => sys_write(a1 as usize, a2, a3 as usize),
+        SYS_EXIT => sys_exit(a1 as i32),
+        _ => -ENOSYS,
+    }
+}
+
+/// Write `len` bytes from `buf` to `fd`, invalid UTF-8 is replaced rather than rejected
+///
+/// The buffer is copied a chunk at a time, so a fault part way through returns the number of
+/// bytes already written. A fault in the first chunk returns EFAULT. A character cut off at the
+/// end of a chunk is carried over to the next one, so it isn't replaced
+fn sys_write(fd: usize, buf: u64, len: usize) -> isize {
+    let kind = match process::current_fd(fd) {
+        Some(k) => k,
+        None => return -EBADF,
+    };
+
+    let table = unsafe { load_active_pagetable() }; // Only read while the syscall runs
+    let mut chunk = [0; WRITE_CHUNK_SIZE];
+    // The bytes at the start of `chunk` left over from the last one
+    let mut carried = 0;
+    let mut done = 0;
+    while done < len {
+        let size = min(len - done, WRITE_CHUNK_SIZE - carried);
+        // Every chunk before this one was below the user limit, so this can't overflow
+        let copied = match VirtAddr::try_new(buf + done as u64) {
+            Ok(addr) => copy_from_user(table, addr, &mut chunk[carried..carried + size]).is_ok(),
+            Err(_) => false,
+        };
+        if !copied {
+            // The carried bytes were counted as written, so they can't be dropped
+            kind.write(&String::from_utf8_lossy(&chunk[..carried]));
+            return fault_result(done);
+        }
+
+        let filled = carried + size;
+        let split = incomplete_tail(&chunk[..filled]);
+        kind.write(&String::from_utf8_lossy(&chunk[..split]));
+        chunk.copy_within(split..filled, 0);
+        carried = filled - split;
+        done += size;
+    }
+    // The buffer itself ends part way through a character
+    if carried > 0 {
+        kind.write(&String::from_utf8_lossy(&chunk[..carried]));
+    }
+
+    len as isize
+}
+
+/// Where the character cut off at the end of `bytes` starts, or `bytes.len()` if none is
+fn incomplete_tail(bytes: &[u8]) -> usize {
+    // A character is at most 4 bytes, so only the last 3 can start one that's cut off
+    for start in bytes.len().saturating_sub(3)..bytes.len() {
+        if let Err(err) = core::str::from_utf8(&bytes[start..]) {
+            // No error length means the input ended in the middle of a valid sequence
+            if err.valid_up_to() == 0 && err.error_len().is_none() {
+                return start;
+            }
+        }
+    }
+
+    bytes.len()
+}
+
+/// The result of a write which faulted after `done` bytes
+fn fault_result(done: usize) -> isize {
+    match done {
+        0 => -EFAULT,
+        _ => done as isize,
+    }
+}
+
+/// Exit the running process and switch away from it, it's never scheduled again
+fn sys_exit(code: i32) -> isize {
+    let pid = match process::current_pid() {
+        Some(p) => p,
+        None => return -ESRCH,
+    };
+
+    match process::exit_process(pid, code) {
+        Ok(()) => {}
+        Err(ProcessError::IsInit) => return -EPERM,
+        Err(_) => return -ESRCH,
+    }
+    process::schedule();
+
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::String;
+    use core::arch::asm;
+
+    use crate::{
+        allocator::{FrameAllocator, FrameDeallocator, FRAME_ALLOCATOR},
+        klog::{KERNEL_LOG, LOG_CAPACITY},
+        memory::load_active_pagetable,
+        paging::{Page, PageTableEntry, PageTableEntryFlags, Phys},
+        trap::SYSCALL_VECTOR,
+        usercopy::copy_to_user,
+        virt_addr::VirtAddr,
+    };
+
+    use super::{EBADF, EFAULT, ENOSYS, ESRCH, SYS_EXIT, SYS_WRITE, WRITE_CHUNK_SIZE};
+
+    /// An otherwise unused top level slot for test buffers
+    const USER_BUF: u64 = 0x5800_0000_0000;
+
+    /// Make a syscall through the interrupt gate, as user code would
+    fn raw_syscall(num: u64, a1: u64, a2: u64, a3: u64) -> isize {
+        let ret: u64;
+        unsafe {
+            asm!(
+                "int {vector}",
+                vector = const SYSCALL_VECTOR,
+                inlateout("rax") num => ret,
+                in("rdi") a1,
+                in("rsi") a2,
+                in("rdx") a3,
+            );
+        }
+        ret as isize
+    }
+
+    /// Run `f` with `bytes` copied to a user accessible page in the active address space
+    fn with_user_buffer<F: FnOnce(VirtAddr)>(bytes: &[u8], f: F) {
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+        let frame = match alloc.lock().allocate() {
+            Some(f) => f,
+            None => panic!("could not allocate frame"),
+        };
+
+        let table = unsafe { load_active_pagetable() }; // Nothing else maps the test slot
+        let addr = VirtAddr::new(USER_BUF);
+        let page = Page::containing_address(addr);
+        let entry = PageTableEntry::new(frame, PageTableEntryFlags::user_rw());
+        match unsafe { table.map_page(page, entry, &mut *alloc.lock()) } {
+            Ok(_) => {}
+            Err(err) => panic!("error mapping page: {:?}", err),
+        }
+        match copy_to_user(table, addr, bytes, &mut *alloc.lock()) {
+            Ok(_) => {}
+            Err(err) => panic!("error copying to user: {:?}", err),
+        }
+
+        f(addr);
+
+        match table.unmap_page(page) {
+            Ok(Phys::Size4Kb(f)) => unsafe { alloc.lock().deallocate(f) }, // Nothing maps the frame any more
+            Ok(other) => panic!("unexpected frame unmapped: {:?}", other),
+            Err(err) => panic!("error unmapping page: {:?}", err),
+        }
+    }
+
+    /// The end of the kernel log
+    fn log_tail() -> String {
+        let mut log = [0; LOG_CAPACITY];
+        let len = KERNEL_LOG.copy_to(&mut log);
+        // The oldest bytes may be part of a cut off character
+        String::from_utf8_lossy(&log[..len]).into_owned()
+    }
+
+    #[test_case]
+    fn write_syscall_prints_buffer() {
+        let message = "hello from int 0x80\n";
+        let mut written = 0;
+        with_user_buffer(message.as_bytes(), |buf| {
+            written = raw_syscall(SYS_WRITE, 1, buf.as_u64(), message.len() as u64);
+        });
+        assert_eq!(written, message.len() as isize);
+        assert!(log_tail().ends_with(message));
+    }
+
+    #[test_case]
+    fn write_syscall_copies_in_chunks() {
+        let message = [b'x'; WRITE_CHUNK_SIZE * 2 + 3];
+        let mut written = 0;
+        with_user_buffer(&message, |buf| {
+            written = raw_syscall(SYS_WRITE, 1, buf.as_u64(), message.len() as u64);
+        });
+        assert_eq!(written, message.len() as isize);
+        assert!(log_tail().ends_with(&*String::from_utf8_lossy(&message)));
+    }
+
+    #[test_case]
+    fn write_keeps_characters_split_across_chunks() {
+        // The 3 byte character starts 2 bytes before the end of the first chunk
+        let mut message = String::new();
+        for _ in 0..WRITE_CHUNK_SIZE - 2 {
+            message.push('z');
+        }
+        message.push_str("\u{20AC} end\n");
+
+        let mut written = 0;
+        with_user_buffer(message.as_bytes(), |buf| {
+            written = raw_syscall(SYS_WRITE, 1, buf.as_u64(), message.len() as u64);
+        });
+        assert_eq!(written, message.len() as isize);
+        assert!(log_tail().ends_with(&message));
+    }
+
+    #[test_case]
+    fn write_from_kernel_buffer_faults() {
+        let message = "kernel memory";
+        let written = raw_syscall(SYS_WRITE, 1, message.as_ptr() as u64, message.len() as u64);
+        assert_eq!(written, -EFAULT);
+
+        let written = raw_syscall(SYS_WRITE, 1, 0x8000_0000_0000, 8);
+        assert_eq!(written, -EFAULT);
+    }
+
+    #[test_case]
+    fn write_past_user_buffer_is_partial() {
+        let start = 4096 - WRITE_CHUNK_SIZE as u64;
+        let mut written = 0;
+        with_user_buffer(&[b'y'; 4096], |buf| {
+            // The second chunk is on the next page, which isn't mapped
+            written = raw_syscall(
+                SYS_WRITE,
+                1,
+                buf.as_u64() + start,
+                2 * WRITE_CHUNK_SIZE as u64,
+            );
+        });
+        assert_eq!(written, WRITE_CHUNK_SIZE as isize);
+    }
+
+    #[test_case]
+    fn exit_without_process_fails() {
+        // The tests run on the boot thread, which isn't a process
+        assert_eq!(raw_syscall(SYS_EXIT, 0, 0, 0), -ESRCH);
+    }
+
+    #[test_case]
+    fn write_to_closed_fd_fails() {
+        let message = "unseen";
+        let written = raw_syscall(SYS_WRITE, 7, message.as_ptr() as u64, message.len() as u64);
+        assert_eq!(written, -EBADF);
+    }
+
+    #[test_case]
+    fn unknown_syscall_fails() {
+        assert_eq!(raw_syscall(0xFFFF, 0, 0, 0), -ENOSYS);
+    }
+}
@@ -0,0 +1,126 @@
+use core::arch::asm;
+
+use x86_64::{instructions::interrupts, registers::rflags::RFlags};
+
+use crate::{gdt, virt_addr::VirtAddr};
+
+/// The interrupt vector user code raises to make a syscall
+pub const SYSCALL_VECTOR: u8 = 0x80;
+
+/// Register state saved when a process is interrupted
+///
+/// The general purpose registers are pushed by the interrupt entry stub, from rax down to
+/// r15, followed by the frame the CPU pushes itself. Syscalls follow the SysV calling
+/// convention with the syscall number in rax
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+
+    // Pushed by the CPU
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl TrapFrame {
+    #[inline]
+    pub fn syscall_number(&self) -> u64 {
+        self.rax
+    }
+
+    #[inline]
+    pub fn syscall_args(&self) -> [u64; 3] {
+        [self.rdi, self.rsi, self.rdx]
+    }
+
+    #[inline]
+    pub fn return_value(&self) -> isize {
+        self.rax as isize
+    }
+
+    /// Set the value seen in rax when the process is resumed
+    #[inline]
+    pub fn set_return_value(&mut self, value: isize) {
+        self.rax = value as u64;
+    }
+}
+
+/// Drop to ring 3 and start running at `entry` with `stack` as the stack pointer
+///
+/// Interrupts are enabled in ring 3 only if they're enabled here. They return to the kernel on
+/// the TSS's ring 0 stack, this stack is abandoned
+pub fn enter_user_mode(entry: VirtAddr, stack: VirtAddr) -> ! {
+    let code = u64::from(gdt::user_code_selector().0);
+    let data = u64::from(gdt::user_data_selector().0);
+    // Bit 1 is reserved and always set
+    let mut rflags: u64 = 0x2;
+    if interrupts::are_enabled() {
+        rflags |= RFlags::INTERRUPT_FLAG.bits();
+    }
+
+    unsafe {
+        asm!(
+            "mov ds, {data:x}",
+            "mov es, {data:x}",
+            // The frame iretq pops: rip, cs, rflags, rsp then ss
+            "push {data}",
+            "push {stack}",
+            "push {rflags}",
+            "push {code}",
+            "push {entry}",
+            "iretq",
+            data = in(reg) data,
+            stack = in(reg) stack.as_u64(),
+            rflags = in(reg) rflags,
+            code = in(reg) code,
+            entry = in(reg) entry.as_u64(),
+            options(noreturn),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TrapFrame;
+
+    #[test_case]
+    fn syscall_registers() {
+        let frame = TrapFrame {
+            rax: 1,
+            rdi: 2,
+            rsi: 3,
+            rdx: 4,
+            rcx: 5,
+            ..Default::default()
+        };
+
+        assert_eq!(frame.syscall_number(), 1);
+        assert_eq!(frame.syscall_args(), [2, 3, 4]);
+    }
+
+    #[test_case]
+    fn set_return_value() {
+        let mut frame = TrapFrame::default();
+        frame.set_return_value(-22);
+
+        assert_eq!(frame.rax, (-22i64) as u64);
+        assert_eq!(frame.return_value(), -22);
+    }
+}
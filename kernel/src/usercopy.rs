@@ -0,0 +1,328 @@
+use core::{cmp::min, ops::Range, ptr};
+
+use crate::{
+    allocator::FrameAllocator,
+    memory::get_offset,
+    pagetable::{CowError, PageTable},
+    paging::PageTableEntryFlags,
+    virt_addr::VirtAddr,
+};
+
+const PAGE_SIZE: usize = 4096;
+/// The end of the lower half, user buffers must sit below it
+const USER_ADDR_LIMIT: u64 = 0x8000_0000_0000;
+
+#[derive(Debug, PartialEq)]
+pub enum CopyError {
+    /// The buffer covers an address which isn't mapped
+    NotMapped(VirtAddr),
+    /// The buffer starting at this address reaches into the kernel's half of the address space
+    KernelAddress(VirtAddr),
+    /// The buffer covers a page user code can't access
+    NotUserAccessible(VirtAddr),
+    /// The buffer being written covers a page user code can't write
+    ReadOnly(VirtAddr),
+    /// The buffer being written covers a copy on write page which couldn't be given its own frame
+    CopyOnWrite(CowError),
+}
+
+/// Copy `dst.len()` bytes from `src` in the address space of `table` into `dst`
+pub fn copy_from_user(table: &PageTable, src: VirtAddr, dst: &mut [u8]) -> Result<(), CopyError> {
+    for_each_page(table, src, dst.len(), |user, range| unsafe {
+        // The user pointer covers range.len() bytes inside a single mapped frame
+        ptr::copy_nonoverlapping(user, dst[range.clone()].as_mut_ptr(), range.len());
+    })
+}
+
+/// Copy all of `src` to `dst` in the address space of `table`
+///
+/// Every page must be writable by user code. Copy on write pages are given their own frame from
+/// `allocator` first, so the write isn't seen through the other mappings of the shared frame
+pub fn copy_to_user<T: FrameAllocator>(
+    table: &mut PageTable,
+    dst: VirtAddr,
+    src: &[u8],
+    allocator: &mut T,
+) -> Result<(), CopyError> {
+    make_writable(table, dst, src.len(), allocator)?;
+    for_each_page(table, dst, src.len(), |user, range| unsafe {
+        // The user pointer covers range.len() bytes inside a single mapped frame
+        ptr::copy_nonoverlapping(src[range.clone()].as_ptr(), user, range.len());
+    })
+}
+
+/// Check user code can write every page `len` bytes from `addr` covers, resolving copy on write
+/// pages so each one is backed by a frame this table owns
+fn make_writable<T: FrameAllocator>(
+    table: &mut PageTable,
+    addr: VirtAddr,
+    len: usize,
+    allocator: &mut T,
+) -> Result<(), CopyError> {
+    check_bounds(addr, len)?;
+
+    let mut done = 0;
+    while done < len {
+        let virt = addr + done as u64;
+        let flags = match table.translate_with_flags(virt) {
+            Some((_, f)) if f.contains(PageTableEntryFlags::USER_ACCESSIBLE) => f,
+            Some(_) => return Err(CopyError::NotUserAccessible(virt)),
+            None => return Err(CopyError::NotMapped(virt)),
+        };
+
+        if flags.contains(PageTableEntryFlags::COPY_ON_WRITE) {
+            if let Err(err) = table.resolve_cow(virt, allocator) {
+                return Err(CopyError::CopyOnWrite(err));
+            }
+        } else if !flags.contains(PageTableEntryFlags::WRITABLE) {
+            return Err(CopyError::ReadOnly(virt));
+        }
+
+        done += PAGE_SIZE - u64::from(virt.page_offset()) as usize;
+    }
+
+    Ok(())
+}
+
+/// Check `len` bytes from `addr` sit in the lower half
+fn check_bounds(addr: VirtAddr, len: usize) -> Result<(), CopyError> {
+    match addr.as_u64().checked_add(len as u64) {
+        Some(end) if end <= USER_ADDR_LIMIT => Ok(()),
+        _ => Err(CopyError::KernelAddress(addr)),
+    }
+}
+
+/// Split `len` bytes from `addr` at page boundaries, calling `f` with a pointer to each piece
+/// through the physical memory mapping along with its range in the buffer
+///
+/// Adjacent pages needn't be backed by adjacent frames, so each page is translated separately.
+/// The whole buffer must be in the lower half and every page must be user accessible
+fn for_each_page<F: FnMut(*mut u8, Range<usize>)>(
+    table: &PageTable,
+    addr: VirtAddr,
+    len: usize,
+    mut f: F,
+) -> Result<(), CopyError> {
+    check_bounds(addr, len)?;
+
+    let mut done = 0;
+    while done < len {
+        let virt = addr + done as u64;
+        let phys = match table.translate_with_flags(virt) {
+            Some((p, flags)) if flags.contains(PageTableEntryFlags::USER_ACCESSIBLE) => p,
+            Some(_) => return Err(CopyError::NotUserAccessible(virt)),
+            None => return Err(CopyError::NotMapped(virt)),
+        };
+
+        let page_remaining = PAGE_SIZE - u64::from(virt.page_offset()) as usize;
+        let chunk = min(len - done, page_remaining);
+        f(
+            (get_offset() + phys.as_u64()).as_mut_ptr(),
+            done..done + chunk,
+        );
+        done += chunk;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        allocator::{FrameAllocator, ZeroAllocator, FRAME_ALLOCATOR},
+        memory::get_offset,
+        pagetable::PageTable,
+        paging::{Page, PageTableEntry, PageTableEntryFlags},
+        virt_addr::VirtAddr,
+    };
+
+    use super::{copy_from_user, copy_to_user, CopyError};
+
+    const USER_ADDR: u64 = 0x5555_2000_0000;
+
+    #[test_case]
+    fn copy_across_discontiguous_pages() {
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+        let (first, second) = {
+            let mut alloc = alloc.lock();
+            match (alloc.allocate(), alloc.allocate()) {
+                (Some(a), Some(b)) => (a, b),
+                _ => panic!("could not allocate frames"),
+            }
+        };
+
+        // Map the pages to the frames in reverse so they aren't physically contiguous
+        let mut table = PageTable::new();
+        let flags = PageTableEntryFlags::user_rw();
+        let low = Page::containing_address(VirtAddr::new(USER_ADDR));
+        let high = Page::containing_address(VirtAddr::new(USER_ADDR + 4096));
+        for (page, frame) in [(low, second), (high, first)] {
+            let result = unsafe {
+                table.map_page(page, PageTableEntry::new(frame, flags), &mut *alloc.lock())
+            };
+            match result {
+                Ok(_) => {}
+                Err(err) => panic!("error mapping page: {:?}", err),
+            }
+        }
+
+        let data: [u8; 16] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16];
+        let start = VirtAddr::new(USER_ADDR + 4096 - 8);
+        match copy_to_user(&mut table, start, &data, &mut *alloc.lock()) {
+            Ok(_) => {}
+            Err(err) => panic!("error copying to user: {:?}", err),
+        }
+
+        unsafe {
+            let second_end: *const u8 =
+                (get_offset() + second.start_address().as_u64() + 4088).as_ptr();
+            let first_start: *const u8 = (get_offset() + first.start_address().as_u64()).as_ptr();
+            assert_eq!(*second_end, 1);
+            assert_eq!(*second_end.add(7), 8);
+            assert_eq!(*first_start, 9);
+            assert_eq!(*first_start.add(7), 16);
+        }
+
+        let mut read = [0; 16];
+        match copy_from_user(&table, start, &mut read) {
+            Ok(_) => {}
+            Err(err) => panic!("error copying from user: {:?}", err),
+        }
+        assert_eq!(read, data);
+    }
+
+    #[test_case]
+    fn copy_from_kernel_page() {
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+        let frame = match alloc.lock().allocate() {
+            Some(f) => f,
+            None => panic!("could not allocate frame"),
+        };
+
+        let mut table = PageTable::new();
+        let page = Page::containing_address(VirtAddr::new(USER_ADDR));
+        let entry = PageTableEntry::new(frame, PageTableEntryFlags::kernel_rw());
+        let result = unsafe { table.map_page(page, entry, &mut *alloc.lock()) };
+        match result {
+            Ok(_) => {}
+            Err(err) => panic!("error mapping page: {:?}", err),
+        }
+
+        let mut read = [0; 8];
+        assert_eq!(
+            copy_from_user(&table, VirtAddr::new(USER_ADDR), &mut read),
+            Err(CopyError::NotUserAccessible(VirtAddr::new(USER_ADDR)))
+        );
+    }
+
+    #[test_case]
+    fn copy_to_read_only_page() {
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+        let mut alloc = alloc.lock();
+        let frame = match alloc.allocate() {
+            Some(f) => f,
+            None => panic!("could not allocate frame"),
+        };
+
+        let mut table = PageTable::new();
+        let page = Page::containing_address(VirtAddr::new(USER_ADDR));
+        let flags = PageTableEntryFlags::user_rw() - PageTableEntryFlags::WRITABLE;
+        let result =
+            unsafe { table.map_page(page, PageTableEntry::new(frame, flags), &mut *alloc) };
+        match result {
+            Ok(_) => {}
+            Err(err) => panic!("error mapping page: {:?}", err),
+        }
+
+        assert_eq!(
+            copy_to_user(&mut table, VirtAddr::new(USER_ADDR), &[1; 8], &mut *alloc),
+            Err(CopyError::ReadOnly(VirtAddr::new(USER_ADDR)))
+        );
+        table.free_user_mappings(&mut *alloc);
+    }
+
+    #[test_case]
+    fn copy_to_cow_page_leaves_other_mapping() {
+        let alloc = match FRAME_ALLOCATOR.wait() {
+            Some(a) => a,
+            None => panic!("boot info allocator not initialized"),
+        };
+        let mut alloc = alloc.lock();
+        let frame = match alloc.allocate() {
+            Some(f) => f,
+            None => panic!("could not allocate frame"),
+        };
+
+        let addr = VirtAddr::new(USER_ADDR);
+        let mut parent = PageTable::new();
+        let entry = PageTableEntry::new(frame, PageTableEntryFlags::user_rw());
+        let result = unsafe { parent.map_page(Page::containing_address(addr), entry, &mut *alloc) };
+        match result {
+            Ok(_) => {}
+            Err(err) => panic!("error mapping page: {:?}", err),
+        }
+        match copy_to_user(&mut parent, addr, &[1; 8], &mut *alloc) {
+            Ok(_) => {}
+            Err(err) => panic!("error copying to user: {:?}", err),
+        }
+        let mut child = match parent.clone_cow(&mut *alloc) {
+            Ok(t) => t,
+            Err(err) => panic!("error cloning table: {:?}", err),
+        };
+
+        match copy_to_user(&mut child, addr, &[2; 8], &mut *alloc) {
+            Ok(_) => {}
+            Err(err) => panic!("error copying to user: {:?}", err),
+        }
+
+        let mut read = [0; 8];
+        for (table, expected) in [(&parent, [1; 8]), (&child, [2; 8])] {
+            match copy_from_user(table, addr, &mut read) {
+                Ok(_) => {}
+                Err(err) => panic!("error copying from user: {:?}", err),
+            }
+            assert_eq!(read, expected);
+        }
+        assert_ne!(parent.translate_addr(addr), child.translate_addr(addr));
+
+        child.free_user_mappings(&mut *alloc);
+        parent.free_user_mappings(&mut *alloc);
+    }
+
+    #[test_case]
+    fn copy_across_user_boundary() {
+        let mut table = PageTable::new();
+        let mut read = [0; 8];
+
+        let kernel = VirtAddr::new(0xFFFF_8000_0000_0000);
+        assert_eq!(
+            copy_from_user(&table, kernel, &mut read),
+            Err(CopyError::KernelAddress(kernel))
+        );
+        let straddling = VirtAddr::new(0x7FFF_FFFF_FFFC);
+        assert_eq!(
+            copy_to_user(&mut table, straddling, &read, &mut ZeroAllocator),
+            Err(CopyError::KernelAddress(straddling))
+        );
+    }
+
+    #[test_case]
+    fn copy_from_unmapped_page() {
+        let table = PageTable::new();
+        let mut read = [0; 8];
+
+        assert_eq!(
+            copy_from_user(&table, VirtAddr::new(USER_ADDR), &mut read),
+            Err(CopyError::NotMapped(VirtAddr::new(USER_ADDR)))
+        );
+    }
+}
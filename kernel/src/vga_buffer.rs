@@ -7,6 +7,7 @@ use x86_64::instructions::interrupts;
 lazy_static! {
     pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
         column_position: 0,
+        row_position: BUFFER_HEIGHT - 1,
         color_code: ColorCode::new(Color::Yellow, Color::Black),
         buffer: unsafe { &mut *(VGA_BUFFER_ADDRESS as *mut Buffer) },
     });
@@ -36,16 +37,24 @@ pub enum Color {
     White = 15,
 }
 
+/// A VGA attribute byte, the background color in the high nibble and the foreground in the low
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(transparent)]
-struct ColorCode(u8);
+pub struct ColorCode(u8);
 
 impl ColorCode {
-    fn new(foreground: Color, background: Color) -> ColorCode {
+    pub fn new(foreground: Color, background: Color) -> ColorCode {
         ColorCode((background as u8) << 4 | (foreground as u8))
     }
 }
 
+impl From<Color> for ColorCode {
+    /// The color as a foreground on black
+    fn from(foreground: Color) -> Self {
+        ColorCode::new(foreground, Color::Black)
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(C)]
 struct ScreenChar {
@@ -63,11 +72,23 @@ struct Buffer {
 
 pub struct Writer {
     column_position: usize,
+    row_position: usize,
     color_code: ColorCode,
     buffer: &'static mut Buffer,
 }
 
 impl Writer {
+    #[inline]
+    pub fn color_code(&self) -> ColorCode {
+        self.color_code
+    }
+
+    /// Set the color for everything written from now on, what's already on screen keeps its color
+    #[inline]
+    pub fn set_color_code(&mut self, color_code: ColorCode) {
+        self.color_code = color_code;
+    }
+
     pub fn write_string(&mut self, s: &str) {
         for byte in s.bytes() {
             match byte {
@@ -87,7 +108,7 @@ impl Writer {
                     self.new_line();
                 }
 
-                let row = BUFFER_HEIGHT - 1;
+                let row = self.row_position;
                 let col = self.column_position;
 
                 let color_code = self.color_code;
@@ -101,6 +122,12 @@ impl Writer {
     }
 
     fn new_line(&mut self) {
+        self.column_position = 0;
+        if self.row_position < BUFFER_HEIGHT - 1 {
+            self.row_position += 1;
+            return;
+        }
+
         for row in 1..BUFFER_HEIGHT {
             for col in 0..BUFFER_WIDTH {
                 let character = self.buffer.chars[row][col].read();
@@ -108,7 +135,15 @@ impl Writer {
             }
         }
         self.clear_row(BUFFER_HEIGHT - 1);
+    }
+
+    /// Blank every cell and move the cursor to the top left
+    pub fn clear_screen(&mut self) {
+        for row in 0..BUFFER_HEIGHT {
+            self.clear_row(row);
+        }
         self.column_position = 0;
+        self.row_position = 0;
     }
 
     fn clear_row(&mut self, row: usize) {
@@ -142,18 +177,72 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
+/// Prints to the screen via the VGA buffer in the given color, appending a new line
+///
+/// The color is a `ColorCode` or a foreground `Color`, the writer's color is restored afterwards
+#[macro_export]
+macro_rules! println_colored {
+    ($color:expr) => ($crate::vga_buffer::_print_colored($color.into(), format_args!("\n")));
+    ($color:expr, $($arg:tt)*) => (
+        $crate::vga_buffer::_print_colored($color.into(), format_args!("{}\n", format_args!($($arg)*)))
+    );
+}
+
+/// Clears the screen via the VGA buffer
+#[macro_export]
+macro_rules! clear {
+    () => {
+        $crate::vga_buffer::clear_screen()
+    };
+}
+
+/// Blank the whole screen, output then starts again from the top left
+pub fn clear_screen() {
+    interrupts::without_interrupts(|| {
+        WRITER.lock().clear_screen();
+    })
+}
+
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
 
+    crate::klog::_record(args);
     interrupts::without_interrupts(|| {
         WRITER.lock().write_fmt(args).unwrap();
     })
 }
 
+#[doc(hidden)]
+pub fn _print_colored(color_code: ColorCode, args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    crate::klog::_record(args);
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let previous = writer.color_code();
+        writer.set_color_code(color_code);
+        writer.write_fmt(args).unwrap();
+        writer.set_color_code(previous);
+    })
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::vga_buffer::*;
+    use crate::{
+        memory::load_active_pagetable, paging::PageTableEntryFlags, vga_buffer::*,
+        virt_addr::VirtAddr,
+    };
+
+    #[test_case]
+    fn vga_buffer_is_uncached() {
+        let table = unsafe { load_active_pagetable() };
+
+        match table.leaf_entry_mut(VirtAddr::new(VGA_BUFFER_ADDRESS)) {
+            Some(entry) => assert!(entry.flags().contains(PageTableEntryFlags::DISABLE_CACHE)),
+            None => panic!("vga buffer is not mapped with a 4KiB page"),
+        }
+    }
 
     #[test_case]
     fn test_println_simple() {
@@ -191,6 +280,42 @@ mod tests {
         }
     }
 
+    #[test_case]
+    fn test_println_scrolls() {
+        for i in 0..30 {
+            println!("scroll line {:02}", i);
+        }
+
+        // The last line sits above the blank row, with the first 6 lines scrolled off the top
+        for (row, expected) in [(BUFFER_HEIGHT - 2, "scroll line 29"), (0, "scroll line 06")] {
+            for (col, c) in expected.chars().enumerate() {
+                let screen_char = WRITER.lock().buffer.chars[row][col].read();
+                assert_eq!(char::from(screen_char.ascii_character), c);
+            }
+        }
+    }
+
+    #[test_case]
+    fn test_scroll_keeps_color() {
+        interrupts::without_interrupts(|| {
+            let mut writer = WRITER.lock();
+            let original = writer.color_code;
+            writer.color_code = ColorCode::new(Color::LightGreen, Color::Blue);
+            writer.write_string("green\n");
+            writer.color_code = original;
+            writer.write_string("yellow\n");
+
+            let green = writer.buffer.chars[BUFFER_HEIGHT - 3][0].read();
+            assert_eq!(green.ascii_character, b'g');
+            assert_eq!(
+                green.color_code,
+                ColorCode::new(Color::LightGreen, Color::Blue)
+            );
+            let yellow = writer.buffer.chars[BUFFER_HEIGHT - 2][0].read();
+            assert_eq!(yellow.color_code, original);
+        });
+    }
+
     #[test_case]
     fn test_println_unicode() {
         let s = "Ψ😇🥰";
@@ -201,4 +326,34 @@ mod tests {
             assert_eq!(char::from(screen_char.ascii_character), char::from(0xfe));
         }
     }
+
+    #[test_case]
+    fn test_clear_screen() {
+        println!("text to be cleared");
+        clear!();
+
+        interrupts::without_interrupts(|| {
+            let writer = WRITER.lock();
+            for (row, col) in [(0, 0), (BUFFER_HEIGHT / 2, 7), (BUFFER_HEIGHT - 2, 0)] {
+                let screen_char = writer.buffer.chars[row][col].read();
+                assert_eq!(screen_char.ascii_character, b' ');
+                assert_eq!(screen_char.color_code, writer.color_code);
+            }
+            assert_eq!((writer.row_position, writer.column_position), (0, 0));
+        });
+    }
+
+    #[test_case]
+    fn test_println_colored() {
+        let previous = WRITER.lock().color_code();
+        println_colored!(ColorCode::new(Color::Red, Color::Blue), "x");
+
+        interrupts::without_interrupts(|| {
+            let writer = WRITER.lock();
+            let screen_char = writer.buffer.chars[writer.row_position - 1][0].read();
+            assert_eq!(screen_char.ascii_character, b'x');
+            assert_eq!(screen_char.color_code.0, 0x14);
+            assert_eq!(writer.color_code(), previous);
+        });
+    }
 }
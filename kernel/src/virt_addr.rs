@@ -1,4 +1,4 @@
-use core::ops::Add;
+use core::ops::{Add, Sub};
 
 use crate::paging::{PageOffset, PageTableIndex};
 
@@ -6,16 +6,125 @@ use crate::paging::{PageOffset, PageTableIndex};
 #[repr(transparent)]
 pub struct VirtAddr(u64);
 
+/// An address whose bits 48 to 63 aren't copies of bit 47
+#[derive(Debug, PartialEq)]
+pub struct NotCanonical(pub u64);
+
 impl VirtAddr {
-    // TODO: Make this canonical
+    /// Create an address, debug builds panic if it isn't canonical
+    ///
+    /// Release builds sign extend bit 47 into the top bits instead, as the cpu would.
+    /// Use this for addresses from outside the paging code, anything already known to be
+    /// canonical can skip the check with `new_unchecked`
     pub fn new(addr: u64) -> VirtAddr {
+        debug_assert!(
+            VirtAddr(addr).is_canonical(),
+            "{:#x} is not canonical",
+            addr
+        );
+        VirtAddr::new_truncate(addr)
+    }
+
+    /// Create an address, returning an error if it isn't canonical
+    pub fn try_new(addr: u64) -> Result<VirtAddr, NotCanonical> {
+        let virt = VirtAddr(addr);
+        match virt.is_canonical() {
+            true => Ok(virt),
+            false => Err(NotCanonical(addr)),
+        }
+    }
+
+    /// Create an address by sign extending bit 47 into bits 48 to 63
+    #[inline]
+    pub const fn new_truncate(addr: u64) -> VirtAddr {
+        VirtAddr(((addr << 16) as i64 >> 16) as u64)
+    }
+
+    /// Create an address without checking it's canonical
+    ///
+    /// This is unsafe as the caller must guarantee `addr` is canonical, or be deliberately
+    /// constructing a bad address. It's meant for hot paths in the page table code
+    #[inline]
+    pub const unsafe fn new_unchecked(addr: u64) -> VirtAddr {
         VirtAddr(addr)
     }
 
+    /// Build an address from its 4 level page table indices and page offset, the inverse of
+    /// `page_table_index` and `page_offset`
+    ///
+    /// Bit 47 is sign extended so the address is always canonical
+    #[inline]
+    pub fn from_indices(
+        l4: PageTableIndex,
+        l3: PageTableIndex,
+        l2: PageTableIndex,
+        l1: PageTableIndex,
+        offset: PageOffset,
+    ) -> VirtAddr {
+        VirtAddr::new_truncate(
+            u64::from(l4) << 39
+                | u64::from(l3) << 30
+                | u64::from(l2) << 21
+                | u64::from(l1) << 12
+                | u64::from(offset),
+        )
+    }
+
+    /// Whether bits 48 to 63 are copies of bit 47, as the cpu requires
+    #[inline]
+    pub fn is_canonical(&self) -> bool {
+        ((self.0 << 16) as i64 >> 16) as u64 == self.0
+    }
+
     /// Align downwards to the nearest page boundary
     #[inline]
     pub fn align_down(&self) -> VirtAddr {
-        VirtAddr::new(self.0 & 0xFFFF_FFFF_FFFF_F000)
+        self.align_down_to(4096)
+    }
+
+    /// Align downwards to a multiple of `align`, which must be a power of two
+    ///
+    /// This is how pages of any size find their start, e.g. 0x20_0000 for a 2MiB page
+    #[inline]
+    pub fn align_down_to(&self, align: u64) -> VirtAddr {
+        debug_assert!(
+            align.is_power_of_two(),
+            "{:#x} is not a power of two",
+            align
+        );
+        unsafe { VirtAddr::new_unchecked(self.0 & !(align - 1)) } // Clearing the low bits doesn't affect the sign extension
+    }
+
+    /// Align upwards to the nearest page boundary, an aligned address is returned unchanged
+    ///
+    /// Panics if there's no page boundary above the address, use `checked_align_up` where that
+    /// can happen
+    #[inline]
+    pub fn align_up(&self) -> VirtAddr {
+        match self.checked_align_up() {
+            Some(addr) => addr,
+            None => panic!("{:#x} can't be aligned up to a page", self.0),
+        }
+    }
+
+    /// Align upwards to the nearest page boundary, returning None if the boundary is past the end
+    /// of the address space or in the non canonical hole, i.e. the address is in the last page of
+    /// either half
+    #[inline]
+    pub fn checked_align_up(&self) -> Option<VirtAddr> {
+        let addr = self.0.checked_add(4095)?;
+        VirtAddr::try_new(addr & !4095).ok()
+    }
+
+    /// Whether the address is a multiple of `align`, which must be a power of two
+    #[inline]
+    pub fn is_aligned(&self, align: u64) -> bool {
+        debug_assert!(
+            align.is_power_of_two(),
+            "{:#x} is not a power of two",
+            align
+        );
+        self.0 & (align - 1) == 0
     }
 
     #[inline]
@@ -23,10 +132,14 @@ impl VirtAddr {
         PageOffset::new_truncate(self.0 as u16)
     }
 
+    /// The index into the page table at `level`, where level 0 is the lowest table
+    ///
+    /// Level 4 is only used with 5 level paging, the sign extension above bit 56 is ignored
     #[inline]
     pub fn page_table_index(&self, level: usize) -> PageTableIndex {
-        let index = (self.0 >> (12 + level * 9)) as u16;
-        PageTableIndex::new_truncate(index)
+        debug_assert!(level <= 4, "there is no level {} page table", level);
+        let index = (self.0 >> (12 + level * 9)) & 0x1FF;
+        PageTableIndex::new_truncate(index as u16)
     }
 
     #[inline]
@@ -54,55 +167,230 @@ impl Add<u64> for VirtAddr {
     }
 }
 
+impl Sub<u64> for VirtAddr {
+    type Output = VirtAddr;
+
+    #[inline]
+    fn sub(self, rhs: u64) -> Self::Output {
+        VirtAddr::new(self.0 - rhs)
+    }
+}
+
+impl Sub<VirtAddr> for VirtAddr {
+    type Output = u64;
+
+    /// The number of bytes from `rhs` up to `self`, which must not be below `rhs`
+    #[inline]
+    fn sub(self, rhs: VirtAddr) -> Self::Output {
+        debug_assert!(self >= rhs, "{:?} is below {:?}", self, rhs);
+        self.0.wrapping_sub(rhs.0)
+    }
+}
+
 impl<T> From<*const T> for VirtAddr {
     fn from(ptr: *const T) -> Self {
-        VirtAddr::new(ptr as u64)
+        unsafe { VirtAddr::new_unchecked(ptr as u64) } // Pointers the cpu gave us are always canonical
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::VirtAddr;
+    use super::{NotCanonical, VirtAddr};
 
     #[test_case]
     fn align_down() {
-        let addr = VirtAddr::new(0xE677_BF54_D244);
+        let addr = VirtAddr::new(0xFFFF_E677_BF54_D244);
         let aligned = addr.align_down();
-        assert_eq!(aligned.as_u64(), 0xE677_BF54_D000);
+        assert_eq!(aligned.as_u64(), 0xFFFF_E677_BF54_D000);
+    }
+
+    #[test_case]
+    fn align_up() {
+        let addr = VirtAddr::new(0xFFFF_E677_BF54_D244);
+        let aligned = addr.align_up();
+        assert_eq!(aligned.as_u64(), 0xFFFF_E677_BF54_E000);
+
+        let already = VirtAddr::new(0xFFFF_E677_BF54_D000);
+        assert_eq!(already.align_up(), already);
+
+        let last_page = VirtAddr::new(0xFFFF_FFFF_FFFF_F000);
+        assert_eq!(last_page.align_up(), last_page);
+    }
+
+    #[test_case]
+    fn checked_align_up_in_last_page() {
+        assert_eq!(
+            VirtAddr::new(0xFFFF_FFFF_FFFF_F001).checked_align_up(),
+            None
+        );
+        assert_eq!(VirtAddr::new(0x7FFF_FFFF_F001).checked_align_up(), None);
+
+        let addr = VirtAddr::new(0xFFFF_FFFF_FFFF_E001);
+        assert_eq!(
+            addr.checked_align_up(),
+            Some(VirtAddr::new(0xFFFF_FFFF_FFFF_F000))
+        );
+    }
+
+    #[test_case]
+    fn is_aligned() {
+        let addr = VirtAddr::new(0x20_0000);
+        assert!(addr.is_aligned(4096));
+        assert!(addr.is_aligned(0x20_0000));
+        assert!(!addr.is_aligned(0x40_0000));
+        assert!(!VirtAddr::new(0x20_0008).is_aligned(16));
+    }
+
+    #[test_case]
+    fn align_down_to_huge_pages() {
+        let addr = VirtAddr::new(0x20_0001);
+        assert_eq!(addr.align_down_to(0x20_0000).as_u64(), 0x20_0000);
+
+        let addr = VirtAddr::new(0x7FFF_FFFF);
+        assert_eq!(addr.align_down_to(0x4000_0000).as_u64(), 0x4000_0000);
+
+        let high = VirtAddr::new(0xFFFF_8000_4020_1000);
+        assert_eq!(
+            high.align_down_to(0x4000_0000).as_u64(),
+            0xFFFF_8000_4000_0000
+        );
+    }
+
+    #[test_case]
+    fn canonical_addresses() {
+        assert!(VirtAddr::new(0x0000_7FFF_FFFF_FFFF).is_canonical());
+        assert!(VirtAddr::new(0xFFFF_8000_0000_0000).is_canonical());
+        assert!(!unsafe { VirtAddr::new_unchecked(0x0000_8000_0000_0000) }.is_canonical());
+        assert!(!unsafe { VirtAddr::new_unchecked(0xFFFF_7FFF_FFFF_FFFF) }.is_canonical());
+    }
+
+    #[test_case]
+    fn try_new_canonical() {
+        match VirtAddr::try_new(0x1000) {
+            Ok(addr) => assert_eq!(addr.as_u64(), 0x1000),
+            Err(err) => panic!("low address was rejected: {:?}", err),
+        }
+        match VirtAddr::try_new(0xFFFF_8000_0000_1000) {
+            Ok(addr) => assert_eq!(addr.as_u64(), 0xFFFF_8000_0000_1000),
+            Err(err) => panic!("high address was rejected: {:?}", err),
+        }
+    }
+
+    #[test_case]
+    fn try_new_rejects_non_canonical() {
+        assert_eq!(
+            VirtAddr::try_new(0x0000_8000_0000_1234),
+            Err(NotCanonical(0x0000_8000_0000_1234))
+        );
+    }
+
+    #[test_case]
+    fn new_truncate_sign_extends() {
+        assert_eq!(
+            VirtAddr::new_truncate(0x0000_8000_0000_1234).as_u64(),
+            0xFFFF_8000_0000_1234
+        );
+        assert_eq!(
+            VirtAddr::new_truncate(0xFFFF_0000_0000_1234).as_u64(),
+            0x1234
+        );
+    }
+
+    /// `new` rejecting the same address is covered by the non_canonical_addr integration test
+    #[test_case]
+    fn new_unchecked_skips_validation() {
+        let addr = unsafe { VirtAddr::new_unchecked(0x0000_8000_0000_1234) };
+        assert_eq!(addr.as_u64(), 0x0000_8000_0000_1234);
+        assert!(!addr.is_canonical());
     }
 
     #[test_case]
     fn get_page_offset() {
-        let addr = VirtAddr::new(0xE677_BF54_D244);
+        let addr = VirtAddr::new(0xFFFF_E677_BF54_D244);
         let level1: u16 = addr.page_offset().into();
         assert_eq!(level1, 580);
     }
 
     #[test_case]
     fn get_level1_index() {
-        let addr = VirtAddr::new(0xE677_BF54_D244);
+        let addr = VirtAddr::new(0xFFFF_E677_BF54_D244);
         let level1: u16 = addr.page_table_index(0).into();
         assert_eq!(level1, 333);
     }
 
     #[test_case]
     fn get_level2_index() {
-        let addr = VirtAddr::new(0xE677_BF54_D244);
+        let addr = VirtAddr::new(0xFFFF_E677_BF54_D244);
         let level1: u16 = addr.page_table_index(1).into();
         assert_eq!(level1, 506);
     }
 
     #[test_case]
     fn get_level3_index() {
-        let addr = VirtAddr::new(0xE677_BF54_D244);
+        let addr = VirtAddr::new(0xFFFF_E677_BF54_D244);
         let level1: u16 = addr.page_table_index(2).into();
         assert_eq!(level1, 478);
     }
 
     #[test_case]
     fn get_level4_index() {
-        let addr = VirtAddr::new(0xE677_BF54_D244);
+        let addr = VirtAddr::new(0xFFFF_E677_BF54_D244);
         let level1: u16 = addr.page_table_index(3).into();
         assert_eq!(level1, 460);
     }
+
+    #[test_case]
+    fn indices_round_trip() {
+        let addr = VirtAddr::new(0xFFFF_E677_BF54_D244);
+        let rebuilt = VirtAddr::from_indices(
+            addr.page_table_index(3),
+            addr.page_table_index(2),
+            addr.page_table_index(1),
+            addr.page_table_index(0),
+            addr.page_offset(),
+        );
+        assert_eq!(rebuilt, addr);
+
+        let low = VirtAddr::new(0x4444_4444_1234);
+        let rebuilt = VirtAddr::from_indices(
+            low.page_table_index(3),
+            low.page_table_index(2),
+            low.page_table_index(1),
+            low.page_table_index(0),
+            low.page_offset(),
+        );
+        assert_eq!(rebuilt, low);
+    }
+
+    #[test_case]
+    fn get_level5_index() {
+        // Only canonical with 57 bit addresses
+        let addr = unsafe { VirtAddr::new_unchecked(0x01AB_E677_BF54_D244) };
+        let level5: u16 = addr.page_table_index(4).into();
+        assert_eq!(level5, 0x1AB);
+
+        let high = unsafe { VirtAddr::new_unchecked(0xFF5A_E677_BF54_D244) };
+        let level5: u16 = high.page_table_index(4).into();
+        assert_eq!(level5, 0x15A);
+        let level4: u16 = high.page_table_index(3).into();
+        assert_eq!(level4, 460);
+    }
+
+    #[test_case]
+    fn sub_offset() {
+        let addr = VirtAddr::new(0xFFFF_8000_0000_2000);
+        assert_eq!((addr - 0x1000).as_u64(), 0xFFFF_8000_0000_1000);
+        assert_eq!(addr - 0, addr);
+    }
+
+    #[test_case]
+    fn sub_addresses() {
+        let start = VirtAddr::new(0x20_0000);
+        let end = VirtAddr::new(0x20_3000);
+        assert_eq!(end - start, 0x3000);
+        assert_eq!(start - start, 0);
+
+        let high = VirtAddr::new(0xFFFF_8000_0000_1000);
+        assert_eq!(high - VirtAddr::new(0xFFFF_8000_0000_0000), 0x1000);
+    }
 }
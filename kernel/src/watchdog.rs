@@ -0,0 +1,114 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{exit_qemu, hlt_loop, serial, QemuExitCode};
+
+/// The default per test budget, roughly 30 seconds at the PIT's default 18.2Hz
+pub const DEFAULT_TEST_BUDGET: u64 = 18 * 30;
+
+/// The watchdog the test runner arms around each test
+pub static TEST_WATCHDOG: Watchdog = Watchdog::new();
+
+static TEST_BUDGET: AtomicU64 = AtomicU64::new(DEFAULT_TEST_BUDGET);
+
+/// Set the number of timer ticks a single test may run for before it is failed
+pub fn set_test_budget(ticks: u64) {
+    TEST_BUDGET.store(ticks, Ordering::Relaxed);
+}
+
+pub fn test_budget() -> u64 {
+    TEST_BUDGET.load(Ordering::Relaxed)
+}
+
+/// Called on every timer tick, failing the running test if it has used up its budget
+///
+/// The test may be hung holding the serial lock, so this reports over the lock free early serial writer
+pub fn on_timer_tick() {
+    if TEST_WATCHDOG.tick() {
+        serial::early_print("[timeout]\n");
+        exit_qemu(QemuExitCode::Failed);
+        hlt_loop();
+    }
+}
+
+/// Counts timer ticks and expires once more than its budget have passed while armed
+pub struct Watchdog {
+    ticks: AtomicU64,
+    /// The number of ticks allowed, 0 when disarmed
+    budget: AtomicU64,
+}
+
+impl Watchdog {
+    pub const fn new() -> Self {
+        Watchdog {
+            ticks: AtomicU64::new(0),
+            budget: AtomicU64::new(0),
+        }
+    }
+
+    /// Start counting from zero, expiring after `budget` ticks
+    pub fn arm(&self, budget: u64) {
+        self.ticks.store(0, Ordering::SeqCst);
+        self.budget.store(budget, Ordering::SeqCst);
+    }
+
+    pub fn disarm(&self) {
+        self.budget.store(0, Ordering::SeqCst);
+    }
+
+    /// The number of ticks since the watchdog was last armed
+    pub fn elapsed(&self) -> u64 {
+        self.ticks.load(Ordering::SeqCst)
+    }
+
+    /// Record a tick, returning true if the watchdog is armed and has run over its budget
+    pub fn tick(&self) -> bool {
+        let ticks = self.ticks.fetch_add(1, Ordering::SeqCst) + 1;
+        let budget = self.budget.load(Ordering::SeqCst);
+
+        budget != 0 && ticks > budget
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Watchdog, TEST_WATCHDOG};
+
+    /// Busy loop for `ticks` timer interrupts, ticking `watchdog` on each one
+    fn run_for(watchdog: &Watchdog, ticks: u64) -> bool {
+        let mut expired = false;
+        for _ in 0..ticks {
+            let start = TEST_WATCHDOG.elapsed();
+            while TEST_WATCHDOG.elapsed() == start {
+                core::hint::spin_loop();
+            }
+            expired |= watchdog.tick();
+        }
+
+        expired
+    }
+
+    #[test_case]
+    fn bounded_test_does_not_trip() {
+        let watchdog = Watchdog::new();
+        watchdog.arm(10);
+
+        assert!(!run_for(&watchdog, 3));
+    }
+
+    #[test_case]
+    fn long_test_trips() {
+        let watchdog = Watchdog::new();
+        watchdog.arm(2);
+
+        assert!(run_for(&watchdog, 5));
+    }
+
+    #[test_case]
+    fn disarmed_watchdog_never_trips() {
+        let watchdog = Watchdog::new();
+        watchdog.arm(1);
+        watchdog.disarm();
+
+        assert!(!run_for(&watchdog, 3));
+    }
+}
@@ -6,6 +6,8 @@
 
 use core::panic::PanicInfo;
 
+use kernel::memory::{try_load_active_pagetable, MemoryError};
+
 #[no_mangle]
 pub extern "C" fn _start() -> ! {
     test_main();
@@ -17,3 +19,11 @@ pub extern "C" fn _start() -> ! {
 fn panic(info: &PanicInfo) -> ! {
     kernel::test_panic_handler(info);
 }
+
+#[test_case]
+fn load_pagetable_before_init() {
+    match unsafe { try_load_active_pagetable() } {
+        Ok(_) => panic!("page table was loaded before the memory system was initialized"),
+        Err(err) => assert_eq!(err, MemoryError::NotInitialized),
+    }
+}
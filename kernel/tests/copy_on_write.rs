@@ -0,0 +1,156 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use kernel::{
+    allocator::{frame_refs, share_frame, FrameAllocator, FRAME_ALLOCATOR},
+    memory::{active_pagetable_frame, load_active_pagetable, switch_pagetable},
+    pagetable::PageTable,
+    paging::{Page, PageTableEntry, PageTableEntryFlags},
+    virt_addr::VirtAddr,
+};
+use x86_64::structures::paging::PhysFrame;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    match kernel::test_boot_with_heap(boot_info) {
+        Ok(_) => {}
+        Err(err) => panic!("init heap failed: {:?}", err),
+    }
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info);
+}
+
+const PARENT_ADDR: u64 = 0x5555_0000_0000;
+const CHILD_ADDR: u64 = 0x5555_0000_1000;
+/// In an otherwise unused top level slot, so cloning only copies this page
+const CLONED_ADDR: u64 = 0x3100_0000_0000;
+
+fn frame_of(table: &PageTable) -> PhysFrame {
+    let kernel_table = unsafe { load_active_pagetable() }; // Every address space maps the heap the same way
+    match kernel_table.translate_addr(VirtAddr::from(table as *const PageTable)) {
+        Some(addr) => PhysFrame::containing_address(addr.into()),
+        None => panic!("page table isn't mapped"),
+    }
+}
+
+#[test_case]
+fn write_to_cow_page_copies_frame() {
+    let alloc = match FRAME_ALLOCATOR.wait() {
+        Some(a) => a,
+        None => panic!("boot info allocator not initialized"),
+    };
+    let table = unsafe { load_active_pagetable() };
+
+    let frame = match alloc.lock().allocate() {
+        Some(f) => f,
+        None => panic!("could not allocate frame"),
+    };
+
+    let parent = Page::containing_address(VirtAddr::new(PARENT_ADDR));
+    let entry = PageTableEntry::new(
+        frame,
+        PageTableEntryFlags::PRESENT | PageTableEntryFlags::WRITABLE,
+    );
+    let result = unsafe { table.map_page(parent, entry, &mut *alloc.lock()) };
+    match result {
+        Ok(_) => {}
+        Err(err) => panic!("error mapping parent page: {:?}", err),
+    }
+
+    let child = Page::containing_address(VirtAddr::new(CHILD_ADDR));
+    let entry = PageTableEntry::new(
+        frame,
+        PageTableEntryFlags::PRESENT | PageTableEntryFlags::COPY_ON_WRITE,
+    );
+    let result = unsafe { table.map_page(child, entry, &mut *alloc.lock()) };
+    match result {
+        Ok(_) => {}
+        Err(err) => panic!("error mapping child page: {:?}", err),
+    }
+    share_frame(frame);
+
+    let parent_ptr: *mut u64 = VirtAddr::new(PARENT_ADDR).as_mut_ptr();
+    let child_ptr: *mut u64 = VirtAddr::new(CHILD_ADDR).as_mut_ptr();
+    unsafe {
+        parent_ptr.write_volatile(0xAAAA);
+        assert_eq!(child_ptr.read_volatile(), 0xAAAA);
+
+        // This write faults and is resolved by copying the shared frame
+        child_ptr.write_volatile(0xBBBB);
+
+        assert_eq!(parent_ptr.read_volatile(), 0xAAAA);
+        assert_eq!(child_ptr.read_volatile(), 0xBBBB);
+    }
+
+    assert_eq!(frame_refs(frame), 1);
+}
+
+#[test_case]
+fn write_to_cloned_table_keeps_parent_data() {
+    let alloc = match FRAME_ALLOCATOR.wait() {
+        Some(a) => a,
+        None => panic!("boot info allocator not initialized"),
+    };
+    let kernel_frame = active_pagetable_frame();
+    let kernel_table = unsafe { load_active_pagetable() };
+
+    let frame = match alloc.lock().allocate() {
+        Some(f) => f,
+        None => panic!("could not allocate frame"),
+    };
+    let mut parent = Box::new(kernel_table.clone());
+    let page = Page::containing_address(VirtAddr::new(CLONED_ADDR));
+    let entry = PageTableEntry::new(frame, PageTableEntryFlags::user_rw());
+    let result = unsafe { parent.map_page(page, entry, &mut *alloc.lock()) };
+    match result {
+        Ok(_) => {}
+        Err(err) => panic!("error mapping page: {:?}", err),
+    }
+
+    let data: *mut u64 = VirtAddr::new(CLONED_ADDR).as_mut_ptr();
+    unsafe {
+        switch_pagetable(frame_of(&parent));
+        data.write_volatile(0x1111);
+        switch_pagetable(kernel_frame);
+    }
+
+    let mut child = match parent.clone_cow(&mut *alloc.lock()) {
+        Ok(t) => Box::new(t),
+        Err(err) => panic!("error cloning table: {:?}", err),
+    };
+
+    unsafe {
+        // This write faults and is resolved by copying the shared frame
+        switch_pagetable(frame_of(&child));
+        data.write_volatile(0xBBBB);
+
+        switch_pagetable(frame_of(&parent));
+        let parent_value = data.read_volatile();
+        switch_pagetable(frame_of(&child));
+        let child_value = data.read_volatile();
+        switch_pagetable(kernel_frame);
+
+        assert_eq!(parent_value, 0x1111);
+        assert_eq!(child_value, 0xBBBB);
+    }
+    assert_eq!(frame_refs(frame), 1);
+
+    child.free_user_mappings(&mut *alloc.lock());
+    parent.free_user_mappings(&mut *alloc.lock());
+}
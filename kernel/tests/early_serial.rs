@@ -0,0 +1,20 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+use kernel::{exit_qemu, serial::early_print, QemuExitCode};
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    // Nothing is initialized, so this can't rely on the serial lock or interrupts
+    early_print("early_serial::early_print...\t");
+    early_print("[ok]\n");
+    exit_qemu(QemuExitCode::Success);
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info);
+}
@@ -0,0 +1,129 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use kernel::{
+    allocator::HEAP_START,
+    elf::{ElfError, PF_X},
+    gdt,
+    process::{exec_init, iter_in_state, trap_frame_of, ExecError, State},
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    match kernel::test_boot_with_heap(boot_info) {
+        Ok(_) => {}
+        Err(err) => panic!("init heap failed: {:?}", err),
+    }
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info);
+}
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+/// An otherwise unused top level slot
+const IMAGE_BASE: u64 = 0x2000_0000_0000;
+
+/// `jmp $`, there's no way back into the kernel from ring 3 yet
+const CODE: [u8; 2] = [0xEB, 0xFE];
+
+/// An executable with a single segment at `base` holding `CODE`
+fn tiny_elf(base: u64) -> Vec<u8> {
+    let code = (HEADER_SIZE + PROGRAM_HEADER_SIZE) as u64;
+    let size = code + CODE.len() as u64;
+    let mut image = Vec::new();
+
+    image.extend_from_slice(&[0x7F, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    image.extend_from_slice(&2u16.to_le_bytes()); // ET_EXEC
+    image.extend_from_slice(&0x3Eu16.to_le_bytes()); // x86_64
+    image.extend_from_slice(&1u32.to_le_bytes());
+    image.extend_from_slice(&(base + code).to_le_bytes());
+    image.extend_from_slice(&(HEADER_SIZE as u64).to_le_bytes());
+    image.extend_from_slice(&0u64.to_le_bytes());
+    image.extend_from_slice(&0u32.to_le_bytes());
+    image.extend_from_slice(&(HEADER_SIZE as u16).to_le_bytes());
+    image.extend_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    image.extend_from_slice(&1u16.to_le_bytes());
+    image.extend_from_slice(&[0; 6]);
+
+    image.extend_from_slice(&1u32.to_le_bytes()); // PT_LOAD
+    image.extend_from_slice(&PF_X.to_le_bytes());
+    image.extend_from_slice(&0u64.to_le_bytes());
+    image.extend_from_slice(&base.to_le_bytes());
+    image.extend_from_slice(&base.to_le_bytes());
+    image.extend_from_slice(&size.to_le_bytes());
+    image.extend_from_slice(&size.to_le_bytes());
+    image.extend_from_slice(&0x1000u64.to_le_bytes());
+
+    image.extend_from_slice(&CODE);
+    image
+}
+
+#[test_case]
+fn init_is_ready_to_enter_user_mode() {
+    let pid = match exec_init(&tiny_elf(IMAGE_BASE)) {
+        Ok(pid) => pid,
+        Err(err) => panic!("exec_init failed: {:?}", err),
+    };
+
+    assert!(iter_in_state(State::Ready).any(|p| p == pid));
+    let frame = match trap_frame_of(pid) {
+        Some(f) => f,
+        None => panic!("init process {} not found", pid),
+    };
+    assert_eq!(
+        frame.rip,
+        IMAGE_BASE + (HEADER_SIZE + PROGRAM_HEADER_SIZE) as u64
+    );
+    assert_ne!(frame.rsp, 0);
+    assert_eq!(frame.cs, u64::from(gdt::user_code_selector().0));
+    assert_eq!(frame.ss, u64::from(gdt::user_data_selector().0));
+}
+
+#[test_case]
+fn reject_invalid_image() {
+    let mut image = tiny_elf(IMAGE_BASE);
+    image[0] = 0;
+
+    match exec_init(&image) {
+        Err(ExecError::Elf(ElfError::BadMagic)) => {}
+        result => panic!("unexpected result: {:?}", result),
+    }
+}
+
+#[test_case]
+fn reject_segment_over_kernel_mapping() {
+    match exec_init(&tiny_elf(HEAP_START as u64)) {
+        Err(ExecError::BadAddress(_)) => {}
+        result => panic!("unexpected result: {:?}", result),
+    }
+}
+
+#[test_case]
+fn reject_entry_outside_code() {
+    // The entry point is the 8 bytes after the ELF identification, type, machine and version
+    for entry in [0xFFFF_8000_0000_0000, 0x8000_0000_0000, IMAGE_BASE + 0x1000] {
+        let mut image = tiny_elf(IMAGE_BASE);
+        image[24..32].copy_from_slice(&entry.to_le_bytes());
+
+        match exec_init(&image) {
+            Err(ExecError::BadAddress(addr)) => assert_eq!(addr, entry),
+            result => panic!("unexpected result for {:#x}: {:?}", entry, result),
+        }
+    }
+}
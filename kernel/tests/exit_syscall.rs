@@ -0,0 +1,112 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use kernel::{
+    elf::PF_X,
+    process::{self, exec, exit_code_of, iter_in_state, trap_frame_of, State},
+    syscall::SYS_EXIT,
+    trap::SYSCALL_VECTOR,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    match kernel::test_boot_with_heap(boot_info) {
+        Ok(_) => {}
+        Err(err) => panic!("init heap failed: {:?}", err),
+    }
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info);
+}
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+/// An otherwise unused top level slot
+const IMAGE_BASE: u64 = 0x2800_0000_0000;
+const EXIT_CODE: u8 = 42;
+
+/// `mov edi, EXIT_CODE; mov eax, SYS_EXIT; int SYSCALL_VECTOR; jmp $`
+const CODE: [u8; 14] = [
+    0xBF,
+    EXIT_CODE,
+    0,
+    0,
+    0,
+    0xB8,
+    SYS_EXIT as u8,
+    0,
+    0,
+    0,
+    0xCD,
+    SYSCALL_VECTOR,
+    0xEB,
+    0xFE,
+];
+
+/// An executable with a single segment holding `CODE`
+fn tiny_elf() -> Vec<u8> {
+    let code = (HEADER_SIZE + PROGRAM_HEADER_SIZE) as u64;
+    let size = code + CODE.len() as u64;
+    let mut image = Vec::new();
+
+    image.extend_from_slice(&[0x7F, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    image.extend_from_slice(&2u16.to_le_bytes()); // ET_EXEC
+    image.extend_from_slice(&0x3Eu16.to_le_bytes()); // x86_64
+    image.extend_from_slice(&1u32.to_le_bytes());
+    image.extend_from_slice(&(IMAGE_BASE + code).to_le_bytes());
+    image.extend_from_slice(&(HEADER_SIZE as u64).to_le_bytes());
+    image.extend_from_slice(&0u64.to_le_bytes());
+    image.extend_from_slice(&0u32.to_le_bytes());
+    image.extend_from_slice(&(HEADER_SIZE as u16).to_le_bytes());
+    image.extend_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    image.extend_from_slice(&1u16.to_le_bytes());
+    image.extend_from_slice(&[0; 6]);
+
+    image.extend_from_slice(&1u32.to_le_bytes()); // PT_LOAD
+    image.extend_from_slice(&PF_X.to_le_bytes());
+    image.extend_from_slice(&0u64.to_le_bytes());
+    image.extend_from_slice(&IMAGE_BASE.to_le_bytes());
+    image.extend_from_slice(&IMAGE_BASE.to_le_bytes());
+    image.extend_from_slice(&size.to_le_bytes());
+    image.extend_from_slice(&size.to_le_bytes());
+    image.extend_from_slice(&0x1000u64.to_le_bytes());
+
+    image.extend_from_slice(&CODE);
+    image
+}
+
+#[test_case]
+fn exit_syscall_leaves_a_zombie() {
+    let pid = match exec(&tiny_elf(), "exit") {
+        Ok(pid) => pid,
+        Err(err) => panic!("exec failed: {:?}", err),
+    };
+
+    // Runs the process until its exit syscall switches back to this thread
+    process::schedule();
+
+    assert!(iter_in_state(State::Zombie).any(|p| p == pid));
+    assert_eq!(exit_code_of(pid), Some(i32::from(EXIT_CODE)));
+    // The frame is saved on syscall entry, not left as exec set it up
+    let frame = match trap_frame_of(pid) {
+        Some(f) => f,
+        None => panic!("process {} has no trap frame", pid),
+    };
+    assert_eq!(frame.syscall_number(), SYS_EXIT);
+    assert_eq!(frame.syscall_args()[0], u64::from(EXIT_CODE));
+}
@@ -0,0 +1,145 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use kernel::{
+    elf::{PF_W, PF_X},
+    memory::{active_pagetable_frame, switch_pagetable},
+    process::{exec_init, fork, pagetable_frame, trap_frame_of},
+    virt_addr::VirtAddr,
+};
+use x86_64::structures::paging::PhysFrame;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    match kernel::test_boot_with_heap(boot_info) {
+        Ok(_) => {}
+        Err(err) => panic!("init heap failed: {:?}", err),
+    }
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info);
+}
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+/// An otherwise unused top level slot
+const IMAGE_BASE: u64 = 0x3000_0000_0000;
+/// A zero filled page after the code
+const DATA_ADDR: u64 = IMAGE_BASE + 0x1000;
+
+/// An executable with one writable segment holding `jmp $` followed by a page of zeroes
+fn tiny_elf() -> Vec<u8> {
+    let code = (HEADER_SIZE + PROGRAM_HEADER_SIZE) as u64;
+    let file_size = code + 2;
+    let mut image = Vec::new();
+
+    image.extend_from_slice(&[0x7F, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    image.extend_from_slice(&2u16.to_le_bytes()); // ET_EXEC
+    image.extend_from_slice(&0x3Eu16.to_le_bytes()); // x86_64
+    image.extend_from_slice(&1u32.to_le_bytes());
+    image.extend_from_slice(&(IMAGE_BASE + code).to_le_bytes());
+    image.extend_from_slice(&(HEADER_SIZE as u64).to_le_bytes());
+    image.extend_from_slice(&0u64.to_le_bytes());
+    image.extend_from_slice(&0u32.to_le_bytes());
+    image.extend_from_slice(&(HEADER_SIZE as u16).to_le_bytes());
+    image.extend_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    image.extend_from_slice(&1u16.to_le_bytes());
+    image.extend_from_slice(&[0; 6]);
+
+    image.extend_from_slice(&1u32.to_le_bytes()); // PT_LOAD
+    image.extend_from_slice(&(PF_W | PF_X).to_le_bytes());
+    image.extend_from_slice(&0u64.to_le_bytes());
+    image.extend_from_slice(&IMAGE_BASE.to_le_bytes());
+    image.extend_from_slice(&IMAGE_BASE.to_le_bytes());
+    image.extend_from_slice(&file_size.to_le_bytes());
+    image.extend_from_slice(&0x2000u64.to_le_bytes());
+    image.extend_from_slice(&0x1000u64.to_le_bytes());
+
+    image.extend_from_slice(&[0xEB, 0xFE]);
+    image
+}
+
+fn frame_of(pid: u64) -> PhysFrame {
+    match pagetable_frame(pid) {
+        Some(f) => f,
+        None => panic!("process {} has no page table", pid),
+    }
+}
+
+#[test_case]
+fn fork_returns_pid_to_parent_and_zero_to_child() {
+    let parent = match exec_init(&tiny_elf()) {
+        Ok(pid) => pid,
+        Err(err) => panic!("exec_init failed: {:?}", err),
+    };
+    let child = match fork(parent) {
+        Ok(pid) => pid,
+        Err(err) => panic!("fork failed: {:?}", err),
+    };
+
+    match (trap_frame_of(parent), trap_frame_of(child)) {
+        (Some(p), Some(c)) => {
+            assert_eq!(p.rax, child);
+            assert_eq!(c.rax, 0);
+            assert_eq!(p.rip, c.rip);
+            assert_eq!(p.rsp, c.rsp);
+        }
+        _ => panic!("forked processes not found"),
+    }
+}
+
+#[test_case]
+fn forked_writes_are_independent() {
+    let parent = match exec_init(&tiny_elf()) {
+        Ok(pid) => pid,
+        Err(err) => panic!("exec_init failed: {:?}", err),
+    };
+    let kernel_frame = active_pagetable_frame();
+    let data: *mut u64 = VirtAddr::new(DATA_ADDR).as_mut_ptr();
+
+    // Both processes share this frame once forked
+    unsafe {
+        switch_pagetable(frame_of(parent));
+        data.write_volatile(0x1111);
+        switch_pagetable(kernel_frame);
+    }
+
+    let child = match fork(parent) {
+        Ok(pid) => pid,
+        Err(err) => panic!("fork failed: {:?}", err),
+    };
+
+    unsafe {
+        switch_pagetable(frame_of(parent));
+        data.write_volatile(0xAAAA);
+
+        switch_pagetable(frame_of(child));
+        let child_value = data.read_volatile();
+        data.write_volatile(0xBBBB);
+
+        switch_pagetable(frame_of(parent));
+        let parent_value = data.read_volatile();
+        switch_pagetable(frame_of(child));
+        let child_write = data.read_volatile();
+        switch_pagetable(kernel_frame);
+
+        assert_eq!(child_value, 0x1111);
+        assert_eq!(parent_value, 0xAAAA);
+        assert_eq!(child_write, 0xBBBB);
+    }
+}
@@ -4,26 +4,28 @@
 #![test_runner(kernel::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
-use core::panic::PanicInfo;
+use core::{alloc::Layout, panic::PanicInfo};
 
 use alloc::{boxed::Box, vec::Vec};
 use bootloader::{entry_point, BootInfo};
-use kernel::allocator::{init_heap, FRAME_ALLOCATOR, HEAP_SIZE};
+use kernel::{
+    allocator::{
+        alloc_histogram, heap_is_nx, FRAME_ALLOCATOR, HEAP_MAX_SIZE, HEAP_SIZE, HEAP_START,
+    },
+    cpu,
+    memory::load_active_pagetable,
+    paging::PageTableEntryFlags,
+    virt_addr::VirtAddr,
+};
 
 extern crate alloc;
 
 entry_point!(main);
 
 fn main(boot_info: &'static BootInfo) -> ! {
-    kernel::init(boot_info);
-    let alloc = match FRAME_ALLOCATOR.wait() {
-        Some(a) => a,
-        None => panic!("boot info allocator not initialized"),
-    };
-    let result = init_heap(&mut *alloc.lock());
-    match result {
+    match kernel::test_boot_with_heap(boot_info) {
         Ok(_) => {}
-        Err(_) => panic!("init heap failed"),
+        Err(err) => panic!("init heap failed: {:?}", err),
     }
 
     test_main();
@@ -35,6 +37,13 @@ fn panic(info: &PanicInfo) -> ! {
     kernel::test_panic_handler(info);
 }
 
+#[test_case]
+fn allocation_after_shared_boot() {
+    let values: Vec<u64> = (0..16).collect();
+    assert_eq!(values.len(), 16);
+    assert_eq!(values[15], 15);
+}
+
 #[test_case]
 fn simple_allocation() {
     let heap_value_1 = Box::new(41);
@@ -61,3 +70,71 @@ fn many_boxes() {
         assert_eq!(*x, i);
     }
 }
+
+#[test_case]
+fn heap_grows_past_initial_size() {
+    let size = 2 * HEAP_SIZE;
+    let mut big: Vec<u8> = Vec::with_capacity(size);
+    big.resize(size, 0xAB);
+
+    assert_eq!(big[0], 0xAB);
+    assert_eq!(big[size - 1], 0xAB);
+}
+
+#[test_case]
+fn growth_fails_while_frame_allocator_is_locked() {
+    let frames = match FRAME_ALLOCATOR.wait() {
+        Some(a) => a,
+        None => panic!("boot info allocator not initialized"),
+    };
+    // Bigger than anything earlier tests leave free, so the heap has to grow
+    let layout = match Layout::from_size_align(HEAP_MAX_SIZE / 4, 8) {
+        Ok(l) => l,
+        Err(err) => panic!("invalid layout: {:?}", err),
+    };
+
+    let held = frames.lock();
+    let ptr = unsafe { alloc::alloc::alloc(layout) };
+    drop(held);
+    assert!(ptr.is_null());
+
+    let ptr = unsafe { alloc::alloc::alloc(layout) };
+    assert!(!ptr.is_null());
+    unsafe { alloc::alloc::dealloc(ptr, layout) };
+}
+
+#[test_case]
+fn histogram_counts_allocation_sizes() {
+    let before = alloc_histogram();
+    let small = Box::new(0u64);
+    let medium: Vec<u8> = Vec::with_capacity(200);
+    let page: Vec<u8> = Vec::with_capacity(4096);
+    let large: Vec<u8> = Vec::with_capacity(5000);
+    let after = alloc_histogram();
+
+    let mut counted = [0; 6];
+    for (i, count) in counted.iter_mut().enumerate() {
+        *count = after[i] - before[i];
+    }
+    assert_eq!(counted, [1, 0, 1, 0, 1, 1]);
+
+    drop((small, medium, page, large));
+}
+
+#[test_case]
+fn heap_pages_are_no_execute() {
+    if !cpu::nx_supported() {
+        assert!(!heap_is_nx());
+        return;
+    }
+    assert!(heap_is_nx());
+
+    let table = unsafe { load_active_pagetable() };
+    for offset in [0, HEAP_SIZE - 1] {
+        let addr = VirtAddr::new((HEAP_START + offset) as u64);
+        match table.leaf_entry_mut(addr) {
+            Some(entry) => assert!(entry.flags().contains(PageTableEntryFlags::NO_EXECUTE)),
+            None => panic!("heap page has no leaf entry"),
+        }
+    }
+}
@@ -0,0 +1,92 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use bootloader::{entry_point, BootInfo};
+use kernel::process::{schedule, set_preemption, spawn_kernel};
+use x86_64::instructions::hlt;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    match kernel::test_boot_with_heap(boot_info) {
+        Ok(_) => {}
+        Err(err) => panic!("init heap failed: {:?}", err),
+    }
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info);
+}
+
+static RAN: AtomicBool = AtomicBool::new(false);
+
+fn worker() -> ! {
+    RAN.store(true, Ordering::SeqCst);
+    loop {
+        schedule();
+    }
+}
+
+#[test_case]
+fn spawned_thread_runs() {
+    spawn_kernel(worker, "worker");
+
+    schedule();
+
+    assert!(RAN.load(Ordering::SeqCst));
+}
+
+static FIRST_COUNT: AtomicU64 = AtomicU64::new(0);
+static SECOND_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// Never yields, so it only stops running when the timer preempts it
+fn first_counter() -> ! {
+    loop {
+        FIRST_COUNT.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+fn second_counter() -> ! {
+    loop {
+        SECOND_COUNT.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[test_case]
+fn timer_preempts_threads() {
+    for (entry, name) in [
+        (first_counter as fn() -> !, "first"),
+        (second_counter, "second"),
+    ] {
+        spawn_kernel(entry, name);
+    }
+
+    set_preemption(true);
+    while FIRST_COUNT.load(Ordering::SeqCst) == 0 || SECOND_COUNT.load(Ordering::SeqCst) == 0 {
+        hlt();
+    }
+
+    // Both keep advancing as they take turns
+    let (first, second) = (
+        FIRST_COUNT.load(Ordering::SeqCst),
+        SECOND_COUNT.load(Ordering::SeqCst),
+    );
+    while FIRST_COUNT.load(Ordering::SeqCst) == first
+        || SECOND_COUNT.load(Ordering::SeqCst) == second
+    {
+        hlt();
+    }
+    set_preemption(false);
+}
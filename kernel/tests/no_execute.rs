@@ -0,0 +1,98 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use kernel::{
+    allocator::{FrameAllocator, FRAME_ALLOCATOR},
+    cpu, exit_qemu,
+    memory::{load_active_pagetable, set_no_execute},
+    paging::{Page, PageRangeInclusive, PageTableEntry, PageTableEntryFlags},
+    serial_print, serial_println,
+    virt_addr::VirtAddr,
+    QemuExitCode,
+};
+use lazy_static::lazy_static;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+
+const CODE_ADDR: u64 = 0x5555_6000_0000;
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+
+        idt
+    };
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("no_execute::fetch_from_nx_page_faults...\t");
+
+    kernel::init(boot_info);
+    if !cpu::nx_enabled() {
+        serial_println!("[ok]");
+        serial_println!("NX isn't supported, skipping");
+        exit_qemu(QemuExitCode::Success);
+    }
+
+    let alloc = match FRAME_ALLOCATOR.wait() {
+        Some(a) => a,
+        None => panic!("boot info allocator not initialized"),
+    };
+    let frame = match alloc.lock().allocate() {
+        Some(f) => f,
+        None => panic!("could not allocate frame"),
+    };
+    let page = Page::containing_address(VirtAddr::new(CODE_ADDR));
+    let table = unsafe { load_active_pagetable() };
+    let entry = PageTableEntry::new(frame, PageTableEntryFlags::kernel_rw());
+    if let Err(err) = unsafe { table.map_page(page, entry, &mut *alloc.lock()) } {
+        panic!("error mapping page: {:?}", err);
+    }
+
+    // A lone ret, which would return straight away if the page were executable
+    let code: *mut u8 = VirtAddr::new(CODE_ADDR).as_mut_ptr();
+    unsafe { code.write_volatile(0xC3) };
+    if set_no_execute(PageRangeInclusive::new(page, page + 1)) != 1 {
+        panic!("page wasn't marked no execute");
+    }
+
+    x86_64::instructions::interrupts::disable();
+    TEST_IDT.load();
+
+    let function: extern "C" fn() = unsafe { core::mem::transmute(CODE_ADDR) };
+    function();
+
+    panic!("Execution continued in a no execute page");
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    _stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    let addr = cpu::read_cr2().as_u64();
+    if addr == CODE_ADDR && error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!(
+            "Error: fault at {:#x} with {:?}, expected an instruction fetch at {:#x}\n",
+            addr,
+            error_code,
+            CODE_ADDR
+        );
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info)
+}
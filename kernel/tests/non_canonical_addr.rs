@@ -0,0 +1,30 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+use kernel::{exit_qemu, serial_print, serial_println, virt_addr::VirtAddr, QemuExitCode};
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("non_canonical_addr::new_rejects_non_canonical...\t");
+
+    // Release builds skip the check
+    if !cfg!(debug_assertions) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+        loop {}
+    }
+
+    let addr = VirtAddr::new(0x0000_8000_0000_1234);
+    serial_println!("[test did not panic] {:?}", addr);
+    exit_qemu(QemuExitCode::Failed);
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
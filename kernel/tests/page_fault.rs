@@ -0,0 +1,62 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use core::panic::PanicInfo;
+use kernel::{cpu, exit_qemu, serial_print, serial_println, virt_addr::VirtAddr, QemuExitCode};
+use lazy_static::lazy_static;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+
+const FAULT_ADDR: u64 = 0xDEAD_BEE8;
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+
+        idt
+    };
+}
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("page_fault::read_cr2...\t");
+
+    kernel::gdt::init();
+    init_test_idt();
+
+    // trigger a page fault on an unmapped address
+    let ptr: *const u64 = VirtAddr::new(FAULT_ADDR).as_ptr();
+    unsafe { ptr.read_volatile() };
+
+    panic!("Execution continued after page fault");
+}
+
+fn init_test_idt() {
+    TEST_IDT.load();
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    _stack_frame: InterruptStackFrame,
+    _error_code: PageFaultErrorCode,
+) {
+    let addr = cpu::read_cr2();
+    if addr.as_u64() == FAULT_ADDR {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!(
+            "Error: cr2 was {:#x}, expected {:#x}\n",
+            addr.as_u64(),
+            FAULT_ADDR
+        );
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info)
+}
@@ -0,0 +1,141 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use kernel::{
+    allocator::{FrameAllocator, FRAME_ALLOCATOR},
+    pagetable::{PageMapError, PageTable},
+    paging::{Page, PageRangeInclusive, PageTableEntry, PageTableEntryFlags},
+    virt_addr::VirtAddr,
+};
+use x86_64::structures::paging::PhysFrame;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    match kernel::test_boot_with_heap(boot_info) {
+        Ok(_) => {}
+        Err(err) => panic!("init heap failed: {:?}", err),
+    }
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info);
+}
+
+const SEED: u64 = 0x7468_6f72_6e4f_53;
+const OPERATIONS: usize = 4000;
+const SLOTS: u64 = 64;
+const POOL_SIZE: usize = 32;
+
+/// A 64 bit linear congruential generator, good enough to pick operations
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self) -> u64 {
+        self.0 = self
+            .0
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        self.0 >> 33
+    }
+}
+
+/// Spread the slots across different top level, level 2 & level 1 tables
+fn slot_page(slot: u64) -> Page {
+    let addr = (slot & 0x7) << 39 | (slot >> 3) << 21 | (slot & 0x3) << 12;
+    Page::containing_address(VirtAddr::new(addr))
+}
+
+fn check_slot(table: &PageTable, model: &BTreeMap<u64, PhysFrame>, slot: u64) {
+    let addr = slot_page(slot).as_virt_addr() + 0x123;
+    match (table.translate_addr(addr), model.get(&slot)) {
+        (Some(pa), Some(frame)) => assert_eq!(pa.as_u64(), frame.start_address().as_u64() + 0x123),
+        (None, None) => {}
+        (Some(pa), None) => panic!("slot {} unexpectedly mapped to {:#x}", slot, pa.as_u64()),
+        (None, Some(_)) => panic!("slot {} is missing its mapping", slot),
+    }
+}
+
+#[test_case]
+fn seeded_map_unmap() {
+    let alloc = match FRAME_ALLOCATOR.wait() {
+        Some(a) => a,
+        None => panic!("boot info allocator not initialized"),
+    };
+
+    let mut pool: Vec<PhysFrame> = Vec::new();
+    for _ in 0..POOL_SIZE {
+        match alloc.lock().allocate() {
+            Some(f) => pool.push(f),
+            None => panic!("could not allocate frame"),
+        }
+    }
+
+    let mut rng = Lcg(SEED);
+    let mut table = PageTable::new();
+    let mut model: BTreeMap<u64, PhysFrame> = BTreeMap::new();
+
+    for op in 0..OPERATIONS {
+        let slot = rng.next() % SLOTS;
+        let page = slot_page(slot);
+
+        if rng.next() % 2 == 0 {
+            // Map, taking a frame from the pool
+            let frame = match pool.pop() {
+                Some(f) => f,
+                None => continue,
+            };
+            let entry = PageTableEntry::new(frame, PageTableEntryFlags::PRESENT);
+            let result = unsafe { table.map_page(page, entry, &mut *alloc.lock()) };
+            match (result, model.contains_key(&slot)) {
+                (Ok(_), false) => {
+                    model.insert(slot, frame);
+                }
+                (Err(PageMapError::PageAlreadyMapped), true) => pool.push(frame),
+                (result, _) => panic!("op {}: unexpected map result {:?}", op, result),
+            }
+        } else {
+            // Unmap, returning the frame to the pool
+            let mut freed = None;
+            table.unmap_range(PageRangeInclusive::new(page, page + 1), &mut |f| {
+                assert!(freed.is_none(), "op {}: unmapped more than one frame", op);
+                freed = Some(f.start_address());
+            });
+
+            match (freed, model.remove(&slot)) {
+                (Some(addr), Some(frame)) => {
+                    assert_eq!(addr, frame.start_address().into());
+                    pool.push(frame);
+                }
+                (None, None) => {}
+                (freed, expected) => {
+                    panic!("op {}: unmapped {:?}, expected {:?}", op, freed, expected)
+                }
+            }
+        }
+
+        check_slot(&table, &model, slot);
+        if op % 256 == 0 {
+            for slot in 0..SLOTS {
+                check_slot(&table, &model, slot);
+            }
+        }
+    }
+
+    for slot in 0..SLOTS {
+        check_slot(&table, &model, slot);
+    }
+}
@@ -0,0 +1,74 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use kernel::ramfs::{self, RamfsError};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    match kernel::test_boot_with_heap(boot_info) {
+        Ok(_) => {}
+        Err(err) => panic!("init heap failed: {:?}", err),
+    }
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info);
+}
+
+#[test_case]
+fn write_then_read_back() {
+    let fd = match ramfs::create("hello") {
+        Ok(fd) => fd,
+        Err(err) => panic!("create failed: {:?}", err),
+    };
+    assert_eq!(ramfs::write(fd, b"hello "), Ok(6));
+    assert_eq!(ramfs::write(fd, b"world"), Ok(5));
+    assert_eq!(ramfs::close(fd), Ok(()));
+
+    let fd = match ramfs::open("hello") {
+        Ok(fd) => fd,
+        Err(err) => panic!("open failed: {:?}", err),
+    };
+    let mut buf = [0; 16];
+    assert_eq!(ramfs::read(fd, &mut buf[..4]), Ok(4));
+    assert_eq!(ramfs::read(fd, &mut buf[4..]), Ok(7));
+    assert_eq!(&buf[..11], b"hello world");
+    assert_eq!(ramfs::read(fd, &mut buf), Ok(0));
+    assert_eq!(ramfs::close(fd), Ok(()));
+}
+
+#[test_case]
+fn files_are_independent() {
+    let a = ramfs::create("a").unwrap();
+    let b = ramfs::create("b").unwrap();
+    assert_eq!(ramfs::write(a, b"aaaa"), Ok(4));
+    assert_eq!(ramfs::write(b, b"bb"), Ok(2));
+
+    let mut buf = [0; 8];
+    let b = ramfs::open("b").unwrap();
+    assert_eq!(ramfs::read(b, &mut buf), Ok(2));
+    assert_eq!(&buf[..2], b"bb");
+}
+
+#[test_case]
+fn errors() {
+    assert_eq!(ramfs::open("missing"), Err(RamfsError::NotFound));
+
+    let fd = ramfs::create("exists").unwrap();
+    assert_eq!(ramfs::create("exists"), Err(RamfsError::AlreadyExists));
+
+    assert_eq!(ramfs::close(fd), Ok(()));
+    assert_eq!(ramfs::write(fd, b"x"), Err(RamfsError::BadFd));
+    assert_eq!(ramfs::close(fd), Err(RamfsError::BadFd));
+}
@@ -0,0 +1,33 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+use kernel::{exit_qemu, serial_print, serial_println, sync::DebugMutex, QemuExitCode};
+
+static LOCK: DebugMutex<u64> = DebugMutex::new(0);
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("recursive_lock::recursive_lock_panics...\t");
+
+    // Release builds don't detect recursion so this would hang
+    if !cfg!(debug_assertions) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+        loop {}
+    }
+
+    let _first = LOCK.lock();
+    let _second = LOCK.lock();
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
@@ -0,0 +1,79 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use bootloader::{entry_point, BootInfo};
+use kernel::{
+    cpu, exit_qemu, memory::install_kernel_stack_guard, serial_print, serial_println, QemuExitCode,
+};
+use lazy_static::lazy_static;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+
+static GUARD_PAGE: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        unsafe {
+            idt.double_fault
+                .set_handler_fn(test_double_fault_handler)
+                .set_stack_index(kernel::gdt::DOUBLE_FAULT_IST_INDEX);
+        }
+
+        idt
+    };
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("stack_guard::overflow_hits_guard_page...\t");
+
+    kernel::init(boot_info);
+    let guard = match install_kernel_stack_guard() {
+        Some(g) => g,
+        None => panic!("could not install stack guard"),
+    };
+    GUARD_PAGE.store(guard.as_u64(), Ordering::SeqCst);
+
+    x86_64::instructions::interrupts::disable();
+    TEST_IDT.load();
+
+    // trigger a stack overflow
+    stack_overflow();
+
+    panic!("Execution continued after stack overflow");
+}
+
+extern "x86-interrupt" fn test_double_fault_handler(
+    _stack_frame: InterruptStackFrame,
+    _error_code: u64,
+) -> ! {
+    // The fault handler couldn't push its frame, so cr2 is the guard page rather than corrupted memory
+    let fault_page = cpu::read_cr2().align_down().as_u64();
+    if fault_page == GUARD_PAGE.load(Ordering::SeqCst) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]");
+        serial_println!("Faulted at {:#x} instead of the guard page", fault_page);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
+
+#[allow(unconditional_recursion)]
+fn stack_overflow() {
+    stack_overflow();
+    volatile::Volatile::new(0).read(); // Prevent tail recursion optimization
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info)
+}
@@ -0,0 +1,133 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use kernel::{
+    allocator::{FrameAllocator, FRAME_ALLOCATOR},
+    exit_qemu,
+    memory::load_active_pagetable,
+    paging::{Page, PageTableEntry, PageTableEntryFlags},
+    serial_print, serial_println,
+    trap::{enter_user_mode, SYSCALL_VECTOR},
+    virt_addr::VirtAddr,
+    QemuExitCode,
+};
+use lazy_static::lazy_static;
+use x86_64::{
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+    PrivilegeLevel,
+};
+
+const CODE_ADDR: u64 = 0x5555_7000_0000;
+const STACK_ADDR: u64 = 0x5555_7001_0000;
+
+/// int 0x80 then spin, in case the interrupt ever returns
+const USER_CODE: [u8; 4] = [0xCD, SYSCALL_VECTOR, 0xEB, 0xFE];
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt[SYSCALL_VECTOR as usize]
+            .set_handler_fn(test_syscall_handler)
+            .set_privilege_level(PrivilegeLevel::Ring3);
+        idt.general_protection_fault
+            .set_handler_fn(test_general_protection_handler);
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+
+        idt
+    };
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("user_mode::syscall_from_ring_3...\t");
+
+    kernel::init(boot_info);
+    // Writable so the code can be copied in, the cpu may enforce write protection in ring 0
+    map_user_page(CODE_ADDR, PageTableEntryFlags::user_rw());
+    map_user_page(STACK_ADDR, PageTableEntryFlags::user_rw());
+
+    let code: *mut [u8; 4] = VirtAddr::new(CODE_ADDR).as_mut_ptr();
+    unsafe { code.write_volatile(USER_CODE) };
+
+    x86_64::instructions::interrupts::disable();
+    TEST_IDT.load();
+
+    enter_user_mode(VirtAddr::new(CODE_ADDR), VirtAddr::new(STACK_ADDR + 4096));
+}
+
+fn map_user_page(addr: u64, flags: PageTableEntryFlags) {
+    let alloc = match FRAME_ALLOCATOR.wait() {
+        Some(a) => a,
+        None => panic!("boot info allocator not initialized"),
+    };
+    let frame = match alloc.lock().allocate() {
+        Some(f) => f,
+        None => panic!("could not allocate frame"),
+    };
+
+    let table = unsafe { load_active_pagetable() };
+    let page = Page::containing_address(VirtAddr::new(addr));
+    let entry = PageTableEntry::new(frame, flags);
+    if let Err(err) = unsafe { table.map_page(page, entry, &mut *alloc.lock()) } {
+        panic!("error mapping page: {:?}", err);
+    }
+}
+
+extern "x86-interrupt" fn test_syscall_handler(stack_frame: InterruptStackFrame) {
+    // The cpu reports the privilege level it was running at in the pushed cs
+    let cpl = stack_frame.code_segment & 0b11;
+    let rip = stack_frame.instruction_pointer.as_u64();
+    if cpl == 3 && rip == CODE_ADDR + 2 {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!(
+            "Error: syscall from ring {} at {:#x}, expected ring 3 at {:#x}\n",
+            cpl,
+            rip,
+            CODE_ADDR + 2
+        );
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
+
+extern "x86-interrupt" fn test_general_protection_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    serial_println!("[failed]\n");
+    serial_println!(
+        "Error: general protection fault {:#x}\n{:#?}\n",
+        error_code,
+        stack_frame
+    );
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    serial_println!("[failed]\n");
+    serial_println!(
+        "Error: page fault {:?} at {:?}\n{:#?}\n",
+        error_code,
+        kernel::cpu::read_cr2(),
+        stack_frame
+    );
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info)
+}
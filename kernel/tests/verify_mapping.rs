@@ -0,0 +1,55 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use kernel::{
+    allocator::FRAME_ALLOCATOR,
+    exit_qemu,
+    pagetable::PageTable,
+    paging::{Page, PageTableEntry, PageTableEntryFlags, Phys},
+    phys_addr::PhysAddr,
+    serial_print, serial_println,
+    virt_addr::VirtAddr,
+    QemuExitCode,
+};
+use x86_64::structures::paging::{PhysFrame, Size4KiB};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    kernel::init(boot_info);
+    serial_print!("verify_mapping::wrong_frame_panics...\t");
+
+    let alloc = match FRAME_ALLOCATOR.wait() {
+        Some(a) => a,
+        None => panic!("boot info allocator not initialized"),
+    };
+    let mut table = PageTable::new();
+    let page = Page::containing_address(VirtAddr::new(0xDEADBEEF));
+    let frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(0x1000).into());
+    let entry = PageTableEntry::new(frame, PageTableEntryFlags::PRESENT);
+    let result = unsafe { table.map_page(page, entry, &mut *alloc.lock()) };
+    if result.is_err() {
+        serial_println!("[failed]");
+        serial_println!("Error mapping page: {:?}", result);
+        exit_qemu(QemuExitCode::Failed);
+        loop {}
+    }
+
+    // Simulate the mapping resolving to the wrong frame
+    let wrong = PhysFrame::containing_address(PhysAddr::new(0x2000).into());
+    table.verify_mapping(page, Phys::Size4Kb(wrong));
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}